@@ -0,0 +1,32 @@
+//! REM, a cache server and the clients for it
+//!
+//! The server and command line client are run by the rem binary. Code that uses a server
+//! directly can do so through rem_client::RemClient, or sharded_client::ShardedClient to spread
+//! keys across several servers
+
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate log;
+
+extern crate env_logger;
+extern crate backtrace;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+extern crate tokio_io;
+extern crate bytes;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate toml;
+extern crate rustyline;
+extern crate serde_json;
+extern crate base64;
+extern crate signal_hook;
+
+mod rem;
+
+pub use rem::*;
@@ -1,23 +1,4 @@
-#[macro_use]
-extern crate serde_derive;
-
-#[macro_use]
-extern crate log;
-
-extern crate env_logger;
-extern crate backtrace;
-extern crate futures;
-extern crate futures_cpupool;
-extern crate tokio_core;
-extern crate tokio_proto;
-extern crate tokio_service;
-extern crate tokio_io;
-extern crate bytes;
-extern crate native_tls;
-extern crate tokio_tls;
-extern crate toml;
-
-mod rem;
+extern crate rem;
 
 use std::string::String;
 use std::env;
@@ -25,23 +6,38 @@ use std::env::Args;
 
 use rem::error::*;
 use rem::config::Config;
+use rem::script::ScriptOptions;
+use rem::output::OutputFormat;
 
 /// The different run modes for REM
 enum Mode {
     NONE,
     CLIENT,
     SERVER,
+    /// Validates the config file and exits
+    CHECK,
 }
 
 fn main() {
-    env_logger::init().unwrap();
+    let log = match rem::logging::init() {
+        Ok(log) => log,
+        Err(why) => panic!("{}", why),
+    };
 
     let mut args: Args = env::args();
 
-    // Set default values for arguments
-    let mut ip: String = String::from("127.0.0.1");
-    let mut port: String = String::from("8080");
+    // Set default values for arguments, the address defaults to the one in the config
+    let mut ip: Option<String> = None;
+    let mut port: Option<String> = None;
     let mut config_file: String = String::from("rem.toml");
+    let mut script_file: Option<String> = None;
+    let mut continue_on_error: bool = false;
+    let mut pipeline: usize = 1;
+    let mut output: OutputFormat = OutputFormat::RAW;
+    let mut replica_of: Option<String> = None;
+    let mut cluster: Option<Vec<String>> = None;
+    let mut slots: bool = false;
+    let mut gossip: Option<Vec<String>> = None;
 
     let mut mode: Mode = Mode::NONE;
 
@@ -58,15 +54,25 @@ fn main() {
                     "client" => {
                         mode = Mode::CLIENT;
                     }
+                    "config" => {
+                        match args.next().as_ref().map(String::as_str) {
+                            Some("check") => mode = Mode::CHECK,
+                            _ => {
+                                RemError::with_reason_str_and_details(REM_00002,
+                                                                      String::from("config expects check"))
+                                    .log_and_exit();
+                            }
+                        }
+                    }
                     "-port" => {
                         match args.next() {
-                            Some(x) => port = x,
+                            Some(x) => port = Some(x),
                             None => break,
                         }
                     }
                     "-ip" => {
                         match args.next() {
-                            Some(x) => ip = x,
+                            Some(x) => ip = Some(x),
                             None => break,
                         }
                     }
@@ -76,6 +82,74 @@ fn main() {
                             None => break,
                         }
                     }
+                    "-script" => {
+                        match args.next() {
+                            Some(x) => script_file = Some(x),
+                            None => break,
+                        }
+                    }
+                    "-output" => {
+                        match args.next() {
+                            Some(x) => {
+                                match OutputFormat::from_name(x.as_str()) {
+                                    Ok(format) => output = format,
+                                    Err(why) => why.log_and_exit(),
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                    "-replicaof" => {
+                        match args.next() {
+                            Some(x) => replica_of = Some(x),
+                            None => break,
+                        }
+                    }
+                    "-cluster" => {
+                        match args.next() {
+                            Some(x) => {
+                                cluster = Some(x.split(',')
+                                    .filter(|peer| !peer.is_empty())
+                                    .map(String::from)
+                                    .collect())
+                            }
+                            None => break,
+                        }
+                    }
+                    "-gossip" => {
+                        match args.next() {
+                            Some(x) => {
+                                gossip = Some(x.split(',')
+                                    .filter(|seed| !seed.is_empty())
+                                    .map(String::from)
+                                    .collect())
+                            }
+                            None => break,
+                        }
+                    }
+                    "-slots" => {
+                        slots = true;
+                    }
+                    "-continue" => {
+                        continue_on_error = true;
+                    }
+                    "-pipeline" => {
+                        match args.next() {
+                            Some(x) => {
+                                match x.parse::<usize>() {
+                                    Ok(n) if n > 0 => pipeline = n,
+                                    _ => {
+                                        RemError::with_reason_str_and_details(REM_00002,
+                                                                              format!("Pipeline size {} is not a \
+                                                                                       positive integer",
+                                                                                      x))
+                                            .log_and_exit();
+                                    }
+                                }
+                            }
+                            None => break,
+                        }
+                    }
                     _ => {
                         RemError::with_reason_str_and_details(REM_00002,
                                                               format!("Argument {} is not a \
@@ -89,12 +163,49 @@ fn main() {
         }
     }
 
-    // Todo handle errors
-    let config: Config = Config::from_file(config_file).unwrap();
+    let config: Config = match Config::from_file(config_file.clone()) {
+        Ok(config) => config,
+        Err(why) => {
+            why.log_and_exit();
+            return;
+        }
+    };
+    let ip = ip.unwrap_or(config.server.bind.clone());
+    let port = port.unwrap_or(config.server.port.to_string());
 
     match mode {
-        Mode::CLIENT => rem::client::launch(config, ip, port),
-        Mode::SERVER => rem::server::launch(config, ip, port),
+        Mode::CLIENT => {
+            match script_file {
+                Some(file) => {
+                    let options = ScriptOptions {
+                        file: file,
+                        continue_on_error: continue_on_error,
+                        pipeline: pipeline,
+                        output: output,
+                    };
+                    rem::client::launch_script(config, ip, port, options);
+                }
+                None => rem::client::launch(config, ip, port, output),
+            }
+        }
+        Mode::SERVER => {
+            if replica_of.is_some() && cluster.is_some() {
+                RemError::with_reason_str_and_details(REM_00002,
+                                                      String::from("-replicaof and -cluster can not be \
+                                                                    used together"))
+                    .log_and_exit();
+            }
+            if slots && cluster.is_some() {
+                RemError::with_reason_str_and_details(REM_00002,
+                                                      String::from("-slots and -cluster can not be \
+                                                                    used together"))
+                    .log_and_exit();
+            }
+            rem::server::launch(config, config_file, log, ip, port, replica_of, cluster, slots, gossip)
+        }
+        Mode::CHECK => {
+            println!("{} is valid", config_file);
+        }
         Mode::NONE => {
             RemError::with_reason_str(REM_00001).log();
         }
@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, HashMap};
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+
+use rem::cache::Cache;
+use rem::config::Config;
+use rem::op;
+use rem::pubsub::PubSub;
+use rem::replication::{self, Replication, StreamEntry};
+use rem::ring;
+use rem::service;
+use rem::tcp_stream::TcpStream;
+use rem::error::*;
+
+/// How often a replica compares its keys with its primary's
+pub const ANTI_ENTROPY_INTERVAL_SECS: u64 = 60;
+
+/// The number of children of each node of a Merkle tree
+pub const FANOUT: usize = 16;
+
+/// The number of levels below the root, the leaves are the last level
+pub const DEPTH: usize = 3;
+
+/// The number of leaves, FANOUT to the power of DEPTH
+pub const LEAF_COUNT: usize = 4096;
+
+/// A Merkle tree over the keys in the cache
+///
+/// Every key belongs to a leaf picked by hashing the key. A leaf's hash covers the digest of
+/// each of its keys and their values, and the hash of any other node covers the hashes of its
+/// children. Two caches hold the same keys and values when their roots match, and when they
+/// don't only the children that differ need to be compared, down to the leaves that differ
+pub struct MerkleTree {
+    /// The hashes of each level of the tree, the root first and the leaves last
+    levels: Vec<Vec<u64>>,
+    /// The digest of each key grouped by leaf
+    leaves: Vec<BTreeMap<String, u64>>
+}
+
+impl MerkleTree {
+    /// Builds the tree over every key in the cache, the caller must hold the cache lock
+    pub fn build(cache: &Cache) -> Result<MerkleTree, RemError> {
+        let mut digests: Vec<(String, u64)> = Vec::new();
+        for key in try!(cache.keys()) {
+            if let Some(val) = try!(cache.read_value(key.as_str())) {
+                let digest = ring::hash(&replication::encode_change(0, key.as_str(), Some(&val)));
+                digests.push((key, digest));
+            }
+        }
+        return Ok(MerkleTree::from_digests(digests));
+    }
+
+    /// Builds the tree over keys with the digests of their values
+    pub fn from_digests(digests: Vec<(String, u64)>) -> MerkleTree {
+        let mut leaves: Vec<BTreeMap<String, u64>> = (0..LEAF_COUNT).map(|_| BTreeMap::new()).collect();
+        for (key, digest) in digests {
+            let leaf = leaf_for(key.as_str());
+            leaves[leaf].insert(key, digest);
+        }
+        let mut level: Vec<u64> = leaves.iter()
+            .map(|keys| {
+                let digests: Vec<String> = keys.values().map(|digest| format!("{:016x}", digest)).collect();
+                ring::hash(digests.concat().as_bytes())
+            })
+            .collect();
+        let mut levels: Vec<Vec<u64>> = vec![level.clone()];
+        while level.len() > 1 {
+            level = level.chunks(FANOUT)
+                .map(|children| {
+                    let hashes: Vec<String> = children.iter().map(|hash| format!("{:016x}", hash)).collect();
+                    ring::hash(hashes.concat().as_bytes())
+                })
+                .collect();
+            levels.insert(0, level.clone());
+        }
+        return MerkleTree {
+            levels: levels,
+            leaves: leaves
+        };
+    }
+
+    /// Returns the hashes of the nodes at the indexes of a level, the root is level 0
+    pub fn hashes(&self, level: usize, idxs: &[usize]) -> Result<Vec<u64>, RemError> {
+        let hashes = try!(self.levels.get(level).ok_or(RemError::with_reason_str(REM_00015)));
+        let mut res: Vec<u64> = Vec::new();
+        for &idx in idxs {
+            res.push(*try!(hashes.get(idx).ok_or(RemError::with_reason_str(REM_00015))));
+        }
+        return Ok(res);
+    }
+
+    /// Returns the keys of the leaves at the indexes with their digests
+    pub fn keys(&self, leaves: &[usize]) -> Result<Vec<(String, u64)>, RemError> {
+        let mut keys: Vec<(String, u64)> = Vec::new();
+        for &leaf in leaves {
+            let digests = try!(self.leaves.get(leaf).ok_or(RemError::with_reason_str(REM_00015)));
+            keys.extend(digests.iter().map(|(key, digest)| (key.clone(), *digest)));
+        }
+        return Ok(keys);
+    }
+
+    /// Returns the leaves that differ from another tree, remote returns the hashes of the nodes
+    /// at the indexes of a level of the other tree
+    ///
+    /// Starting at the root only the children of the nodes that differ are compared
+    pub fn differing_leaves<F>(&self, mut remote: F) -> Result<Vec<usize>, RemError>
+        where F: FnMut(usize, &[usize]) -> Result<Vec<u64>, RemError>
+    {
+        let mut nodes: Vec<usize> = vec![0];
+        for level in 0..DEPTH + 1 {
+            let remote_hashes = try!(remote(level, &nodes));
+            let local_hashes = try!(self.hashes(level, &nodes));
+            if remote_hashes.len() != local_hashes.len() {
+                return Err(RemError::with_reason_str(REM_00015));
+            }
+            let differing: Vec<usize> = nodes.iter()
+                .zip(local_hashes.iter().zip(remote_hashes.iter()))
+                .filter(|&(_, (local_hash, remote_hash))| local_hash != remote_hash)
+                .map(|(idx, _)| *idx)
+                .collect();
+            if level == DEPTH || differing.is_empty() {
+                return Ok(differing);
+            }
+            nodes = differing.into_iter().flat_map(|idx| idx * FANOUT..(idx + 1) * FANOUT).collect();
+        }
+        return Ok(Vec::new());
+    }
+}
+
+/// The keys a repair changed to match the primary
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The number of leaves whose keys differed
+    pub leaves: usize,
+    /// Keys that were missing or held a different value, they now hold the primary's value
+    pub fixed: Vec<String>,
+    /// Keys that the primary doesn't have, they have been deleted
+    pub deleted: Vec<String>
+}
+
+impl RepairReport {
+    /// Describes the repair, ```leaves```, ```fixed``` and ```deleted``` counts followed by
+    /// ```fixed:{key}``` and ```deleted:{key}``` for each key that was changed
+    pub fn info(&self) -> Vec<String> {
+        let mut info = vec![format!("leaves:{}", self.leaves),
+                            format!("fixed:{}", self.fixed.len()),
+                            format!("deleted:{}", self.deleted.len())];
+        info.extend(self.fixed.iter().map(|key| format!("fixed:{}", key)));
+        info.extend(self.deleted.iter().map(|key| format!("deleted:{}", key)));
+        return info;
+    }
+}
+
+/// Starts the thread that repairs a replica every ANTI_ENTROPY_INTERVAL_SECS
+pub fn start(config: Config,
+             cache: Arc<Mutex<Cache>>,
+             pubsub: Arc<Mutex<PubSub>>,
+             replication: Arc<Mutex<Replication>>) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(ANTI_ENTROPY_INTERVAL_SECS));
+            // There is nothing to compare until the replica has synced with its primary
+            if !replication.lock().unwrap().is_synced() {
+                continue;
+            }
+            match repair(&config, &cache, &pubsub, &replication) {
+                Ok(ref report) if report.fixed.is_empty() && report.deleted.is_empty() => (),
+                Ok(report) => {
+                    warn!("Anti-entropy repaired {} keys and deleted {} keys that had drifted from the primary",
+                          report.fixed.len(),
+                          report.deleted.len())
+                }
+                Err(why) => why.log(),
+            }
+        }
+    });
+}
+
+/// Compares the keys of a replica with its primary and changes any that differ to match it
+///
+/// The replica builds its own tree then asks the primary for the hashes of the root, of the
+/// children of each node that differs and so on down to the leaves. It then asks for the keys
+/// of the leaves that differ and fetches the values of the keys that don't match. The primary
+/// builds its tree once, when it is asked for the root, and answers the rest from it.
+/// Keys changed on the primary while the repair runs may be fixed before their changes are
+/// streamed, the value written is the primary's latest so applying those changes ends on it too
+pub fn repair(config: &Config,
+              cache_mtx: &Mutex<Cache>,
+              pubsub_mtx: &Mutex<PubSub>,
+              replication_mtx: &Mutex<Replication>)
+              -> Result<RepairReport, RemError> {
+    let primary = {
+        let replication = replication_mtx.lock().unwrap();
+        match replication.primary {
+            Some(ref primary) if replication.is_synced() => primary.clone(),
+            Some(_) => return Err(RemError::with_reason_str(REM_00052)),
+            None => return Err(RemError::with_reason_str(REM_00051)),
+        }
+    };
+    let local = {
+        let cache = cache_mtx.lock().unwrap();
+        try!(MerkleTree::build(&cache))
+    };
+    let mut stream = try!(TcpStream::connect(config, primary.as_str()));
+
+    let mut report = RepairReport::default();
+    let nodes = try!(local.differing_leaves(|level, nodes| {
+        let mut req: Vec<Vec<u8>> = vec![level.to_string().into_bytes()];
+        req.extend(nodes.iter().map(|idx| idx.to_string().into_bytes()));
+        return decode_integers(&try!(op::send_request(&mut stream, "MERKLE", &op::encode_list(&req))));
+    }));
+    if nodes.is_empty() {
+        return Ok(report);
+    }
+    report.leaves = nodes.len();
+
+    let req: Vec<Vec<u8>> = nodes.iter().map(|idx| idx.to_string().into_bytes()).collect();
+    let items = try!(op::decode_list(&try!(op::send_request(&mut stream, "MERKLEKEYS", &op::encode_list(&req)))));
+    let mut remote_keys: HashMap<String, u64> = HashMap::new();
+    for pair in items.chunks(2) {
+        if pair.len() != 2 {
+            return Err(RemError::with_reason_str(REM_00015));
+        }
+        let digest = try!(String::from_utf8_lossy(&pair[1]).parse::<u64>());
+        remote_keys.insert(String::from_utf8_lossy(&pair[0]).into_owned(), digest);
+    }
+    let local_keys: HashMap<String, u64> = try!(local.keys(&nodes)).into_iter().collect();
+    let mut stale: Vec<Vec<u8>> = Vec::new();
+    for (key, digest) in &local_keys {
+        if remote_keys.get(key) != Some(digest) {
+            stale.push(key.clone().into_bytes());
+        }
+    }
+    for key in remote_keys.keys() {
+        if !local_keys.contains_key(key) {
+            stale.push(key.clone().into_bytes());
+        }
+    }
+    if stale.is_empty() {
+        return Ok(report);
+    }
+
+    let entries = try!(op::decode_list(&try!(op::send_request(&mut stream, "MERKLEFETCH", &op::encode_list(&stale)))));
+    let mut cache = cache_mtx.lock().unwrap();
+    for entry in entries {
+        let entry = try!(StreamEntry::decode(&entry));
+        match entry {
+            StreamEntry::SET(_, ref key, _) => report.fixed.push(key.clone()),
+            StreamEntry::DEL(_, ref key) => report.deleted.push(key.clone()),
+            StreamEntry::SYNCED(..) => return Err(RemError::with_reason_str(REM_00015)),
+        }
+        try!(entry.apply(&mut cache));
+    }
+    service::publish_key_events(pubsub_mtx, &mut cache);
+    replication_mtx.lock().unwrap().record_repair(report.fixed.len() + report.deleted.len());
+    return Ok(report);
+}
+
+/// Responds to MERKLE, ```[level, idx...]```, with the hash of each node
+///
+/// A request for the root builds the tree the rest of the repair is answered from and keeps it
+/// in the session, so every level and the keys sent come from the same state of the cache
+pub fn merkle_hashes(cache_mtx: &Mutex<Cache>,
+                     session: &Mutex<Option<Arc<MerkleTree>>>,
+                     bytes: &[u8])
+                     -> Result<Vec<u8>, RemError> {
+    let args = try!(decode_integers(bytes));
+    let (level, idxs) = try!(args.split_first().ok_or(RemError::with_reason_str(REM_00015)));
+    let idxs: Vec<usize> = idxs.iter().map(|&idx| idx as usize).collect();
+    let tree = if *level == 0 {
+        let tree = {
+            let cache = cache_mtx.lock().unwrap();
+            Arc::new(try!(MerkleTree::build(&cache)))
+        };
+        *session.lock().unwrap() = Some(tree.clone());
+        tree
+    } else {
+        try!(session_tree(session))
+    };
+    let hashes = try!(tree.hashes(*level as usize, &idxs));
+    let items: Vec<Vec<u8>> = hashes.iter().map(|hash| hash.to_string().into_bytes()).collect();
+    return Ok(op::encode_list(&items));
+}
+
+/// Responds to MERKLEKEYS, a list of leaves, with alternating keys and digests for every key
+/// in those leaves of the tree built for the session
+pub fn merkle_keys(session: &Mutex<Option<Arc<MerkleTree>>>, bytes: &[u8]) -> Result<Vec<u8>, RemError> {
+    let leaves: Vec<usize> = try!(decode_integers(bytes)).into_iter().map(|leaf| leaf as usize).collect();
+    let mut items: Vec<Vec<u8>> = Vec::new();
+    for (key, digest) in try!(try!(session_tree(session)).keys(&leaves)) {
+        items.push(key.into_bytes());
+        items.push(digest.to_string().into_bytes());
+    }
+    return Ok(op::encode_list(&items));
+}
+
+fn session_tree(session: &Mutex<Option<Arc<MerkleTree>>>) -> Result<Arc<MerkleTree>, RemError> {
+    return session.lock().unwrap().clone().ok_or(RemError::with_reason_str(REM_00059));
+}
+
+/// Responds to MERKLEFETCH, a list of keys, with each key encoded as a change that sets it
+/// to its value or deletes it if it doesn't exist
+pub fn merkle_fetch(cache: &Cache, bytes: &[u8]) -> Result<Vec<u8>, RemError> {
+    let mut items: Vec<Vec<u8>> = Vec::new();
+    for key in try!(op::decode_keys(bytes)) {
+        let val = try!(cache.read_value(key.as_str()));
+        items.push(replication::encode_change(0, key.as_str(), val.as_ref()));
+    }
+    return Ok(op::encode_list(&items));
+}
+
+fn leaf_for(key: &str) -> usize {
+    return (ring::hash(key.as_bytes()) % LEAF_COUNT as u64) as usize;
+}
+
+/// Decodes a list of non negative integers, hashes are sent as base 10
+fn decode_integers(bytes: &[u8]) -> Result<Vec<u64>, RemError> {
+    let mut integers: Vec<u64> = Vec::new();
+    for item in try!(op::decode_list(bytes)) {
+        let integer = try!(String::from_utf8_lossy(&item).parse::<u64>());
+        integers.push(integer);
+    }
+    return Ok(integers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaf_for, MerkleTree, DEPTH, FANOUT, LEAF_COUNT};
+
+    fn tree(keys: &[(&str, u64)]) -> MerkleTree {
+        return MerkleTree::from_digests(keys.iter().map(|&(key, digest)| (String::from(key), digest)).collect());
+    }
+
+    fn diff(local: &MerkleTree, remote: &MerkleTree) -> Vec<usize> {
+        return local.differing_leaves(|level, nodes| remote.hashes(level, nodes)).unwrap();
+    }
+
+    #[test]
+    fn the_tree_has_a_level_for_each_depth() {
+        assert_eq!(FANOUT.pow(DEPTH as u32), LEAF_COUNT);
+        let empty = tree(&[]);
+        assert_eq!(empty.hashes(0, &[0]).unwrap().len(), 1);
+        assert_eq!(empty.hashes(DEPTH, &[LEAF_COUNT - 1]).unwrap().len(), 1);
+        assert!(empty.hashes(DEPTH, &[LEAF_COUNT]).is_err());
+        assert!(empty.hashes(DEPTH + 1, &[0]).is_err());
+    }
+
+    #[test]
+    fn matching_trees_have_no_differing_leaves() {
+        let keys = [("a", 1), ("b", 2), ("c", 3)];
+        assert_eq!(tree(&keys).hashes(0, &[0]).unwrap(), tree(&keys).hashes(0, &[0]).unwrap());
+        assert!(diff(&tree(&keys), &tree(&keys)).is_empty());
+        assert!(diff(&tree(&[]), &tree(&[])).is_empty());
+    }
+
+    #[test]
+    fn a_changed_value_differs_only_in_its_leaf() {
+        let local = tree(&[("a", 1), ("b", 2), ("c", 3)]);
+        let remote = tree(&[("a", 1), ("b", 20), ("c", 3)]);
+        assert_eq!(diff(&local, &remote), vec![leaf_for("b")]);
+        assert_eq!(remote.keys(&[leaf_for("b")]).unwrap(), vec![(String::from("b"), 20)]);
+    }
+
+    #[test]
+    fn a_missing_key_differs_in_its_leaf_on_either_side() {
+        let local = tree(&[("a", 1), ("b", 2)]);
+        let remote = tree(&[("a", 1), ("b", 2), ("c", 3)]);
+        assert_eq!(diff(&local, &remote), vec![leaf_for("c")]);
+        assert_eq!(diff(&remote, &local), vec![leaf_for("c")]);
+        assert!(local.keys(&[leaf_for("c")]).unwrap().iter().all(|&(ref key, _)| key != "c"));
+    }
+
+    #[test]
+    fn every_differing_leaf_is_found() {
+        let keys: Vec<String> = (0..1000).map(|idx| format!("key{}", idx)).collect();
+        let local = MerkleTree::from_digests(keys.iter().map(|key| (key.clone(), 1)).collect());
+        let remote = MerkleTree::from_digests(keys.iter()
+            .enumerate()
+            .map(|(idx, key)| (key.clone(), if idx % 100 == 0 { 2 } else { 1 }))
+            .collect());
+        let mut expected: Vec<usize> = (0..10).map(|idx| leaf_for(format!("key{}", idx * 100).as_str())).collect();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(diff(&local, &remote), expected);
+    }
+}
@@ -1,65 +1,207 @@
 use std::io::prelude::*;
 use std::io;
-use std::fs::File;
-use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::Bound::{Excluded, Included, Unbounded};
 use std::path::Path;
 use std::fs; 
+use std::mem;
 
-use rem::error::RemError;
+use rem::error::*;
+use rem::glob;
+use rem::op;
+use rem::value::{self, Value};
 
 pub const CACHE_DIR: &'static str = "_cache";
 
+/// The undo log of the running transaction, kept outside of the cache directory so it is never
+/// mistaken for a key
+pub const UNDO_LOG: &'static str = "_cache.undo";
+
+/// Structured values are stored in a directory per type next to the cache directory,
+/// ex: lists are stored in _cache.list, so a key can't be confused with another type
+fn type_dir(type_name: &str) -> String {
+    return format!("{}.{}", CACHE_DIR, type_name);
+}
+
+/// Returns the path of the file holding a value of the named type
+fn file_path(key: &str, type_name: &str) -> String {
+    if type_name == "string" {
+        return format!("{}/{}", CACHE_DIR, key);
+    }
+    return format!("{}/{}", type_dir(type_name), key);
+}
+
 /// A structure to store a series of cache operations and a value
 /// Cache operations are represented by a single character
 pub struct CacheOperation {
     pub commands: Vec<char>,
-    pub value: String
+    pub value: Vec<u8>
 }
 
 impl CacheOperation {
-    /// Creates a new CacheOperation instance from an input string
+    /// Creates a new CacheOperation instance from the bytes of a request
     /// Stores the commands and corresponding value
     /// Write Example: ```W$abc:def```
     /// The resulting command would be W with a value of abc:def
-    pub fn new_from_string(cache_op_str: &String) -> CacheOperation {
+    ///
+    /// The value is kept as raw bytes since written values may be binary
+    pub fn new_from_bytes(cache_op_bytes: &[u8]) -> CacheOperation {
         let mut cache_op = CacheOperation {
             commands: Vec::new(),
-            value: String::new(),
+            value: Vec::new(),
         };
-        let mut idx = 0;
-        let chars_iter = cache_op_str.chars();
-        // Read the string until $ is found
-        for c in chars_iter {
-            idx += 1;
-            if c == '$' {
+        // Read the bytes until $ is found
+        for (idx, b) in cache_op_bytes.iter().enumerate() {
+            if *b == b'$' {
                 // Set the value to everything after $
-                cache_op.value = String::from(&cache_op_str[idx..]);
+                cache_op.value = cache_op_bytes[idx + 1..].to_vec();
                 break;
             }
             // Add a new command for each iteration where $ hasn't been found yet
-            cache_op.commands.push(c);
+            cache_op.commands.push(*b as char);
         }
         return cache_op;
     }
+
+    /// Returns the name of the command, every character before the $
+    ///
+    /// Single character commands such as W are the original commands, longer names
+    /// such as INCR are used for everything else
+    pub fn command(&self) -> String {
+        return self.commands.iter().cloned().collect();
+    }
+
+    /// Splits the value into a key and an argument at the first :
+    /// ex: a value of abc:5 would be split into abc and 5
+    pub fn key_and_arg(&self) -> Result<(String, Vec<u8>), RemError> {
+        let split = self.value.iter().position(|&b| b == b':').unwrap_or(self.value.len());
+        let key: String = match String::from_utf8(self.value[..split].to_vec()) {
+            Ok(key) => key,
+            Err(_) => return Err(RemError::with_reason_str(REM_00005)),
+        };
+        let arg: Vec<u8> = if split < self.value.len() {
+            self.value[split + 1..].to_vec()
+        } else {
+            Vec::new()
+        };
+        return Ok((key, arg));
+    }
+
+    /// Returns the value as a string, for operations where the value is a key
+    ///
+    /// Keys are used as file names so they must be valid UTF-8
+    pub fn value_str(&self) -> Result<String, RemError> {
+        match String::from_utf8(self.value.clone()) {
+            Ok(key) => Ok(key),
+            Err(_) => Err(RemError::with_reason_str(REM_00005)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CachePair{
     pub key: String,
-    pub val: Vec<u8>
+    pub val: Value
+}
+
+/// The condition that must hold for a conditional write to be performed
+#[derive(Debug, Clone, Copy)]
+pub enum WriteCondition {
+    /// The key must not have a value
+    ABSENT,
+    /// The key must have a value
+    PRESENT,
+    /// The current version of the key must match
+    VERSION(u64),
+}
+
+/// An entry in the undo log, the value and version a key had before it was changed
+/// by a transaction
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    key: String,
+    val: Option<Value>,
+    version: Option<u64>
+}
+
+/// A change made to a key, published as a keyspace notification
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    /// The key was written, whatever the type of its value
+    SET,
+    /// The key was deleted
+    DEL,
+}
+
+impl KeyEvent {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyEvent::SET => "set",
+            KeyEvent::DEL => "del",
+        }
+    }
+}
+
+/// The memory used by the values of one type
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryUsage {
+    pub keys: usize,
+    /// The bytes of the keys and the data in their values
+    pub bytes: usize
 }
 
 /// Cache object -- Simple wrapper around a map
+///
+/// Every key has a version which changes each time the key is written or deleted. Versions are
+/// taken from a counter shared by every key so a key that is deleted and written again never
+/// reuses a version. A deleted key keeps the version of its delete as a tombstone so a watch on it
+/// sees the delete and any later write, even when the key is absent again by then.
+/// Versions are only kept in memory, a key that was loaded from the file store has version 1
+/// and a key that has never had a value in this process has version 0
 #[derive(Debug, Clone)]
 pub struct Cache {
-    pub map_internal: HashMap<String, Vec<u8>>,
-    pub pending: Option<CachePair>
+    pub map_internal: HashMap<String, Value>,
+    pub versions: HashMap<String, u64>,
+    pub next_version: u64,
+    pub pending: Option<CachePair>,
+    /// Keyspace events are only recorded when this is true
+    pub keyspace_events: bool,
+    /// Each write is synced to disk before it returns when this is true
+    pub sync_writes: bool,
+    /// The events recorded since they were last taken
+    events: Vec<(KeyEvent, String)>,
+    /// Changes are only recorded when this is true, a primary records them for its replicas
+    pub record_changes: bool,
+    /// The changes recorded since they were last taken, the value is None for a delete
+    changes: Vec<(String, Option<Value>)>,
+    /// The number of keys and bytes held in memory for each type of value
+    memory: BTreeMap<&'static str, MemoryUsage>,
+    /// The undo entries of the running transaction, None when no transaction is running
+    undo: Option<Vec<UndoEntry>>,
+    /// Every key with a value in either store, sorted so a scan can seek to its cursor.
+    /// Loaded from the file store by recover
+    index: BTreeSet<String>
 }
 
 impl Cache {
    
     pub fn new() -> Cache {
-        return Cache { map_internal: HashMap::new(), pending: None };
+        return Cache {
+            map_internal: HashMap::new(),
+            versions: HashMap::new(),
+            // Version 1 is used for keys loaded from the file store
+            next_version: 2,
+            pending: None,
+            keyspace_events: false,
+            sync_writes: false,
+            events: Vec::new(),
+            record_changes: false,
+            changes: Vec::new(),
+            memory: BTreeMap::new(),
+            undo: None,
+            index: BTreeSet::new()
+        };
     }
 
     /// Writes the provided value to the cache using the provided key
@@ -70,6 +212,13 @@ impl Cache {
     ///
     /// If a value for the provided key already exists it will be overwritten
     pub fn cache_item(&mut self, key: &str, val: Vec<u8>) -> Result<(), RemError> {
+        return self.cache_value(key, Value::BYTES(val));
+    }
+
+    /// Writes a value of any type to the cache using the provided key
+    ///
+    /// Any existing value is overwritten, even if it is of a different type
+    pub fn cache_value(&mut self, key: &str, val: Value) -> Result<(), RemError> {
         try!(self.create_cache_dir());
         if self.lock_exists(key){
             self.pending = Some(CachePair{
@@ -78,44 +227,151 @@ impl Cache {
             });
             return Ok(());
         }
+        try!(self.record_undo(key));
         try!(self.create_lock_file(&key));
-        let mut f: File = File::create(format!("{}/{}", CACHE_DIR, key)).unwrap();
-        try!(f.write(&val.as_slice()));
-        try!(f.flush());
-        self.map_internal.insert(String::from(key), val);
+        try!(self.write_file(key, &val));
+        let version = self.next_version;
+        self.next_version += 1;
+        if self.record_changes {
+            self.changes.push((String::from(key), Some(val.clone())));
+        }
+        self.insert_value(key, val);
+        self.index.insert(String::from(key));
+        self.versions.insert(String::from(key), version);
+        self.record_event(KeyEvent::SET, key);
         try!(self.delete_lock_file(&key));
         if let Some(cache_pair) = self.pending.clone() {
-            try!(self.cache_item(cache_pair.key.as_str(), cache_pair.val));
+            try!(self.cache_value(cache_pair.key.as_str(), cache_pair.val));
             self.pending = None;
         }
         return Ok(());
     }
 
-    /// Reads a value from the cache
+    /// Writes the provided value to the cache only if the condition holds
+    ///
+    /// Returns the new version of the key, or an error if the condition did not hold.
+    /// A conditional write is never held back in pending, if the key is locked it fails
+    /// instead so no version is returned for a value that hasn't been written
+    pub fn cache_item_if(&mut self,
+                         key: &str,
+                         val: Vec<u8>,
+                         condition: WriteCondition)
+                         -> Result<u64, RemError> {
+        let current = self.version(key);
+        let holds = match condition {
+            WriteCondition::ABSENT => current == 0,
+            WriteCondition::PRESENT => current != 0,
+            WriteCondition::VERSION(expected) => current == expected,
+        };
+        if !holds {
+            return Err(RemError::with_reason(format!("{}: {:?} expected but the current version \
+                                                      of {} is {}",
+                                                     REM_00014,
+                                                     condition,
+                                                     key,
+                                                     current)));
+        }
+        if self.lock_exists(key) {
+            return Err(RemError::with_reason(format!("{}: {}", REM_00061, key)));
+        }
+        try!(self.cache_item(key, val));
+        return Ok(self.version(key));
+    }
+
+    /// Returns the current version of the key, 0 if the key has no value
+    pub fn version(&self, key: &str) -> u64 {
+        if !self.contains_key(key) {
+            return 0;
+        }
+        return self.versions.get(key).cloned().unwrap_or(1);
+    }
+
+    /// Returns the version of the key's last write or delete, which is what WATCH compares
+    ///
+    /// Unlike version this changes when the key is deleted, so a key that is written and then
+    /// deleted again doesn't look unchanged to a transaction watching it
+    pub fn watch_version(&self, key: &str) -> u64 {
+        return match self.versions.get(key) {
+            Some(version) => *version,
+            None => if self.contains_key(key) { 1 } else { 0 },
+        };
+    }
+
+    /// Checks if the key has a value in either the in memory map or the file store
+    pub fn contains_key(&self, key: &str) -> bool {
+        return self.index.contains(key);
+    }
+
+    /// Reads a plain value from the cache
     ///
     /// If the key is found in the in memory map then the corresponding value is returned
     ///
     /// If the key cannot be found in the map then an attempt will be made to load the value
     /// from the file corresponding with the key
+    ///
+    /// Reading a key that holds a structured value such as a list is an error
     pub fn read_item(&self, key: String) -> Result<Option<Box<Vec<u8>>>, RemError> {
-        let res: Option<Box<Vec<u8>>>;
-        if self.map_internal.contains_key(&key) {
-            let res_opt = self.map_internal.get(&key);
-            match res_opt {
-                Some(vec_ref) => res = Some(Box::new(vec_ref.clone())),
-                None => res = None,
-            }
-        } else {
+        match try!(self.read_value(key.as_str())) {
+            Some(Value::BYTES(val)) => Ok(Some(Box::new(val))),
+            Some(other) => Err(value::wrong_type(key.as_str(), &other)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a value of any type from the in memory map, or the file store if the key
+    /// isn't in the map
+    pub fn read_value(&self, key: &str) -> Result<Option<Value>, RemError> {
+        if let Some(val) = self.map_internal.get(key) {
+            return Ok(Some(val.clone()));
+        }
+        for type_name in value::TYPES {
             let mut buf: Vec<u8> = Vec::new();
-            match File::open(format!("{}/{}", CACHE_DIR, key)) {
-                Err(_) => res = None,
+            match File::open(file_path(key, type_name)) {
+                Err(_) => continue,
                 Ok(mut file) => {
                     try!(file.read_to_end(&mut buf));
-                    res = Some(Box::new(buf));
+                    return Ok(Some(try!(Value::decode(type_name, buf))));
                 }
             };
         }
-        return Ok(res);
+        return Ok(None);
+    }
+
+    /// Makes sure every value held in memory has reached the disk, called when the server
+    /// shuts down. The caller must hold the cache lock so no write is in progress
+    ///
+    /// A write that was held back in pending by a lock file is made first
+    pub fn flush(&mut self) -> Result<(), RemError> {
+        if let Some(cache_pair) = self.pending.take() {
+            try!(self.delete_lock_file(cache_pair.key.as_str()));
+            try!(self.cache_value(cache_pair.key.as_str(), cache_pair.val));
+        }
+        for (key, val) in &self.map_internal {
+            if let Ok(f) = File::open(file_path(key, val.type_name())) {
+                try!(f.sync_all());
+            }
+        }
+        return Ok(());
+    }
+
+    /// Removes every lock file and returns the number removed
+    ///
+    /// A lock file only exists while a write is in progress, one left behind by a write that
+    /// was interrupted would hold back every later write to its key. The caller must hold the
+    /// cache lock so no write is in progress
+    pub fn remove_lock_files(&self) -> Result<usize, RemError> {
+        if !Path::new(CACHE_DIR).exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in try!(fs::read_dir(CACHE_DIR)) {
+            let path = try!(entry).path();
+            if path.extension().map(|ext| ext == "lock").unwrap_or(false) {
+                try!(fs::remove_file(&path));
+                removed += 1;
+            }
+        }
+        return Ok(removed);
     }
 
     /// Delete's an item from the cache
@@ -124,16 +380,287 @@ impl Cache {
     ///
     /// The file corresponding to the key will also be deleted
     pub fn delete_item(&mut self, key: String) -> Result<(), RemError> {
-        if self.map_internal.contains_key(&key) {
-            self.map_internal.remove(&key);
+        try!(self.record_undo(key.as_str()));
+        if self.contains_key(key.as_str()) {
+            self.record_event(KeyEvent::DEL, key.as_str());
+            if self.record_changes {
+                self.changes.push((key.clone(), None));
+            }
+            // The tombstone
+            let version = self.next_version;
+            self.next_version += 1;
+            self.versions.insert(key.clone(), version);
         }
-        let path = format!("{}/{}", CACHE_DIR, key);
-        if Path::new(&path).exists() {
-            try!(self.delete_file(path.as_str()));
+        self.remove_value(key.as_str());
+        self.index.remove(&key);
+        return self.delete_files(key.as_str());
+    }
+
+
+    /// Returns every key in the cache, sorted
+    pub fn keys(&self) -> Result<Vec<String>, RemError> {
+        return Ok(self.index.iter().cloned().collect());
+    }
+
+    /// Returns every key in the cache that starts with the provided prefix, sorted
+    pub fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, RemError> {
+        return Ok(self.index
+            .range::<str, _>((Included(prefix), Unbounded))
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect());
+    }
+
+    /// Returns every key in the cache that matches the glob pattern, sorted
+    pub fn keys_matching(&self, pattern: &str) -> Result<Vec<String>, RemError> {
+        let keys = try!(self.keys_with_prefix(glob::literal_prefix(pattern).as_str()));
+        return Ok(keys.into_iter().filter(|key| glob::matches(pattern, key)).collect());
+    }
+
+    /// Returns up to count keys matching the glob pattern which sort after the cursor
+    ///
+    /// An empty cursor starts from the first key. The cursor to continue from is returned
+    /// with the keys and is empty once every key has been scanned.
+    /// Keys written during a scan are returned if they sort after the cursor
+    ///
+    /// The scan seeks into the key index so a page costs the keys it passes over, not every key
+    pub fn scan(&self,
+                cursor: &str,
+                pattern: &str,
+                count: usize)
+                -> Result<(String, Vec<String>), RemError> {
+        let prefix = glob::literal_prefix(pattern);
+        // Keys before the prefix can never match so the scan starts from whichever is later
+        let start = if prefix.as_str() > cursor { Included(prefix.as_str()) } else { Excluded(cursor) };
+        let mut page: Vec<String> = Vec::new();
+        let mut remaining = false;
+        for key in self.index.range::<str, _>((start, Unbounded)) {
+            if !key.starts_with(prefix.as_str()) {
+                break;
+            }
+            if !glob::matches(pattern, key.as_str()) {
+                continue;
+            }
+            if page.len() == count {
+                remaining = true;
+                break;
+            }
+            page.push(key.clone());
+        }
+        let next = if remaining {
+            page.last().cloned().unwrap_or(String::new())
+        } else {
+            String::new()
+        };
+        return Ok((next, page));
+    }
+
+    /// Deletes every key that starts with the prefix and returns the number of keys removed
+    pub fn delete_with_prefix(&mut self, prefix: &str) -> Result<usize, RemError> {
+        let keys = try!(self.keys_with_prefix(prefix));
+        return self.delete_items(keys);
+    }
+
+    /// Deletes every key that matches the glob pattern and returns the number of keys removed
+    pub fn delete_matching(&mut self, pattern: &str) -> Result<usize, RemError> {
+        let keys = try!(self.keys_matching(pattern));
+        return self.delete_items(keys);
+    }
+
+    fn delete_items(&mut self, keys: Vec<String>) -> Result<usize, RemError> {
+        let count = keys.len();
+        for key in keys {
+            try!(self.delete_item(key));
+        }
+        return Ok(count);
+    }
+
+    /// Deletes every key in the cache and returns the number of keys removed
+    pub fn clear(&mut self) -> Result<usize, RemError> {
+        let keys = try!(self.keys());
+        return self.delete_items(keys);
+    }
+
+    /// Returns the number of keys in the cache
+    pub fn count(&self) -> Result<usize, RemError> {
+        return Ok(self.index.len());
+    }
+
+    /// Returns the memory used by the values held in memory for every type, in the order of
+    /// value::TYPES. Values that are only in the file store are not counted
+    pub fn memory_usage(&self) -> Vec<(&'static str, MemoryUsage)> {
+        return value::TYPES.iter()
+            .map(|type_name| (*type_name, self.memory.get(type_name).cloned().unwrap_or_default()))
+            .collect();
+    }
+
+    /// Returns the keyspace events recorded since the last call, oldest first
+    pub fn take_events(&mut self) -> Vec<(KeyEvent, String)> {
+        return mem::replace(&mut self.events, Vec::new());
+    }
+
+    /// Returns the changes recorded since the last call, oldest first
+    pub fn take_changes(&mut self) -> Vec<(String, Option<Value>)> {
+        return mem::replace(&mut self.changes, Vec::new());
+    }
+
+    fn record_event(&mut self, event: KeyEvent, key: &str) {
+        if self.keyspace_events {
+            self.events.push((event, String::from(key)));
+        }
+    }
+
+    /// Inserts a value into the in memory map, keeping the memory accounting up to date
+    fn insert_value(&mut self, key: &str, val: Value) {
+        self.remove_value(key);
+        {
+            let usage = self.memory.entry(val.type_name()).or_insert(MemoryUsage::default());
+            usage.keys += 1;
+            usage.bytes += key.len() + val.size();
+        }
+        self.map_internal.insert(String::from(key), val);
+    }
+
+    /// Removes a value from the in memory map, keeping the memory accounting up to date
+    fn remove_value(&mut self, key: &str) {
+        if let Some(val) = self.map_internal.remove(key) {
+            if let Some(usage) = self.memory.get_mut(val.type_name()) {
+                usage.keys -= 1;
+                usage.bytes -= key.len() + val.size();
+            }
         }
+    }
+
+    /// Starts a transaction
+    ///
+    /// Until the transaction is committed the previous value of every key that is written or
+    /// deleted is appended to the undo log before the change is made. If the server stops before
+    /// the transaction is committed recover will restore those values so either every change
+    /// in the transaction is kept or none are
+    pub fn begin_transaction(&mut self) -> Result<(), RemError> {
+        try!(self.delete_file(UNDO_LOG));
+        self.undo = Some(Vec::new());
         return Ok(());
     }
 
+    /// Commits the running transaction, making its changes permanent
+    pub fn commit_transaction(&mut self) -> Result<(), RemError> {
+        self.undo = None;
+        return self.delete_file(UNDO_LOG);
+    }
+
+    /// Restores the values recorded in the undo log left behind by a transaction that was
+    /// never committed, then loads the key index from the file store.
+    /// Must be called before the cache is used
+    ///
+    /// Returns true if a transaction was rolled back
+    pub fn recover(&mut self) -> Result<bool, RemError> {
+        let rolled_back = try!(self.roll_back());
+        try!(self.load_index());
+        return Ok(rolled_back);
+    }
+
+    fn roll_back(&mut self) -> Result<bool, RemError> {
+        if !Path::new(UNDO_LOG).exists() {
+            return Ok(false);
+        }
+        let mut buf: Vec<u8> = Vec::new();
+        let mut f: File = try!(File::open(UNDO_LOG));
+        try!(f.read_to_end(&mut buf));
+        let entries = decode_undo_entries(&buf);
+        warn!("Rolling back {} changes from an uncommitted transaction", entries.len());
+        self.undo = None;
+        try!(self.create_cache_dir());
+        // Restore in reverse so the oldest value of a key that was changed twice wins
+        for entry in entries.into_iter().rev() {
+            match entry.val {
+                Some(val) => {
+                    try!(self.write_file(entry.key.as_str(), &val));
+                    self.insert_value(entry.key.as_str(), val);
+                }
+                None => {
+                    self.remove_value(entry.key.as_str());
+                    try!(self.delete_files(entry.key.as_str()));
+                }
+            }
+            match entry.version {
+                Some(version) => self.versions.insert(entry.key, version),
+                None => self.versions.remove(&entry.key),
+            };
+        }
+        try!(self.delete_file(UNDO_LOG));
+        return Ok(true);
+    }
+
+    /// Rebuilds the key index from the in memory map and the file store of every type
+    fn load_index(&mut self) -> Result<(), RemError> {
+        let mut index: BTreeSet<String> = self.map_internal.keys().cloned().collect();
+        for type_name in value::TYPES {
+            let dir = if *type_name == "string" { String::from(CACHE_DIR) } else { type_dir(type_name) };
+            if !Path::new(&dir).exists() {
+                continue;
+            }
+            for entry_res in try!(fs::read_dir(&dir)) {
+                let entry = try!(entry_res);
+                if let Some(name) = entry.file_name().to_str() {
+                    // Lock files are not keys
+                    if !name.ends_with(".lock") {
+                        index.insert(String::from(name));
+                    }
+                }
+            }
+        }
+        self.index = index;
+        return Ok(());
+    }
+
+    /// Appends the current value of the key to the undo log if a transaction is running
+    ///
+    /// The log is synced to disk before returning so the entry is never lost if the change
+    /// it protects has been made
+    fn record_undo(&mut self, key: &str) -> Result<(), RemError> {
+        if self.undo.is_none() {
+            return Ok(());
+        }
+        let entry = UndoEntry {
+            key: String::from(key),
+            val: try!(self.read_value(key)),
+            version: self.versions.get(key).cloned()
+        };
+        let mut f: File = try!(OpenOptions::new().create(true).append(true).open(UNDO_LOG));
+        try!(f.write_all(&encode_undo_entry(&entry)));
+        try!(f.sync_data());
+        if let Some(ref mut undo) = self.undo {
+            undo.push(entry);
+        }
+        return Ok(());
+    }
+
+    /// Writes the value to the file for the key and its type, removing the file of any
+    /// other type the key held before
+    ///
+    /// Assumes that the cache directory exists
+    fn write_file(&self, key: &str, val: &Value) -> Result<(), RemError> {
+        try!(self.delete_files(key));
+        if val.type_name() != "string" {
+            try!(fs::create_dir_all(type_dir(val.type_name())));
+        }
+        let mut f: File = try!(File::create(file_path(key, val.type_name())));
+        try!(f.write_all(&val.encode()));
+        try!(f.flush());
+        if self.sync_writes {
+            try!(f.sync_data());
+        }
+        return Ok(());
+    }
+
+    /// Deletes the file holding the value of the key, whatever its type
+    fn delete_files(&self, key: &str) -> Result<(), RemError> {
+        for type_name in value::TYPES {
+            try!(self.delete_file(file_path(key, type_name).as_str()));
+        }
+        return Ok(());
+    }
 
     /// Creates the cache directory, returning an error if permission is denired
     fn create_cache_dir(&self) -> Result<(), RemError> {
@@ -180,3 +707,51 @@ impl Cache {
         Ok(())
     }
 }
+
+/// Encodes an undo entry as a single list item so entries can be appended to the undo log
+///
+/// The second field is the type of the previous value, empty if the key had no value
+fn encode_undo_entry(entry: &UndoEntry) -> Vec<u8> {
+    let fields: Vec<Vec<u8>> = vec![entry.key.clone().into_bytes(),
+                                    entry.val.as_ref().map(|val| val.type_name()).unwrap_or("").as_bytes().to_vec(),
+                                    entry.val.as_ref().map(|val| val.encode()).unwrap_or(Vec::new()),
+                                    entry.version.map(|v| v.to_string()).unwrap_or(String::new()).into_bytes()];
+    return op::encode_list(&[op::encode_list(&fields)]);
+}
+
+/// Decodes the entries of the undo log
+///
+/// The server may have stopped part way through appending the last entry, so decoding stops
+/// at the first entry that is incomplete. The change that entry protected was never made
+fn decode_undo_entries(bytes: &[u8]) -> Vec<UndoEntry> {
+    let mut entries: Vec<UndoEntry> = Vec::new();
+    let mut rest: &[u8] = bytes;
+    while let Some((item, next)) = op::decode_item(rest) {
+        rest = next;
+        let fields = match op::decode_list(&item) {
+            Ok(fields) => fields,
+            Err(_) => break,
+        };
+        if fields.len() != 4 {
+            break;
+        }
+        let key = match String::from_utf8(fields[0].clone()) {
+            Ok(key) => key,
+            Err(_) => break,
+        };
+        let val = if fields[1].is_empty() {
+            None
+        } else {
+            match Value::decode(&String::from_utf8_lossy(&fields[1]), fields[2].clone()) {
+                Ok(val) => Some(val),
+                Err(_) => break,
+            }
+        };
+        entries.push(UndoEntry {
+            key: key,
+            val: val,
+            version: op::parse_integer(&fields[3]).ok().map(|v| v as u64)
+        });
+    }
+    return entries;
+}
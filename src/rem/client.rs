@@ -1,61 +1,222 @@
-use std::io::prelude::*;
-use std::io;
+use std::env;
 use std::string::String;
 use std::vec::Vec;
 use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+use rustyline;
+use rustyline::Editor;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
 
 use rem::tcp_stream::TcpStream;
 use rem::config::Config;
+use rem::script;
+use rem::script::ScriptOptions;
+use rem::output;
+use rem::output::{OutputFormat, Response};
 
 use rem::op;
+use rem::service::ERROR;
 use rem::error::*;
 
-pub fn launch(config: Config, ip: String, port: String) {
-    match TcpStream::connect(&config, format!("{}:{}", ip, port).as_str()) {
-        Ok(mut stream) => {
+/// The commands understood by the interactive client, used for help and tab completion
+const COMMANDS: &'static [&'static str] = &["write", "read", "delete", "incr", "decr", "incrby",
+                                             "readv", "setnx", "setxx", "setv", "mget", "mset", "mdel",
+                                             "scan", "keys", "dbsize", "delprefix", "delmatch",
+                                             "multi", "exec", "discard", "watch", "unwatch",
+                                             "lpush", "rpush", "lpop", "rpop", "lrange", "llen",
+                                             "blpop", "brpop", "hset", "hget", "hdel", "hgetall",
+                                             "hincrby", "sadd", "srem", "sismember", "smembers", "sinter",
+                                             "zadd", "zrange", "zrangebyscore", "zincrby", "memory",
+                                             "publish", "subscribe", "psubscribe", "unsubscribe",
+                                             "punsubscribe", "replication", "raft", "slots", "slotset", "migrate", "members", "repair",
+                                             "config",
+                                             "help", "quit", "exit"];
+
+/// The commands whose first argument is a key, these are completed using the keys on the server
+const KEY_COMMANDS: &'static [&'static str] = &["write", "read", "delete", "incr", "decr", "incrby",
+                                                 "readv", "setnx", "setxx", "setv", "mget", "mset",
+                                                 "mdel", "watch", "lpush", "rpush", "lpop", "rpop",
+                                                 "lrange", "llen", "blpop", "brpop", "hset", "hget",
+                                                 "hdel", "hgetall", "hincrby", "sadd", "srem", "sismember",
+                                                 "smembers", "sinter", "zadd", "zrange", "zrangebyscore",
+                                                 "zincrby"];
+
+const HELP: &'static str = "Commands:
+  write <key> <value>   Writes value to the cache under key
+  read <key>            Reads the value stored under key
+  delete <key>          Deletes the value stored under key
+  incr <key>            Adds 1 to the integer stored under key, a missing key counts as 0
+  decr <key>            Subtracts 1 from the integer stored under key
+  incrby <key> <n>      Adds n, which may be negative, to the integer stored under key
+  readv <key>           Reads the value stored under key prefixed by its version ex: 3:value
+  setnx <key> <value>   Writes value only if key has no value, prints the new version
+  setxx <key> <value>   Writes value only if key already has a value, prints the new version
+  setv <key> <version> <value>
+                        Writes value only if the version of key matches, 0 means no value
+  mget <key>...         Reads every key, printing one result per key
+  mset <key> <value>... Writes every key value pair
+  mdel <key>...         Deletes every key, printing 1 for each key that had a value and 0 otherwise
+  scan <cursor> [pattern] [count]
+                        Prints the next cursor followed by up to count (default 10) keys
+                        matching the glob pattern (default *), start and finish with cursor 0
+  keys [pattern]        Prints every key matching the glob pattern (default *)
+  dbsize                Prints the number of keys in the cache
+  delprefix <prefix>    Deletes every key starting with prefix, prints the number deleted
+  delmatch <pattern>    Deletes every key matching the glob pattern, prints the number deleted
+  lpush <key> <value>...
+                        Pushes each value onto the start of the list, prints the new length
+  rpush <key> <value>...
+                        Pushes each value onto the end of the list, prints the new length
+  lpop <key>            Removes and prints the first element of the list
+  rpop <key>            Removes and prints the last element of the list
+  lrange <key> <start> <stop>
+                        Prints the elements from start to stop, -1 is the last element
+  llen <key>            Prints the length of the list
+  blpop <key> [timeout] Like lpop but waits up to timeout seconds (default 0, forever)
+                        for an element when the list is empty
+  brpop <key> [timeout] Like rpop but waits for an element when the list is empty
+  hset <key> <field> <value>...
+                        Sets each field of the hash, prints the number of fields added
+  hget <key> <field>    Reads a field of the hash
+  hdel <key> <field>... Deletes each field of the hash, prints the number deleted
+  hgetall <key>         Prints every field of the hash followed by its value
+  hincrby <key> <field> <n>
+                        Adds n to the integer stored in a field of the hash
+  sadd <key> <member>...
+                        Adds each member to the set, prints the number added
+  srem <key> <member>...
+                        Removes each member from the set, prints the number removed
+  sismember <key> <member>
+                        Prints 1 if member is in the set and 0 otherwise
+  smembers <key>        Prints every member of the set
+  sinter <key>...       Prints the members found in every set
+  zadd <key> <score> <member>...
+                        Adds each member to the sorted set with its score, prints the number added
+  zrange <key> <start> <stop>
+                        Prints the members ranked start to stop by score, each followed by its score
+  zrangebyscore <key> <min> <max>
+                        Prints the members with a score from min to max, each followed by its score
+  zincrby <key> <n> <member>
+                        Adds n to the score of member in the sorted set
+  memory                Prints the keys and bytes held in memory for each type ex: list:2:140
+  replication           Prints the role of the server, its replication offset and the lag of
+                        each replica, or of a replica behind its primary
+  repair                Compares the keys of a replica with its primary and fixes any that differ,
+                        prints the number of keys fixed and deleted followed by each key
+  config reload         Reloads the server's config file, prints each setting that changed as
+                        applied, restart when it only changes once the server is restarted or
+                        client when only clients use it
+  raft                  Prints the role, term and log indexes of a node in cluster mode
+                        Other commands sent to a node that isn't the leader fail with the
+                        address of the leader
+  slots                 Prints the node that owns each range of slots and the slots being migrated
+                        when the server runs with -slots
+  slotset <first> <last> <node>
+                        Assigns the slots from first to last to the node at ip:port
+  migrate <slot> <node> Moves every key in a slot owned by the server to the node at ip:port and
+                        hands the slot over, prints the number of keys moved
+  members               Prints each server known through gossip with its state, alive, suspect
+                        or dead, and its incarnation ex: 127.0.0.1:8081:alive:0
+  publish <channel> <message>
+                        Sends message to every subscriber of the channel, prints the number
+                        of subscribers it was sent to
+  subscribe <channel>...
+                        Prints every message published to the channels until Ctrl-C is pressed
+  psubscribe <pattern>...
+                        Like subscribe but for every channel matching the glob patterns
+  unsubscribe [channel]... | punsubscribe [pattern]...
+                        Unsubscribes from the channels or patterns, all of them if none are given
+                        When keyspace_events is enabled on the server each write or delete is
+                        published to __keyspace__:<key> and __keyevent__:<set|del>
+  multi                 Starts a transaction, following commands are queued until exec
+  exec                  Runs the queued commands together, printing one result per command
+  discard               Drops the queued commands and ends the transaction
+  watch <key>...        Makes the next exec fail if any key changes before it runs
+  unwatch               Stops watching every key
+  help                  Prints this message
+  quit | exit           Closes the connection and exits
+
+Arguments containing spaces can be wrapped in single or double quotes
+ex: write greeting \"hello world\"
+
+Backslash escapes \\\\ \\\" \\' \\n \\t \\r \\0 and \\xNN (a hex byte) are supported
+ex: write bin \\x00\\xff";
+
+const HISTORY_FILE: &'static str = ".rem_history";
+
+/// Launches the interactive client
+///
+/// Lines are read with editing and tab completion support, history is loaded from and saved to
+/// ~/.rem_history
+pub fn launch(config: Config, ip: String, port: String, output: OutputFormat) {
+    let addr = format!("{}:{}", ip, port);
+    match TcpStream::connect(&config, addr.as_str()) {
+        Ok(stream) => {
+            let stream = Rc::new(RefCell::new(stream));
+            let mut editor = Editor::<CommandCompleter>::new();
+            editor.set_completer(Some(CommandCompleter { stream: stream.clone() }));
+
+            let history = history_path();
+            if let Some(ref path) = history {
+                // The history file won't exist the first time the client is run
+                let _ = editor.load_history(path);
+            }
+
+            let prompt = format!("rem {}> ", addr);
             loop {
-                // Continue looping, executing any commands from the user
-                let handle = io::stdin();
-                for line_res in handle.lock().lines() {
-                    let line: String = line_res.unwrap();
-                    let args:Vec<String> = parse_input(line);
-                    if args.len() > 0{
-                        let arg_ref = args[0].as_ref();
-                        match arg_ref {
-                            "write" => {
-                                if args.len() == 3 {
-                                    match client_exec_write(&args[1], &args[2], &mut stream){
-                                        Ok(_) => (),
-                                        Err(why) => why.log()
-                                    }
-                                }else{
-                                    error!("Write expects two arguments - key and value");
-                                }
-                            },
-                            "read" => {
-                                if args.len() == 2 {
-                                    match client_exec_read(&args[1], &mut stream){
-                                        Ok(_) => (),
-                                        Err(why) => why.log()
-                                    }
-                                }else{
-                                    error!("Read expects one argument - key");
-                                }
-                            },
-                            "delete" => {
-                                if args.len() == 2 {
-                                    match client_exec_delete(&args[1], &mut stream){
-                                        Ok(_) => (),
-                                        Err(why) => why.log()
-                                    }
-                                }else{
-                                    error!("Delete expects one argument - key");
+                match editor.readline(prompt.as_str()) {
+                    Ok(line) => {
+                        let args: Vec<Vec<u8>> = match parse_input(line.clone()) {
+                            Ok(args) => args,
+                            Err(why) => {
+                                editor.add_history_entry(line.as_str());
+                                print_input_error(&line, &why);
+                                continue;
+                            }
+                        };
+                        if args.len() == 0 {
+                            continue;
+                        }
+                        editor.add_history_entry(line.as_str());
+                        match String::from_utf8_lossy(&args[0]).as_ref() {
+                            "help" => println!("{}", HELP),
+                            "quit" | "exit" => break,
+                            _ => {
+                                match client_exec(&args, &mut stream.borrow_mut(), output) {
+                                    Ok(_) => (),
+                                    Err(why) => why.log()
                                 }
                             }
-                            _ => error!("Not a valid command")
                         }
                     }
+                    // Ctrl-C and Ctrl-D exit the same way quit does
+                    Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                    Err(why) => {
+                        error!("Failed to read input. Error '{}'", why);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(ref path) = history {
+                if let Err(why) = editor.save_history(path) {
+                    warn!("Failed to save history to {:?}. Error '{}'", path, why);
+                }
+            }
+            // The completer holds the only other reference so the stream can be unwrapped once
+            // the editor is dropped
+            drop(editor);
+            match Rc::try_unwrap(stream) {
+                Ok(cell) => {
+                    if let Err(why) = cell.into_inner().close() {
+                        why.log();
+                    }
                 }
+                Err(_) => warn!("Connection is still in use and could not be closed cleanly"),
             }
         }
         Err(e) => {
@@ -64,48 +225,511 @@ pub fn launch(config: Config, ip: String, port: String) {
     }
 }
 
+/// Returns the location of the history file in the user's home directory
+fn history_path() -> Option<PathBuf> {
+    return env::home_dir().map(|home| home.join(HISTORY_FILE));
+}
 
-/// Executes a write operation by parsing the client command and converting it to REM format
-/// ex: write abc:def would be converted to 9|W$abc:def and sent to the REM server
-fn client_exec_write(key:&String, val:&String, mut stream: &mut TcpStream)-> Result<(), RemError> {
-    let sized_val = String::from(format!("W${}:{}", key, val));
-    let res = op::write_str_to_stream_with_size(&mut stream, sized_val);
-    try!(print_response(&mut stream));
-    return res;
+/// Completes command names and, for commands which take a key, the keys stored on the server
+struct CommandCompleter {
+    stream: Rc<RefCell<TcpStream>>
 }
 
-/// Executes a read operation by parsing the client command and converting it to REM format
-/// ex: read abc:def would be converted to 5|R$abc and sent to the REM launch_server
-/// The response from the REM server is written to stdout
-/// If stdout::flush fail a warning will be logged
-fn client_exec_read(key: &String, mut stream: &mut TcpStream)-> Result<(), RemError>{
-    let cmd_val = String::from(format!("R${}", key));
-    try!(op::write_str_to_stream_with_size(&mut stream, cmd_val));
-    try!(print_response(&mut stream));
-    return Ok(());
+impl Completer for CommandCompleter {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let before: &str = &line[..pos];
+        // The word being completed starts after the last space before the cursor
+        let start = match before.rfind(' ') {
+            Some(idx) => idx + 1,
+            None => 0,
+        };
+        let word: &str = &before[start..];
+        let previous: Vec<&str> = before[..start].split_whitespace().collect();
+
+        let candidates: Vec<String> = match previous.len() {
+            0 => {
+                COMMANDS.iter()
+                    .filter(|cmd| cmd.starts_with(word))
+                    .map(|cmd| String::from(*cmd))
+                    .collect()
+            }
+            1 if KEY_COMMANDS.contains(&previous[0]) => {
+                match self.keys_with_prefix(word) {
+                    Ok(keys) => keys,
+                    Err(why) => {
+                        why.log();
+                        Vec::new()
+                    }
+                }
+            }
+            _ => Vec::new(),
+        };
+        return Ok((start, candidates));
+    }
+}
+
+impl CommandCompleter {
+    /// Asks the server for every key starting with prefix
+    /// ex: a prefix of ab would be sent as 3|K$ab
+    fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, RemError> {
+        let mut stream = self.stream.borrow_mut();
+        try!(op::write_bytes_to_stream_with_size(&mut stream, &build_request("K", &[prefix.as_bytes()])));
+        let res = try!(op::bytes_from_stream(&mut stream));
+        if res.starts_with(ERROR.as_bytes()) {
+            return Err(RemError::with_reason(String::from_utf8_lossy(&res).into_owned()));
+        }
+        return op::decode_keys(&res);
+    }
 }
 
-/// Executes a delete operation by parsing the client command and converting it to REM format
-/// ex: delete abc would be converted to 5|D$abc and sent to the REM server
-fn client_exec_delete(key: &String, mut stream: &mut TcpStream) -> Result<(), RemError>{
-    let cmd_val = String::from(format!("D${}", key));
-    let res = op::write_str_to_stream_with_size(&mut stream, cmd_val);
-    try!(print_response(&mut stream));
-    return res;
+/// Connects to the server and executes every command in the script file described by options
+///
+/// The process exits with a non zero status if any of the commands failed
+pub fn launch_script(config: Config, ip: String, port: String, options: ScriptOptions) {
+    match TcpStream::connect(&config, format!("{}:{}", ip, port).as_str()) {
+        Ok(mut stream) => {
+            match script::run(&options, &mut stream) {
+                Ok(summary) => {
+                    summary.print();
+                    if summary.failed > 0 {
+                        RemError::with_reason(format!("{}: {} of {} commands failed",
+                                                      REM_00008,
+                                                      summary.failed,
+                                                      summary.total()))
+                            .log_and_exit();
+                    }
+                }
+                Err(why) => why.log_and_exit()
+            }
+        }
+        Err(e) => {
+            panic!("Failed to connect to server. Error '{}'", e);
+        }
+    }
 }
 
-fn print_response(mut stream: &mut TcpStream) -> Result<(), RemError>{
-    let val: String = try!(op::string_from_stream(&mut stream));
-    println!("{}", val);
-    try!(io::stdout().flush());
+/// Converts the arguments of a client command into the REM request format
+/// ex: ["write", "abc", "def"] would be converted to W$abc:def
+pub fn request_from_args(args: &Vec<Vec<u8>>) -> Result<Vec<u8>, RemError> {
+    match String::from_utf8_lossy(&args[0]).as_ref() {
+        "write" => {
+            if args.len() == 3 {
+                Ok(build_request("W", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Write expects two arguments - key and value"))
+            }
+        },
+        "read" => {
+            if args.len() == 2 {
+                Ok(build_request("R", &[&args[1]]))
+            }else{
+                Err(invalid_command("Read expects one argument - key"))
+            }
+        },
+        "delete" => {
+            if args.len() == 2 {
+                Ok(build_request("D", &[&args[1]]))
+            }else{
+                Err(invalid_command("Delete expects one argument - key"))
+            }
+        }
+        "readv" => {
+            if args.len() == 2 {
+                Ok(build_request("RV", &[&args[1]]))
+            }else{
+                Err(invalid_command("Readv expects one argument - key"))
+            }
+        }
+        "setnx" => {
+            if args.len() == 3 {
+                Ok(build_request("SETNX", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Setnx expects two arguments - key and value"))
+            }
+        }
+        "setxx" => {
+            if args.len() == 3 {
+                Ok(build_request("SETXX", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Setxx expects two arguments - key and value"))
+            }
+        }
+        "setv" => {
+            if args.len() == 4 {
+                Ok(build_request("SETV", &[&args[1], &args[2], &args[3]]))
+            }else{
+                Err(invalid_command("Setv expects three arguments - key, version and value"))
+            }
+        }
+        "mget" => {
+            if args.len() >= 2 {
+                Ok(build_list_request("MGET", &args[1..]))
+            }else{
+                Err(invalid_command("Mget expects at least one key"))
+            }
+        }
+        "mset" => {
+            if args.len() >= 3 && args.len() % 2 == 1 {
+                Ok(build_list_request("MSET", &args[1..]))
+            }else{
+                Err(invalid_command("Mset expects one or more pairs of arguments - key and value"))
+            }
+        }
+        "mdel" => {
+            if args.len() >= 2 {
+                Ok(build_list_request("MDEL", &args[1..]))
+            }else{
+                Err(invalid_command("Mdel expects at least one key"))
+            }
+        }
+        "scan" => {
+            if args.len() >= 2 && args.len() <= 4 {
+                Ok(build_list_request("SCAN", &args[1..]))
+            }else{
+                Err(invalid_command("Scan expects a cursor followed by an optional pattern and count"))
+            }
+        }
+        "keys" => {
+            match args.len() {
+                1 => Ok(build_request("KEYS", &[])),
+                2 => Ok(build_request("KEYS", &[&args[1]])),
+                _ => Err(invalid_command("Keys expects at most one argument - pattern")),
+            }
+        }
+        "lpush" | "rpush" => {
+            if args.len() >= 3 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                Ok(build_list_request(&command, &args[1..]))
+            }else{
+                Err(invalid_command("Lpush and rpush expect at least two arguments - key and value"))
+            }
+        }
+        "lpop" | "rpop" | "llen" => {
+            if args.len() == 2 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                Ok(build_request(&command, &[&args[1]]))
+            }else{
+                Err(invalid_command("Lpop, rpop and llen expect one argument - key"))
+            }
+        }
+        "lrange" => {
+            if args.len() == 4 {
+                Ok(build_request("LRANGE", &[&args[1], &args[2], &args[3]]))
+            }else{
+                Err(invalid_command("Lrange expects three arguments - key, start and stop"))
+            }
+        }
+        "blpop" | "brpop" => {
+            if args.len() == 2 || args.len() == 3 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                let timeout: &[u8] = if args.len() == 3 { &args[2] } else { b"0" };
+                Ok(build_request(&command, &[&args[1], timeout]))
+            }else{
+                Err(invalid_command("Blpop and brpop expect one or two arguments - key and timeout"))
+            }
+        }
+        "hset" => {
+            if args.len() >= 4 && args.len() % 2 == 0 {
+                Ok(build_list_request("HSET", &args[1..]))
+            }else{
+                Err(invalid_command("Hset expects a key followed by one or more pairs of arguments - field and value"))
+            }
+        }
+        "hget" => {
+            if args.len() == 3 {
+                Ok(build_list_request("HGET", &args[1..]))
+            }else{
+                Err(invalid_command("Hget expects two arguments - key and field"))
+            }
+        }
+        "hdel" => {
+            if args.len() >= 3 {
+                Ok(build_list_request("HDEL", &args[1..]))
+            }else{
+                Err(invalid_command("Hdel expects a key and at least one field"))
+            }
+        }
+        "hgetall" => {
+            if args.len() == 2 {
+                Ok(build_request("HGETALL", &[&args[1]]))
+            }else{
+                Err(invalid_command("Hgetall expects one argument - key"))
+            }
+        }
+        "hincrby" => {
+            if args.len() == 4 {
+                Ok(build_list_request("HINCRBY", &args[1..]))
+            }else{
+                Err(invalid_command("Hincrby expects three arguments - key, field and n"))
+            }
+        }
+        "sadd" | "srem" => {
+            if args.len() >= 3 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                Ok(build_list_request(&command, &args[1..]))
+            }else{
+                Err(invalid_command("Sadd and srem expect a key and at least one member"))
+            }
+        }
+        "sismember" => {
+            if args.len() == 3 {
+                Ok(build_list_request("SISMEMBER", &args[1..]))
+            }else{
+                Err(invalid_command("Sismember expects two arguments - key and member"))
+            }
+        }
+        "smembers" => {
+            if args.len() == 2 {
+                Ok(build_request("SMEMBERS", &[&args[1]]))
+            }else{
+                Err(invalid_command("Smembers expects one argument - key"))
+            }
+        }
+        "sinter" => {
+            if args.len() >= 2 {
+                Ok(build_list_request("SINTER", &args[1..]))
+            }else{
+                Err(invalid_command("Sinter expects at least one key"))
+            }
+        }
+        "zadd" => {
+            if args.len() >= 4 && args.len() % 2 == 0 {
+                Ok(build_list_request("ZADD", &args[1..]))
+            }else{
+                Err(invalid_command("Zadd expects a key followed by one or more pairs of arguments - score and member"))
+            }
+        }
+        "zrange" | "zrangebyscore" | "zincrby" => {
+            if args.len() == 4 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                Ok(build_list_request(&command, &args[1..]))
+            }else{
+                Err(invalid_command("Zrange, zrangebyscore and zincrby expect three arguments"))
+            }
+        }
+        "memory" => {
+            if args.len() == 1 {
+                Ok(build_request("MEMORY", &[]))
+            }else{
+                Err(invalid_command("Memory expects no arguments"))
+            }
+        }
+        "replication" => {
+            if args.len() == 1 {
+                Ok(build_request("REPLICATION", &[]))
+            }else{
+                Err(invalid_command("Replication expects no arguments"))
+            }
+        }
+        "raft" => {
+            if args.len() == 1 {
+                Ok(build_request("RAFT", &[]))
+            }else{
+                Err(invalid_command("Raft expects no arguments"))
+            }
+        }
+        "repair" => {
+            if args.len() == 1 {
+                Ok(build_request("REPAIR", &[]))
+            }else{
+                Err(invalid_command("Repair expects no arguments"))
+            }
+        }
+        "config" => {
+            if args.len() == 2 && String::from_utf8_lossy(&args[1]).to_lowercase() == "reload" {
+                Ok(build_request("CONFIG", &[b"RELOAD"]))
+            }else{
+                Err(invalid_command("Config expects one argument - reload"))
+            }
+        }
+        "members" => {
+            if args.len() == 1 {
+                Ok(build_request("MEMBERS", &[]))
+            }else{
+                Err(invalid_command("Members expects no arguments"))
+            }
+        }
+        "slots" => {
+            if args.len() == 1 {
+                Ok(build_request("SLOTS", &[]))
+            }else{
+                Err(invalid_command("Slots expects no arguments"))
+            }
+        }
+        "slotset" => {
+            if args.len() == 4 {
+                Ok(build_request("SLOTSET", &[&args[1], &args[2], &args[3]]))
+            }else{
+                Err(invalid_command("Slotset expects three arguments - first slot, last slot and node"))
+            }
+        }
+        "migrate" => {
+            if args.len() == 3 {
+                Ok(build_request("MIGRATE", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Migrate expects two arguments - slot and node"))
+            }
+        }
+        "publish" => {
+            if args.len() == 3 {
+                Ok(build_request("PUBLISH", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Publish expects two arguments - channel and message"))
+            }
+        }
+        "subscribe" | "psubscribe" => {
+            if args.len() >= 2 {
+                let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+                Ok(build_list_request(&command, &args[1..]))
+            }else{
+                Err(invalid_command("Subscribe and psubscribe expect at least one channel or pattern"))
+            }
+        }
+        "unsubscribe" | "punsubscribe" => {
+            let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+            Ok(build_list_request(&command, &args[1..]))
+        }
+        "delprefix" => {
+            if args.len() == 2 {
+                Ok(build_request("DELPREFIX", &[&args[1]]))
+            }else{
+                Err(invalid_command("Delprefix expects one argument - prefix"))
+            }
+        }
+        "delmatch" => {
+            if args.len() == 2 {
+                Ok(build_request("DELMATCH", &[&args[1]]))
+            }else{
+                Err(invalid_command("Delmatch expects one argument - pattern"))
+            }
+        }
+        "dbsize" => {
+            if args.len() == 1 {
+                Ok(build_request("DBSIZE", &[]))
+            }else{
+                Err(invalid_command("Dbsize expects no arguments"))
+            }
+        }
+        "multi" | "exec" | "discard" | "unwatch" => {
+            if args.len() == 1 {
+                Ok(build_request(&String::from_utf8_lossy(&args[0]).to_uppercase(), &[]))
+            }else{
+                Err(invalid_command("Multi, exec, discard and unwatch expect no arguments"))
+            }
+        }
+        "watch" => {
+            if args.len() >= 2 {
+                Ok(build_list_request("WATCH", &args[1..]))
+            }else{
+                Err(invalid_command("Watch expects at least one argument - key"))
+            }
+        }
+        "incr" => {
+            if args.len() == 2 {
+                Ok(build_request("INCR", &[&args[1]]))
+            }else{
+                Err(invalid_command("Incr expects one argument - key"))
+            }
+        }
+        "decr" => {
+            if args.len() == 2 {
+                Ok(build_request("DECR", &[&args[1]]))
+            }else{
+                Err(invalid_command("Decr expects one argument - key"))
+            }
+        }
+        "incrby" => {
+            if args.len() == 3 {
+                Ok(build_request("INCRBY", &[&args[1], &args[2]]))
+            }else{
+                Err(invalid_command("Incrby expects two arguments - key and amount"))
+            }
+        }
+        _ => Err(invalid_command("Not a valid command"))
+    }
+}
+
+/// Builds a request from a command and its parts, the parts are separated by :
+/// ex: W with parts abc and def would be built as W$abc:def
+pub fn build_request(command: &str, parts: &[&[u8]]) -> Vec<u8> {
+    let mut req: Vec<u8> = format!("{}$", command).into_bytes();
+    for (idx, part) in parts.iter().enumerate() {
+        if idx > 0 {
+            req.push(b':');
+        }
+        req.extend_from_slice(part);
+    }
+    return req;
+}
+
+/// Builds a request for a multi key command, the items are encoded as a list
+/// ex: MGET with items abc and de would be built as MGET$3|abc2|de
+pub fn build_list_request(command: &str, items: &[Vec<u8>]) -> Vec<u8> {
+    let mut req: Vec<u8> = format!("{}$", command).into_bytes();
+    req.extend(op::encode_list(items));
+    return req;
+}
+
+/// Classifies the response to the command described by args
+/// Multi key commands respond with a list containing a result for each key
+pub fn response_from_bytes(args: &Vec<Vec<u8>>, res: Vec<u8>) -> Response {
+    match String::from_utf8_lossy(&args[0]).as_ref() {
+        "mget" | "mset" | "mdel" | "scan" | "keys" | "exec" | "lrange" | "hgetall" |
+        "smembers" | "sinter" | "zrange" | "zrangebyscore" | "memory" |
+        "replication" | "raft" | "slots" | "members" | "repair" | "config" => {
+            Response::from_list_bytes(res)
+        }
+        _ => Response::from_bytes(res),
+    }
+}
+
+fn invalid_command(details: &str) -> RemError {
+    RemError::with_reason(format!("{}: {}", REM_00007, details))
+}
+
+/// Executes a client command by converting it to REM format and sending it to the REM server
+/// ex: write abc def would be sent as 9|W$abc:def, read abc would be sent as 5|R$abc
+/// The response from the REM server is written to stdout using the output format
+fn client_exec(args: &Vec<Vec<u8>>, mut stream: &mut TcpStream, output: OutputFormat) -> Result<(), RemError> {
+    let req = try!(request_from_args(args));
+    try!(op::write_bytes_to_stream_with_size(&mut stream, &req));
+    let res = response_from_bytes(args, try!(op::bytes_from_stream(&mut stream)));
+    try!(output::print_response(output, &res));
+    if let Response::OK = res {
+        match String::from_utf8_lossy(&args[0]).as_ref() {
+            "subscribe" | "psubscribe" => try!(print_pushed_messages(stream, output)),
+            _ => (),
+        }
+    }
     return Ok(());
 }
 
+/// Prints the messages pushed by the server after a subscription until the subscription ends
+///
+/// Each message is a list such as message, channel, payload. An empty frame ends the messages
+fn print_pushed_messages(mut stream: &mut TcpStream, output: OutputFormat) -> Result<(), RemError> {
+    loop {
+        let pushed = try!(op::bytes_from_stream(&mut stream));
+        if pushed.is_empty() {
+            return Ok(());
+        }
+        try!(output::print_response(output, &Response::from_list_bytes(pushed)));
+    }
+}
+
+/// Prints the input line with a marker under the column where parsing failed
+fn print_input_error(line: &String, why: &InputError) {
+    println!("{}", line);
+    println!("{}^", " ".repeat(why.column - 1));
+    error!("{}", why);
+}
+
 struct InputParser{
-    args:Vec<String>,
-    current:String,
+    args:Vec<Vec<u8>>,
+    current:Vec<u8>,
     consumed_double_quote:bool,
-    consumed_single_quote:bool
+    consumed_single_quote:bool,
+    quote_column:usize
 }
 
 impl InputParser{
@@ -117,82 +741,175 @@ impl InputParser{
         if !self.consumed_double_quote && !self.consumed_single_quote {
             self.push_current();
         }else{
-            self.current.push(' ');
+            self.current.push(b' ');
         }
     }
 
     /// Consumes a double quote, keeping track of whether it is an opening or cloing quote
     /// Takes single quotes into account when determening if the double quote is a delimiter or character
-    pub fn consume_double_quote(&mut self){
+    pub fn consume_double_quote(&mut self, column:usize){
         // If a single quote hasn't been consumed we're at the end or 
         // beginning of an argument in double quotes
         if  !self.consumed_single_quote {
             if self.consumed_double_quote{
                 self.push_current();
+            }else{
+                self.quote_column = column;
             }
             // Flip the value so we know the sate for the next double quote that is consumed
             self.consumed_double_quote = !self.consumed_double_quote;
         }else{
             // If we're in double quotes just treat the double quote as a regular character 
-            self.current.push('"');
+            self.current.push(b'"');
         }
     }
 
     /// Consumes a single quote, keeping track of whether it is an opening or cloing quote
     /// Takes double quotes into account when determening if the single quote is a delimiter or character
-    pub fn consume_single_quote(&mut self){
+    pub fn consume_single_quote(&mut self, column:usize){
          // If a double quote hasn't been consumed we're at the end or 
         // beginning of an argument in single quotes
          if !self.consumed_double_quote {
             if self.consumed_single_quote{
                 self.push_current();
+            }else{
+                self.quote_column = column;
             }
             // Flip the value so we know the sate for the next single quote that is consumed
             self.consumed_single_quote = !self.consumed_single_quote;
         }else{
             // If we're in double quotes just treat the single quote as a regular character 
-            self.current.push('\'');
+            self.current.push(b'\'');
         }
     }
 
+    /// Consumes the character(s) following a backslash
+    ///
+    /// Supported escapes are \\ \" \' \<space> \n \t \r \0 and \xNN where NN is a hex byte
+    /// Escapes are handled the same way inside and outside of quotes
+    pub fn consume_escape<I: Iterator<Item = (usize, char)>>(&mut self,
+                                                             chars: &mut I,
+                                                             column: usize)
+                                                             -> Result<(), InputError>{
+        match chars.next() {
+            Some((_, 'n')) => self.current.push(b'\n'),
+            Some((_, 't')) => self.current.push(b'\t'),
+            Some((_, 'r')) => self.current.push(b'\r'),
+            Some((_, '0')) => self.current.push(0),
+            Some((_, 'x')) => {
+                let hex: String = chars.by_ref().take(2).map(|(_, h)| h).collect();
+                if hex.len() != 2 || !hex.chars().all(|h| h.is_digit(16)) {
+                    return Err(InputError::new(REM_00010, column));
+                }
+                // Both characters are hex digits so this can't fail
+                self.current.push(u8::from_str_radix(hex.as_str(), 16).unwrap());
+            }
+            Some((_, c)) if c == '\\' || c == '"' || c == '\'' || c == ' ' => self.consume_char(c),
+            _ => return Err(InputError::new(REM_00010, column)),
+        }
+        return Ok(());
+    }
+
     /// Adds the character onto the current argument
     pub fn consume_char(&mut self, c:char){
-        self.current.push(c);
+        let mut utf8: [u8; 4] = [0; 4];
+        self.current.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
     }
 
     /// To be called when everything has been parsed
-    pub fn end(&mut self){
+    /// Fails if a quote was opened but never closed
+    pub fn end(&mut self) -> Result<(), InputError>{
+        if self.consumed_double_quote || self.consumed_single_quote {
+            return Err(InputError::new(REM_00009, self.quote_column));
+        }
         self.push_current();
+        return Ok(());
     }
 
     /// Pushes the current string into the list of args
     /// If the length of current is 0 no actions are performed
     pub fn push_current(&mut self){
         if self.current.len() > 0 {
-            let arg = mem::replace(&mut self.current, String::new());
+            let arg = mem::replace(&mut self.current, Vec::new());
             self.args.push(arg);
         }
     }
 
 }
 
-/// Parses the arguments out of an input string taking quotes and spaces into consideration
-pub fn parse_input(input: String) -> Vec<String>{
+/// Parses the arguments out of an input string taking quotes, spaces and escapes into consideration
+/// Arguments are returned as bytes since \xNN escapes can produce values that aren't valid UTF-8
+pub fn parse_input(input: String) -> Result<Vec<Vec<u8>>, InputError>{
     let mut parser = InputParser{
         args:Vec::new(),
-        current:String::new(),
+        current:Vec::new(),
         consumed_double_quote:false,
-        consumed_single_quote:false
+        consumed_single_quote:false,
+        quote_column:0
     };    
-    for c in input.chars(){
+    let mut chars = input.chars().enumerate();
+    while let Some((idx, c)) = chars.next() {
+        // Columns are 1 based
+        let column = idx + 1;
         match c {
-            '"'  => parser.consume_double_quote(),
+            '"'  => parser.consume_double_quote(column),
             ' '  => parser.consume_space(),
-            '\'' => parser.consume_single_quote(), 
+            '\'' => parser.consume_single_quote(column), 
+            '\\' => try!(parser.consume_escape(&mut chars, column)),
             _    => parser.consume_char(c)
         }
     }
-    parser.end();
+    try!(parser.end());
+
+    return Ok(parser.args);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_input;
+    use rem::error::*;
+
+    fn parse(input: &str) -> Vec<Vec<u8>> {
+        return parse_input(String::from(input)).unwrap();
+    }
+
+    #[test]
+    fn splits_arguments_on_spaces() {
+        assert_eq!(parse("write  abc def "), vec![b"write".to_vec(), b"abc".to_vec(), b"def".to_vec()]);
+    }
+
+    #[test]
+    fn keeps_spaces_inside_quotes() {
+        assert_eq!(parse("write a \"hello world\""), vec![b"write".to_vec(), b"a".to_vec(), b"hello world".to_vec()]);
+        assert_eq!(parse("write a 'it \"is\"'"), vec![b"write".to_vec(), b"a".to_vec(), b"it \"is\"".to_vec()]);
+        assert_eq!(parse("write a \"it's\""), vec![b"write".to_vec(), b"a".to_vec(), b"it's".to_vec()]);
+    }
 
-    return parser.args;
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(parse("a\\nb\\tc\\r\\0"), vec![b"a\nb\tc\r\0".to_vec()]);
+        assert_eq!(parse("\\x00\\xff\\x7F"), vec![vec![0x00, 0xff, 0x7f]]);
+        assert_eq!(parse("a\\ b \\\\ \\\" \\'"), vec![b"a b".to_vec(), b"\\".to_vec(), b"\"".to_vec(), b"'".to_vec()]);
+        assert_eq!(parse("\"say \\\"hi\\\"\""), vec![b"say \"hi\"".to_vec()]);
+    }
+
+    #[test]
+    fn reports_unterminated_quotes_at_the_opening_quote() {
+        let why = parse_input(String::from("write a \"abc")).unwrap_err();
+        assert_eq!(why.reason, REM_00009);
+        assert_eq!(why.column, 9);
+        let why = parse_input(String::from("'abc")).unwrap_err();
+        assert_eq!(why.reason, REM_00009);
+        assert_eq!(why.column, 1);
+    }
+
+    #[test]
+    fn reports_invalid_escapes_at_the_backslash() {
+        let why = parse_input(String::from("ab\\q")).unwrap_err();
+        assert_eq!(why.reason, REM_00010);
+        assert_eq!(why.column, 3);
+        assert_eq!(parse_input(String::from("\\xZ1")).unwrap_err().reason, REM_00010);
+        assert_eq!(parse_input(String::from("\\x1")).unwrap_err().reason, REM_00010);
+        assert_eq!(parse_input(String::from("abc\\")).unwrap_err().column, 4);
+    }
 }
@@ -1,12 +1,18 @@
 use std::io;
 use std::str;
 use tokio_io::codec::{Decoder, Encoder};
+use tokio_proto::streaming::pipeline::Frame;
 use bytes::BytesMut;
 
-pub struct CacheCodec {}
+use rem::service::ERROR;
+
+pub struct CacheCodec {
+    /// The largest request accepted, the connection is closed when a larger one is sent
+    pub max_request_bytes: usize
+}
 
 impl Decoder for CacheCodec{
-     type Item  = String;
+     type Item  = Frame<Vec<u8>, Vec<u8>, io::Error>;
      type Error = io::Error;
 
      fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
@@ -17,13 +23,19 @@ impl Decoder for CacheCodec{
                 Ok(descriptor) => {
                     match descriptor.parse::<i32>() {
                         Ok(size) => {
-                            if buf.len() >= size as usize + descriptor.len() as usize {
+                            if size < 0 || size as usize > self.max_request_bytes {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                          format!("Request of {} bytes exceeds the limit of {} bytes",
+                                                                  size, self.max_request_bytes)));
+                            }
+                            if buf.len() >= idx + 1 + size as usize {
                                 buf.split_to(idx + 1);
                                 let content = buf.split_to(size as usize);
-                                match str::from_utf8(content.as_ref()){
-                                    Ok(s) => Ok(Some(String::from(s))),
-                                    Err(_) => Err(io::Error::from(io::ErrorKind::InvalidData))
-                                }
+                                // The content is not required to be UTF-8 since values can be binary
+                                Ok(Some(Frame::Message {
+                                    message: content.to_vec(),
+                                    body: false
+                                }))
                             }else {
                                 return Ok(None);
                             }
@@ -43,13 +55,24 @@ impl Decoder for CacheCodec{
     }
 }
 
+/// Every frame is written as ```{size}|{content}```
+///
+/// A body chunk is written the same way as a message. The end of a body is written as an empty
+/// frame ```0|```, body chunks are never empty so the client can tell the two apart
 impl Encoder for CacheCodec {
-    type Item = String;
+    type Item = Frame<Vec<u8>, Vec<u8>, io::Error>;
     type Error = io::Error;
 
-    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        let formatted = format!("{}|{}", msg.len(), msg);
-        buf.extend(formatted.as_bytes());
+    fn encode(&mut self, frame: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
+        let msg: Vec<u8> = match frame {
+            Frame::Message { message, .. } => message,
+            Frame::Body { chunk: Some(chunk) } => chunk,
+            Frame::Body { chunk: None } => Vec::new(),
+            Frame::Error { error } => format!("{}:{}", ERROR, error).into_bytes(),
+        };
+        let descriptor = format!("{}|", msg.len());
+        buf.extend(descriptor.as_bytes());
+        buf.extend(msg);
         Ok(())
     }
-}
\ No newline at end of file
+}
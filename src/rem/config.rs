@@ -1,38 +1,437 @@
 use std::io::prelude::*;
 use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
 
 use toml;
 
-use rem::error::RemError;
+use rem::error::*;
 
-#[derive(Deserialize, Debug)]
+/// The settings in rem.toml, every setting has a default so an empty file is a valid config
+///
+/// Unknown settings are rejected so a misspelt setting isn't silently ignored
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config{
+    /// Connections made to a server, by the client or by a server to another server, use TLS.
+    /// A server always serves TLS whatever this is set to
+    #[serde(default = "default_ssl")]
     pub ssl: bool,
+    /// The name the server's certificate is checked against
+    #[serde(default = "default_domain")]
     pub domain: String,
+    #[serde(default)]
     pub client:ClientConfig,
+    #[serde(default)]
     pub server:ServerConfig
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ClientConfig{
-   
+    /// The servers ShardedClient spreads keys across
+    /// ex: ```[[client.servers]]``` with ```addr = "127.0.0.1:8080"``` and ```weight = 2```
+    #[serde(default)]
+    pub servers: Vec<ServerNode>,
+    /// The number of points on the hash ring for each unit of a server's weight
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: usize
 }
 
+/// A server listed in the client config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerNode{
+    pub addr: String,
+    /// A server with twice the weight of another is sent about twice as many keys
+    #[serde(default = "default_weight")]
+    pub weight: usize
+}
+
+fn default_ssl() -> bool {
+    return true;
+}
+
+fn default_domain() -> String {
+    return String::from("rem");
+}
+
+fn default_virtual_nodes() -> usize {
+    return 160;
+}
+
+fn default_weight() -> usize {
+    return 1;
+}
+
+fn default_bind() -> String {
+    return String::from("127.0.0.1");
+}
+
+fn default_port() -> u16 {
+    return 8080;
+}
+
+fn default_data_dir() -> String {
+    return String::from(".");
+}
+
+fn default_max_request_bytes() -> usize {
+    return 64 * 1024 * 1024;
+}
+
+fn default_replication_backlog_bytes() -> usize {
+    return 16 * 1024 * 1024;
+}
+
+fn default_cert_file() -> String {
+    return String::from("rem.pfx");
+}
+
+fn default_cluster_port_offset() -> u16 {
+    return 10000;
+}
 
-#[derive(Deserialize, Debug)]
+fn default_shutdown_timeout_secs() -> u64 {
+    return 30;
+}
+
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig{
+    /// The address the server listens on, -ip takes precedence
+    #[serde(default = "default_bind")]
+    pub bind:String,
+    /// The port the server listens on, -port takes precedence
+    #[serde(default = "default_port")]
+    pub port:u16,
+    /// The directory the cache, raft log and slot owners are kept in, created if it doesn't exist
+    #[serde(default = "default_data_dir")]
+    pub data_dir:String,
+    /// The number of threads cache operations run on, one for each CPU when it isn't set
+    #[serde(default)]
+    pub pool_size:Option<usize>,
+    /// The most connections served at once, a connection beyond it is closed as soon as it is
+    /// accepted. There is no limit when it isn't set
+    #[serde(default)]
+    pub max_connections:Option<usize>,
+    /// The largest request a connection may send, the connection is closed if a request is larger
+    #[serde(default = "default_max_request_bytes")]
+    pub max_request_bytes:usize,
+    /// Syncs every write to disk before it is responded to, otherwise a write that the OS hasn't
+    /// written yet is lost if the machine fails
+    #[serde(default)]
+    pub sync_writes:bool,
+    /// The most bytes of recent changes a primary keeps for replicas that reconnect, a replica
+    /// that is further behind than the oldest change kept copies the whole cache again
+    #[serde(default = "default_replication_backlog_bytes")]
+    pub replication_backlog_bytes:usize,
+    /// A PKCS #12 archive with the server's certificate and private key
+    #[serde(default = "default_cert_file")]
     pub cert_file:String,
-    pub cert_password:String
+    #[serde(default)]
+    pub cert_password:String,
+    /// Publishes a notification to subscribers each time a key is written or deleted
+    #[serde(default)]
+    pub keyspace_events:bool,
+    /// Servers started with -gossip exchange membership over UDP on their port plus this offset
+    #[serde(default = "default_cluster_port_offset")]
+    pub cluster_port_offset:u16,
+    /// Gossip messages are signed with this secret and messages that aren't are dropped, every
+    /// server in the cluster needs the same secret. It must be set to run with -gossip
+    #[serde(default)]
+    pub cluster_secret:String,
+    /// On SIGTERM or SIGINT the server waits this long for requests in flight before it exits
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs:u64,
+    /// Filters for the server's log in the same syntax as RUST_LOG, which is used when it isn't set
+    #[serde(default)]
+    pub log_level:Option<String>
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        return ClientConfig {
+            servers: Vec::new(),
+            virtual_nodes: default_virtual_nodes()
+        };
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        return ServerConfig {
+            bind: default_bind(),
+            port: default_port(),
+            data_dir: default_data_dir(),
+            pool_size: None,
+            max_connections: None,
+            max_request_bytes: default_max_request_bytes(),
+            sync_writes: false,
+            replication_backlog_bytes: default_replication_backlog_bytes(),
+            cert_file: default_cert_file(),
+            cert_password: String::new(),
+            keyspace_events: false,
+            cluster_port_offset: default_cluster_port_offset(),
+            cluster_secret: String::new(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            log_level: None
+        };
+    }
 }
 
 
 impl Config {
+    /// Loads and validates a config file
+    ///
+    /// Each problem found is reported on its own line, with the line of the file it is on
+    /// ex: ```rem.toml:4: server.port must be between 1 and 65535```
     pub fn from_file(file:String) -> Result<Config, RemError>{
-         let mut f:File = try!(File::open(file));
          let mut buf = String::new();
-         try!(f.read_to_string(&mut buf));
-         /// TDDO handle toml errors
-         let conf:Config = toml::from_str(buf.as_str()).unwrap();
+         if let Err(why) = File::open(&file).and_then(|mut f| f.read_to_string(&mut buf)) {
+             return Err(invalid_config(&file, vec![(None, format!("{}", why))]));
+         }
+         let conf:Config = match toml::from_str(buf.as_str()) {
+             Ok(conf) => conf,
+             Err(why) => {
+                 let why = format!("{}", why);
+                 let line = if why.contains(" line ") { None } else { line_of(&buf, &error_setting(&why)) };
+                 return Err(invalid_config(&file, vec![(line, why)]));
+             }
+         };
+         let problems: Vec<(Option<usize>, String)> = conf.validate()
+             .into_iter()
+             .map(|(setting, problem)| (line_of(&buf, &setting), format!("{} {}", setting, problem)))
+             .collect();
+         if !problems.is_empty() {
+             return Err(invalid_config(&file, problems));
+         }
          return Ok(conf);
     }
-}
\ No newline at end of file
+
+    /// Returns each setting with a value the server can't run with and what is wrong with it
+    fn validate(&self) -> Vec<(String, &'static str)> {
+        let mut problems: Vec<(String, &'static str)> = Vec::new();
+        if self.domain.is_empty() {
+            problems.push((String::from("domain"), "can not be empty"));
+        }
+        if self.client.virtual_nodes == 0 {
+            problems.push((String::from("client.virtual_nodes"), "must be greater than 0"));
+        }
+        for (idx, server) in self.client.servers.iter().enumerate() {
+            if !has_port(&server.addr) {
+                problems.push((format!("client.servers[{}].addr", idx), "must be an address such as 127.0.0.1:8080"));
+            }
+            if server.weight == 0 {
+                problems.push((format!("client.servers[{}].weight", idx), "must be greater than 0"));
+            }
+        }
+        if self.server.bind.parse::<IpAddr>().is_err() {
+            problems.push((String::from("server.bind"), "must be an IP address such as 127.0.0.1"));
+        }
+        if self.server.port == 0 {
+            problems.push((String::from("server.port"), "must be between 1 and 65535"));
+        }
+        if self.server.data_dir.is_empty() {
+            problems.push((String::from("server.data_dir"), "can not be empty"));
+        }
+        if self.server.pool_size == Some(0) {
+            problems.push((String::from("server.pool_size"), "must be greater than 0"));
+        }
+        if self.server.max_connections == Some(0) {
+            problems.push((String::from("server.max_connections"), "must be greater than 0"));
+        }
+        if self.server.max_request_bytes == 0 {
+            problems.push((String::from("server.max_request_bytes"), "must be greater than 0"));
+        }
+        if self.server.replication_backlog_bytes == 0 {
+            problems.push((String::from("server.replication_backlog_bytes"), "must be greater than 0"));
+        }
+        if self.server.cert_file.is_empty() {
+            problems.push((String::from("server.cert_file"), "can not be empty"));
+        }
+        if self.server.cluster_port_offset == 0 {
+            problems.push((String::from("server.cluster_port_offset"), "must be greater than 0"));
+        } else if self.server.port as u32 + self.server.cluster_port_offset as u32 > 65535 {
+            problems.push((String::from("server.cluster_port_offset"), "added to server.port must be at most 65535"));
+        }
+        return problems;
+    }
+}
+
+fn invalid_config(file: &str, problems: Vec<(Option<usize>, String)>) -> RemError {
+    let lines: Vec<String> = problems.into_iter()
+        .map(|(line, problem)| match line {
+            Some(line) => format!("{}:{}: {}", file, line, problem),
+            None => format!("{}: {}", file, problem),
+        })
+        .collect();
+    return RemError::with_reason(format!("{}\n{}", REM_00054, lines.join("\n")));
+}
+
+/// An address is valid when it ends with a port, the host may be a name
+fn has_port(addr: &str) -> bool {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    return match addr.rfind(':') {
+        Some(idx) => idx > 0 && addr[idx + 1..].parse::<u16>().is_ok(),
+        None => false,
+    };
+}
+
+/// Names the setting a TOML error is about
+/// ex: ```unknown field `prot`, expected one of ... for key `server` ``` is about server.prot
+fn error_setting(why: &str) -> String {
+    let table = quoted_after(why, "for key `");
+    let field = quoted_after(why, "unknown field `");
+    return match (table, field) {
+        (Some(table), Some(field)) => format!("{}.{}", table, field),
+        (Some(table), None) => String::from(table),
+        (None, Some(field)) => String::from(field),
+        (None, None) => String::new(),
+    };
+}
+
+fn quoted_after<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let start = match text.find(prefix) {
+        Some(idx) => idx + prefix.len(),
+        None => return None,
+    };
+    return text[start..].find('`').map(|len| &text[start..start + len]);
+}
+
+/// Returns the 1 based line a setting is on, a table's line is the line of its header
+///
+/// The setting is named by its path, an entry in an array of tables by its index
+/// ex: ```server.port``` or ```client.servers[1].addr```
+fn line_of(source: &str, setting: &str) -> Option<usize> {
+    if setting.is_empty() {
+        return None;
+    }
+    let (table, key) = match setting.rfind('.') {
+        Some(idx) => (&setting[..idx], &setting[idx + 1..]),
+        None => ("", setting),
+    };
+    let (table, index) = match table.find('[') {
+        Some(idx) => (&table[..idx], table[idx + 1..table.len() - 1].parse::<usize>().ok()),
+        None => (table, None),
+    };
+    let mut current = String::new();
+    // The number of entries seen in the current array of tables
+    let mut entries = 0;
+    for (number, line) in source.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.starts_with('[') {
+            let name = dotted_name(line.trim_matches(|c: char| c == '[' || c == ']'));
+            if line.starts_with("[[") {
+                entries = if name == current { entries + 1 } else { 1 };
+            }
+            current = name;
+            if current == setting {
+                return Some(number + 1);
+            }
+            continue;
+        }
+        let in_table = current == table && index.map(|index| entries == index + 1).unwrap_or(true);
+        if in_table && line.contains('=') && dotted_name(line.split('=').next().unwrap_or("")) == key {
+            return Some(number + 1);
+        }
+    }
+    return None;
+}
+
+/// Removes a comment from the end of a line, a # inside a quoted string doesn't start one
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    for (idx, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..idx],
+            None => (),
+        }
+    }
+    return line;
+}
+
+/// Unquotes each part of a dotted key and drops the spaces around the parts
+/// ex: ```"client" . 'servers'``` is client.servers
+fn dotted_name(name: &str) -> String {
+    let parts: Vec<&str> = name.split('.')
+        .map(|part| part.trim().trim_matches(|c: char| c == '"' || c == '\''))
+        .collect();
+    return parts.join(".");
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use super::{error_setting, line_of, Config};
+
+    fn problems(source: &str) -> Vec<String> {
+        let config: Config = toml::from_str(source).unwrap();
+        return config.validate().into_iter().map(|(setting, _)| setting).collect();
+    }
+
+    #[test]
+    fn an_empty_config_is_valid() {
+        assert!(problems("").is_empty());
+        assert!(problems("[server]\nport = 9000\n[client]\n[[client.servers]]\naddr = \"host:8080\"\n").is_empty());
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        assert_eq!(problems("domain = \"\"\n[server]\nport = 0\nbind = \"localhost\"\n"),
+                   vec!["domain", "server.bind", "server.port"]);
+        assert_eq!(problems("[client]\nvirtual_nodes = 0\n[[client.servers]]\naddr = \"a:1\"\n[[client.servers]]\naddr = \"b\"\nweight = 0\n"),
+                   vec!["client.virtual_nodes", "client.servers[1].addr", "client.servers[1].weight"]);
+        assert_eq!(problems("[server]\nport = 60000\ncluster_port_offset = 10000\n"),
+                   vec!["server.cluster_port_offset"]);
+        assert_eq!(problems("[server]\npool_size = 0\nmax_connections = 0\nreplication_backlog_bytes = 0\n"),
+                   vec!["server.pool_size", "server.max_connections", "server.replication_backlog_bytes"]);
+    }
+
+    #[test]
+    fn errors_name_the_setting() {
+        assert_eq!(error_setting("unknown field `prot`, expected one of `bind`, `port` for key `server`"),
+                   "server.prot");
+        assert_eq!(error_setting("unknown field `sll`, expected one of `ssl`, `domain`"), "sll");
+        assert_eq!(error_setting("invalid type: string \"x\", expected u16 for key `server.port`"), "server.port");
+        assert_eq!(error_setting("expected an equals, found eof at line 1"), "");
+    }
+
+    #[test]
+    fn line_of_finds_keys_and_tables() {
+        let source = "domain = \"rem\"\n\n[server]\nport = 0\n# bind = \"x\"\nbind = \"x\"\n";
+        assert_eq!(line_of(source, "domain"), Some(1));
+        assert_eq!(line_of(source, "server"), Some(3));
+        assert_eq!(line_of(source, "server.port"), Some(4));
+        assert_eq!(line_of(source, "server.bind"), Some(6));
+        assert_eq!(line_of(source, "server.data_dir"), None);
+        assert_eq!(line_of(source, "client.virtual_nodes"), None);
+        assert_eq!(line_of(source, ""), None);
+    }
+
+    #[test]
+    fn line_of_ignores_comments_and_quotes() {
+        let source = "[server] # the server\n\"port\" = 0 # no port\n[ \"client\" ]\n'virtual_nodes' = 0\n";
+        assert_eq!(line_of(source, "server"), Some(1));
+        assert_eq!(line_of(source, "server.port"), Some(2));
+        assert_eq!(line_of(source, "client.virtual_nodes"), Some(4));
+
+        let source = "domain = \"a # b\" # c\n[server]\ncert_file = \"#\"\nport = 0\n";
+        assert_eq!(line_of(source, "domain"), Some(1));
+        assert_eq!(line_of(source, "server.port"), Some(4));
+    }
+
+    #[test]
+    fn line_of_counts_entries_in_an_array_of_tables() {
+        let source = "[client]\n[[client.servers]]\naddr = \"a:1\"\n[[ client.servers ]] # second\naddr = \"b\"\n[server]\naddr = \"c\"\n";
+        assert_eq!(line_of(source, "client.servers[0].addr"), Some(3));
+        assert_eq!(line_of(source, "client.servers[1].addr"), Some(5));
+        assert_eq!(line_of(source, "client.servers[2].addr"), None);
+    }
+}
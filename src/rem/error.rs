@@ -6,13 +6,68 @@ use native_tls;
 
 use backtrace::Backtrace;
 
-pub const REM_00001: &'static str = "REM_00001: A run mode must be specified. One of [server, client] \
+pub const REM_00001: &'static str = "REM_00001: A run mode must be specified. One of [server, client, config check] \
                                  expected";
 pub const REM_00002: &'static str = "REM_00002: Unexpected argument encountered";
 pub const REM_00003: &'static str = "REM_00003: IO operation failed";
 pub const REM_00004: &'static str = "REM_00004: Failed to parse integer value from string";
 pub const REM_00005: &'static str = "REM_00005: Invalid key";
 pub const REM_00006: &'static str = "REM_00006: TLS Error";
+pub const REM_00007: &'static str = "REM_00007: Invalid client command";
+pub const REM_00008: &'static str = "REM_00008: Script execution failed";
+pub const REM_00009: &'static str = "REM_00009: Unterminated quote";
+pub const REM_00010: &'static str = "REM_00010: Invalid escape sequence";
+pub const REM_00011: &'static str = "REM_00011: Invalid output format";
+pub const REM_00012: &'static str = "REM_00012: Value is not an integer";
+pub const REM_00013: &'static str = "REM_00013: Increment or decrement would overflow";
+pub const REM_00014: &'static str = "REM_00014: Write condition not met";
+pub const REM_00015: &'static str = "REM_00015: Malformed list";
+pub const REM_00016: &'static str = "REM_00016: Invalid scan cursor";
+pub const REM_00017: &'static str = "REM_00017: Scan count must be greater than 0";
+pub const REM_00018: &'static str = "REM_00018: MULTI calls can not be nested";
+pub const REM_00019: &'static str = "REM_00019: No transaction has been started with MULTI";
+pub const REM_00020: &'static str = "REM_00020: WATCH is not allowed inside a transaction";
+pub const REM_00021: &'static str = "REM_00021: Transaction aborted, a watched key changed";
+pub const REM_00022: &'static str = "REM_00022: WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const REM_00023: &'static str = "REM_00023: List is empty";
+pub const REM_00024: &'static str = "REM_00024: Timed out waiting for an element";
+pub const REM_00025: &'static str = "REM_00025: Hash field not found";
+pub const REM_00026: &'static str = "REM_00026: Value is not a valid score";
+pub const REM_00027: &'static str = "REM_00027: Command is not allowed inside a transaction";
+pub const REM_00028: &'static str = "REM_00028: Only SUBSCRIBE, PSUBSCRIBE, UNSUBSCRIBE and PUNSUBSCRIBE are allowed while subscribed";
+pub const REM_00029: &'static str = "REM_00029: Not subscribed to any channel or pattern";
+pub const REM_00030: &'static str = "REM_00030: Unexpected response from the primary to SYNC";
+pub const REM_00031: &'static str = "REM_00031: The primary ended the replication stream";
+pub const REM_00032: &'static str = "REM_00032: READONLY Writes are not allowed on a replica";
+pub const REM_00033: &'static str = "REM_00033: Only a primary can be synced from";
+pub const REM_00034: &'static str = "REM_00034: NOTLEADER This node is not the leader, the leader is";
+pub const REM_00035: &'static str = "REM_00035: No leader has been elected yet, try again shortly";
+pub const REM_00036: &'static str = "REM_00036: Leadership changed before the write was committed, it may or may not have been applied";
+pub const REM_00037: &'static str = "REM_00037: Command is not supported in cluster mode";
+pub const REM_00038: &'static str = "REM_00038: The server is not running in cluster mode";
+pub const REM_00039: &'static str = "REM_00039: No servers are listed in the client config";
+pub const REM_00040: &'static str = "REM_00040: Every server has been removed from the hash ring";
+pub const REM_00041: &'static str = "REM_00041: CROSSSLOT The keys in the request belong to different slots";
+pub const REM_00042: &'static str = "REM_00042: MOVED The slot is owned by another node, slot and node:";
+pub const REM_00043: &'static str = "REM_00043: ASK The key has been migrated to another node, slot and node:";
+pub const REM_00044: &'static str = "REM_00044: The slot is not assigned to any node";
+pub const REM_00045: &'static str = "REM_00045: Invalid slot range";
+pub const REM_00046: &'static str = "REM_00046: The slot is not owned by this node";
+pub const REM_00047: &'static str = "REM_00047: TRYAGAIN Some of the keys are being migrated, try again shortly";
+pub const REM_00048: &'static str = "REM_00048: The server is not running with slots";
+pub const REM_00049: &'static str = "REM_00049: The server is not running with gossip";
+pub const REM_00050: &'static str = "REM_00050: Invalid node address, expected ip:port";
+pub const REM_00051: &'static str = "REM_00051: Only a replica can be repaired from its primary";
+pub const REM_00052: &'static str = "REM_00052: The replica has not synced with its primary yet";
+pub const REM_00053: &'static str = "REM_00053: The server is shutting down";
+pub const REM_00054: &'static str = "REM_00054: Invalid config file";
+pub const REM_00055: &'static str = "REM_00055: The logger could not be installed";
+pub const REM_00056: &'static str = "REM_00056: The write was not committed in time, it may or may not have been applied";
+pub const REM_00057: &'static str = "REM_00057: The gossip message is not signed with the cluster secret";
+pub const REM_00058: &'static str = "REM_00058: server.cluster_secret must be set to run with gossip";
+pub const REM_00059: &'static str = "REM_00059: MERKLE must be sent for the root first, it builds the tree of the repair";
+pub const REM_00060: &'static str = "REM_00060: The leader has not heard from a majority of the cluster recently, try again shortly";
+pub const REM_00061: &'static str = "REM_00061: The key is locked by another write, the conditional write was not applied";
 
 /// Simple error structure to be used when errors occur during a cache operation
 #[derive(Debug)]
@@ -51,6 +106,36 @@ impl RemError {
 }
 
 
+/// Error produced when client input cannot be parsed
+///
+/// The column is the 1 based position of the character that caused the error
+#[derive(Debug)]
+pub struct InputError{
+    pub reason: &'static str,
+    pub column: usize
+}
+
+impl InputError {
+    pub fn new(reason: &'static str, column: usize) -> InputError {
+        return InputError {
+            reason: reason,
+            column: column
+        };
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return write!(formatter, "{} at column {}", self.reason, self.column);
+    }
+}
+
+impl From<InputError> for RemError {
+    fn from(e: InputError) -> RemError {
+       return RemError::with_reason(format!("{}", e));
+    }
+}
+
 impl<'a> Error for RemError {
     fn description(&self) -> &str {
         return &self.reason;
@@ -0,0 +1,185 @@
+use std::vec::Vec;
+
+/// Checks if text matches a glob pattern
+///
+/// Supported syntax:
+/// * ```*``` matches any sequence of characters, including none
+/// * ```?``` matches any single character
+/// * ```[abc]``` matches one of the listed characters, ```[a-z]``` matches a range
+///   and ```[!abc]``` matches any character that is not listed
+/// * ```\``` matches the following character literally
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    return matches_from(&pattern, &text);
+}
+
+/// Returns the part of the pattern before the first special character
+///
+/// Every key matching the pattern starts with this prefix which allows keys to be skipped
+/// without running the full match
+pub fn literal_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => break,
+            '\\' => {
+                match chars.next() {
+                    Some(escaped) => prefix.push(escaped),
+                    None => break,
+                }
+            }
+            _ => prefix.push(c),
+        }
+    }
+    return prefix;
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    // Position of the last * seen and the text position it was tried against,
+    // used to backtrack when the rest of the pattern fails to match
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                '[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == '[' {
+                        // An unterminated class is treated as a literal [
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                '\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // The current characters didn't match, let the last * consume one more character
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    // Any remaining pattern must be made up of * to match the empty remainder of the text
+    while p < pattern.len() && pattern[p] == '*' {
+        p += 1;
+    }
+    return p == pattern.len();
+}
+
+/// Matches c against the character class starting at pattern[start], which is a [
+///
+/// Returns whether c matched and the position after the closing ], or None if the class
+/// is never closed
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut idx = start + 1;
+    let negated = idx < pattern.len() && pattern[idx] == '!';
+    if negated {
+        idx += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while idx < pattern.len() {
+        // A ] straight after the opening [ is part of the class
+        if pattern[idx] == ']' && !first {
+            return Some((matched != negated, idx + 1));
+        }
+        first = false;
+        let mut low = pattern[idx];
+        if low == '\\' && idx + 1 < pattern.len() {
+            idx += 1;
+            low = pattern[idx];
+        }
+        if idx + 2 < pattern.len() && pattern[idx + 1] == '-' && pattern[idx + 2] != ']' {
+            let high = pattern[idx + 2];
+            if low <= c && c <= high {
+                matched = true;
+            }
+            idx += 3;
+        } else {
+            if low == c {
+                matched = true;
+            }
+            idx += 1;
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{literal_prefix, matches};
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("user:*", "user:1"));
+        assert!(matches("user:*:name", "user:12:name"));
+        assert!(!matches("user:*:name", "user:12:age"));
+        assert!(matches("*a*b", "xaybzb"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+    }
+
+    #[test]
+    fn matches_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("h[!e]llo", "hallo"));
+        assert!(!matches("h[!e]llo", "hello"));
+        // An unterminated class is a literal [
+        assert!(matches("a[b", "a[b"));
+    }
+
+    #[test]
+    fn matches_escapes_literally() {
+        assert!(matches("a\\*", "a*"));
+        assert!(!matches("a\\*", "ab"));
+        assert!(matches("what\\?", "what?"));
+    }
+
+    #[test]
+    fn literal_prefix_stops_at_the_first_special_character() {
+        assert_eq!(literal_prefix("user:*"), "user:");
+        assert_eq!(literal_prefix("a\\*b?c"), "a*b");
+        assert_eq!(literal_prefix("[ab]"), "");
+        assert_eq!(literal_prefix("plain"), "plain");
+    }
+}
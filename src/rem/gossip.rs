@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use rem::hmac;
+use rem::op;
+use rem::pubsub::PubSub;
+use rem::error::*;
+
+/// How often a node pings one of the other members. A member that hasn't acknowledged its ping
+/// half way through the round is pinged through other members, it becomes suspect if no ack
+/// arrives by the end of the round
+pub const GOSSIP_INTERVAL_MS: u64 = 1000;
+
+/// A suspect member that doesn't refute the suspicion within this time is declared dead
+pub const SUSPECT_TIMEOUT_MS: u64 = 5000;
+
+/// A dead member is forgotten after this time, so a server that left for good stops being listed
+pub const DEAD_TIMEOUT_MS: u64 = 30000;
+
+/// The most members asked to ping a member that didn't acknowledge a ping
+pub const INDIRECT_PROBES: usize = 3;
+
+/// The most members described in a single message, the most recent changes are sent first
+pub const MAX_GOSSIP_ENTRIES: usize = 100;
+
+/// The largest message that fits in a UDP datagram
+const MAX_MESSAGE_SIZE: usize = 65507;
+
+/// The prefix of the channels membership changes are published to, a change is published to
+/// ```__cluster__:{state}``` with the address of the member as the message
+pub const CLUSTER_EVENT_PREFIX: &'static str = "__cluster__:";
+
+const PING: &'static [u8] = b"ping";
+const PING_REQ: &'static [u8] = b"ping-req";
+const ACK: &'static [u8] = b"ack";
+
+/// The state of a member as seen by this node
+///
+/// The states are ordered so that for the same incarnation a later state overrides an earlier one
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum MemberState {
+    ALIVE,
+    /// The member didn't respond to a ping, it is declared dead unless it refutes this in time
+    SUSPECT,
+    DEAD,
+}
+
+impl MemberState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MemberState::ALIVE => "alive",
+            MemberState::SUSPECT => "suspect",
+            MemberState::DEAD => "dead",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MemberState> {
+        match name {
+            "alive" => Some(MemberState::ALIVE),
+            "suspect" => Some(MemberState::SUSPECT),
+            "dead" => Some(MemberState::DEAD),
+            _ => None,
+        }
+    }
+}
+
+/// Another server in the membership table
+#[derive(Debug, Clone)]
+struct Member {
+    state: MemberState,
+    /// Only the member increases its incarnation, which it does to refute a suspicion
+    incarnation: u64,
+    /// When the member entered its state
+    since: Instant
+}
+
+/// The servers this node knows about and whether they are alive
+///
+/// Each round the node pings one member and every message carries the latest changes to the
+/// table, so changes spread to every node in a number of rounds that grows with the log of the
+/// number of nodes. A member that doesn't answer is pinged through other members before it
+/// becomes suspect, so a single lost datagram doesn't make it suspect. A suspect member isn't
+/// dead straight away, when it hears it is suspected it increases its incarnation and the news
+/// that it is alive replaces the suspicion. Members are known by their client address ip:port
+/// and gossip on their client port plus the configured offset
+///
+/// Every message is signed with the cluster secret and messages that aren't are dropped, so only
+/// servers that know the secret can change the table. A replayed message only repeats news that
+/// is already known, a member's incarnation never goes back
+pub struct Membership {
+    /// The client address of this node
+    pub id: String,
+    incarnation: u64,
+    members: BTreeMap<String, Member>,
+    /// Servers to ping until they are members, new nodes join the cluster through them
+    seeds: Vec<String>,
+    port_offset: u16,
+    secret: Vec<u8>,
+    next_seq: u64,
+    /// The member waiting on an ack and the sequence number of the ping
+    probe: Option<(String, u64)>,
+    /// The position in the members of the next one to ping, members are pinged in turn
+    next_probe: usize,
+    /// Pings sent for other members by their sequence number, with the member that asked, the
+    /// sequence number it asked with, the member pinged and when it was sent
+    relays: HashMap<u64, (String, u64, String, Instant)>,
+    /// Changes of state that haven't been published yet
+    events: Vec<(MemberState, String)>
+}
+
+impl Membership {
+    pub fn new(id: String, seeds: Vec<String>, port_offset: u16, secret: String) -> Membership {
+        let seeds = seeds.into_iter().filter(|seed| *seed != id).collect();
+        return Membership {
+            id: id,
+            incarnation: 0,
+            members: BTreeMap::new(),
+            seeds: seeds,
+            port_offset: port_offset,
+            secret: secret.into_bytes(),
+            next_seq: 0,
+            probe: None,
+            next_probe: 0,
+            relays: HashMap::new(),
+            events: Vec::new()
+        };
+    }
+
+    /// Describes every node starting with this one, each item is
+    /// ```{addr}:{state}:{incarnation}```
+    pub fn info(&self) -> Vec<String> {
+        let mut info = vec![format!("{}:{}:{}", self.id, MemberState::ALIVE.name(), self.incarnation)];
+        for (addr, member) in &self.members {
+            info.push(format!("{}:{}:{}", addr, member.state.name(), member.incarnation));
+        }
+        return info;
+    }
+
+    /// Returns the state of a member, None if it isn't known
+    pub fn state_of(&self, addr: &str) -> Option<MemberState> {
+        return self.members.get(addr).map(|member| member.state);
+    }
+
+    /// Expires probes, suspicions and dead members, then returns the member to ping this round
+    /// and the ping
+    fn tick(&mut self) -> Vec<(String, Vec<u8>)> {
+        let now = Instant::now();
+        if let Some((addr, _)) = self.probe.take() {
+            // Only a member that is alive becomes suspect, a seed that never answered is not a member
+            let incarnation = self.members
+                .get(&addr)
+                .and_then(|member| if member.state == MemberState::ALIVE { Some(member.incarnation) } else { None });
+            if let Some(incarnation) = incarnation {
+                self.update(addr.as_str(), MemberState::SUSPECT, incarnation);
+            }
+        }
+        let expired: Vec<(String, u64)> = self.members
+            .iter()
+            .filter(|&(_, member)| {
+                member.state == MemberState::SUSPECT &&
+                now.duration_since(member.since) >= Duration::from_millis(SUSPECT_TIMEOUT_MS)
+            })
+            .map(|(addr, member)| (addr.clone(), member.incarnation))
+            .collect();
+        for (addr, incarnation) in expired {
+            self.update(addr.as_str(), MemberState::DEAD, incarnation);
+        }
+        let forgotten: Vec<String> = self.members
+            .iter()
+            .filter(|&(_, member)| {
+                member.state == MemberState::DEAD &&
+                now.duration_since(member.since) >= Duration::from_millis(DEAD_TIMEOUT_MS)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in forgotten {
+            info!("Forgetting dead member {}", addr);
+            self.members.remove(&addr);
+        }
+        self.relays.retain(|_, relay| now.duration_since(relay.3) < Duration::from_millis(GOSSIP_INTERVAL_MS));
+
+        // Seeds are pinged along with the members until they answer, dead members are not
+        let mut targets: Vec<String> = self.members
+            .iter()
+            .filter(|&(_, member)| member.state != MemberState::DEAD)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for seed in &self.seeds {
+            if !targets.contains(seed) {
+                targets.push(seed.clone());
+            }
+        }
+        if targets.is_empty() {
+            return Vec::new();
+        }
+        let target = targets[self.next_probe % targets.len()].clone();
+        self.next_probe = self.next_probe.wrapping_add(1);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.probe = Some((target.clone(), seq));
+        let ping = self.message(PING, seq, target.as_str());
+        return vec![(target, ping)];
+    }
+
+    /// Asks other members that are alive to ping the member that hasn't acknowledged this
+    /// round's ping, returns the requests and the members to send them to
+    fn probe_indirectly(&mut self) -> Vec<(String, Vec<u8>)> {
+        let (target, seq) = match self.probe {
+            Some((ref target, seq)) => (target.clone(), seq),
+            None => return Vec::new(),
+        };
+        let helpers: Vec<String> = self.members
+            .iter()
+            .filter(|&(addr, member)| member.state == MemberState::ALIVE && *addr != target)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        if helpers.is_empty() {
+            return Vec::new();
+        }
+        let first = self.next_probe % helpers.len();
+        let mut requests = Vec::new();
+        for idx in 0..INDIRECT_PROBES.min(helpers.len()) {
+            let helper = helpers[(first + idx) % helpers.len()].clone();
+            requests.push((helper, self.message(PING_REQ, seq, target.as_str())));
+        }
+        return requests;
+    }
+
+    /// Handles a message from another node and returns the messages to send in response and the
+    /// members to send them to
+    fn handle(&mut self, bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>, RemError> {
+        if bytes.len() < hmac::MAC_LEN || !hmac::verify(&self.secret, &bytes[hmac::MAC_LEN..], &bytes[..hmac::MAC_LEN]) {
+            return Err(RemError::with_reason_str(REM_00057));
+        }
+        let items = try!(op::decode_list(&bytes[hmac::MAC_LEN..]));
+        if items.len() < 4 {
+            return Err(RemError::with_reason_str(REM_00015));
+        }
+        let from = String::from_utf8_lossy(&items[1]).into_owned();
+        let seq = try!(op::parse_integer(&items[2])) as u64;
+        let about = String::from_utf8_lossy(&items[3]).into_owned();
+        for entry in &items[4..] {
+            let (addr, state, incarnation) = try!(decode_entry(entry));
+            self.merge(addr, state, incarnation);
+        }
+        if items[0] == PING {
+            let id = self.id.clone();
+            return Ok(vec![(from, self.message(ACK, seq, id.as_str()))]);
+        }
+        if items[0] == PING_REQ {
+            // The target's ack is passed on to the member that asked with the sequence number it asked with
+            let relay_seq = self.next_seq;
+            self.next_seq += 1;
+            self.relays.insert(relay_seq, (from, seq, about.clone(), Instant::now()));
+            let ping = self.message(PING, relay_seq, about.as_str());
+            return Ok(vec![(about, ping)]);
+        }
+        if items[0] == ACK {
+            let answered = match self.probe {
+                Some((ref addr, probe_seq)) => *addr == about && probe_seq == seq,
+                None => false,
+            };
+            if answered {
+                self.probe = None;
+                return Ok(Vec::new());
+            }
+            let relayed = match self.relays.get(&seq) {
+                Some(&(_, _, ref target, _)) => *target == about,
+                None => false,
+            };
+            if relayed {
+                let (requester, requester_seq, _, _) = self.relays.remove(&seq).unwrap();
+                let ack = self.message(ACK, requester_seq, about.as_str());
+                return Ok(vec![(requester, ack)]);
+            }
+        }
+        return Ok(Vec::new());
+    }
+
+    /// Applies what another node says about a member
+    ///
+    /// News with a higher incarnation always wins, for the same incarnation suspect overrides
+    /// alive and dead overrides both. A node that hears it is suspected or dead refutes it. The
+    /// death of a member that isn't known is ignored so a forgotten member isn't added back
+    fn merge(&mut self, addr: String, state: MemberState, incarnation: u64) {
+        if addr == self.id {
+            if state != MemberState::ALIVE && incarnation >= self.incarnation {
+                self.incarnation = incarnation + 1;
+                info!("Refuting that this node is {}, incarnation is now {}", state.name(), self.incarnation);
+            }
+            return;
+        }
+        let newer = match self.members.get(&addr) {
+            Some(member) => {
+                incarnation > member.incarnation || (incarnation == member.incarnation && state > member.state)
+            }
+            None => state != MemberState::DEAD,
+        };
+        if newer {
+            self.update(addr.as_str(), state, incarnation);
+        }
+    }
+
+    fn update(&mut self, addr: &str, state: MemberState, incarnation: u64) {
+        let changed = self.members.get(addr).map(|member| member.state != state).unwrap_or(true);
+        self.members.insert(String::from(addr), Member {
+            state: state,
+            incarnation: incarnation,
+            since: Instant::now()
+        });
+        if changed {
+            info!("Member {} is {}", addr, state.name());
+            self.events.push((state, String::from(addr)));
+        }
+    }
+
+    /// Builds a signed message, the MAC of ```[kind, from, seq, about, entries...]``` followed
+    /// by the list
+    ///
+    /// The member a message is about is the one pinged or the one acknowledging. The entries
+    /// start with this node followed by the members that changed most recently
+    fn message(&self, kind: &[u8], seq: u64, about: &str) -> Vec<u8> {
+        let mut items: Vec<Vec<u8>> = vec![kind.to_vec(),
+                                           self.id.clone().into_bytes(),
+                                           seq.to_string().into_bytes(),
+                                           about.as_bytes().to_vec(),
+                                           encode_entry(self.id.as_str(), MemberState::ALIVE, self.incarnation)];
+        let mut members: Vec<(&String, &Member)> = self.members.iter().collect();
+        members.sort_by(|a, b| b.1.since.cmp(&a.1.since));
+        for (addr, member) in members.into_iter().take(MAX_GOSSIP_ENTRIES - 1) {
+            items.push(encode_entry(addr.as_str(), member.state, member.incarnation));
+        }
+        let body = op::encode_list(&items);
+        let mut message = hmac::hmac_sha256(&self.secret, &body).to_vec();
+        message.extend_from_slice(&body);
+        return message;
+    }
+}
+
+/// Starts gossiping with the other nodes, a thread answers messages and another pings a
+/// member each round
+pub fn start(membership: Arc<Mutex<Membership>>, pubsub: Arc<Mutex<PubSub>>) -> Result<(), RemError> {
+    let (bind_addr, port_offset) = {
+        let membership = membership.lock().unwrap();
+        (try!(gossip_addr(membership.id.as_str(), membership.port_offset)), membership.port_offset)
+    };
+    let socket = try!(UdpSocket::bind(bind_addr));
+    info!("Gossiping on {}", bind_addr);
+
+    let recv_socket = try!(socket.try_clone());
+    let recv_membership = membership.clone();
+    let recv_pubsub = pubsub.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let (len, from) = match recv_socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(why) => {
+                    warn!("Failed to receive a gossip message: {}", why);
+                    continue;
+                }
+            };
+            let replies = recv_membership.lock().unwrap().handle(&buf[..len]);
+            publish_events(&recv_membership, &recv_pubsub);
+            match replies {
+                Ok(replies) => send_all(&recv_socket, port_offset, replies),
+                Err(why) => warn!("Ignoring an invalid gossip message from {}: {}", from, why),
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let half = Duration::from_millis(GOSSIP_INTERVAL_MS / 2);
+        loop {
+            thread::sleep(half);
+            let pings = membership.lock().unwrap().tick();
+            publish_events(&membership, &pubsub);
+            send_all(&socket, port_offset, pings);
+            thread::sleep(half);
+            let requests = membership.lock().unwrap().probe_indirectly();
+            send_all(&socket, port_offset, requests);
+        }
+    });
+    return Ok(());
+}
+
+/// Sends each message to the gossip address of its member
+fn send_all(socket: &UdpSocket, port_offset: u16, messages: Vec<(String, Vec<u8>)>) {
+    for (target, message) in messages {
+        match gossip_addr(target.as_str(), port_offset) {
+            Ok(addr) => {
+                if let Err(why) = socket.send_to(&message, addr) {
+                    warn!("Failed to send a gossip message to {}: {}", target, why);
+                }
+            }
+            Err(why) => warn!("Failed to send a gossip message to {}: {}", target, why),
+        }
+    }
+}
+
+/// Publishes the membership changes to ```__cluster__:{state}```
+fn publish_events(membership_mtx: &Mutex<Membership>, pubsub_mtx: &Mutex<PubSub>) {
+    let events = {
+        let mut membership = membership_mtx.lock().unwrap();
+        if membership.events.is_empty() {
+            return;
+        }
+        ::std::mem::replace(&mut membership.events, Vec::new())
+    };
+    let mut pubsub = pubsub_mtx.lock().unwrap();
+    for (state, addr) in events {
+        pubsub.publish(&format!("{}{}", CLUSTER_EVENT_PREFIX, state.name()), addr.as_bytes());
+    }
+}
+
+/// Returns the address a node gossips on, its client port plus the offset
+fn gossip_addr(addr: &str, port_offset: u16) -> Result<SocketAddr, RemError> {
+    let invalid = || RemError::with_reason(format!("{}: {}", REM_00050, addr));
+    let idx = try!(addr.rfind(':').ok_or_else(&invalid));
+    let port = try!(addr[idx + 1..].parse::<u16>().map_err(|_| invalid()));
+    let port = try!(port.checked_add(port_offset).ok_or_else(&invalid));
+    let mut addrs = try!((&addr[..idx], port).to_socket_addrs());
+    return addrs.next().ok_or_else(invalid);
+}
+
+/// Encodes what is known about a member as ```{state}:{incarnation}:{addr}```
+fn encode_entry(addr: &str, state: MemberState, incarnation: u64) -> Vec<u8> {
+    return format!("{}:{}:{}", state.name(), incarnation, addr).into_bytes();
+}
+
+fn decode_entry(bytes: &[u8]) -> Result<(String, MemberState, u64), RemError> {
+    let entry = String::from_utf8_lossy(bytes);
+    let parts: Vec<&str> = entry.splitn(3, ':').collect();
+    if parts.len() != 3 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let state = try!(MemberState::from_name(parts[0]).ok_or(RemError::with_reason_str(REM_00015)));
+    let incarnation = try!(op::parse_integer(parts[1].as_bytes())) as u64;
+    return Ok((String::from(parts[2]), state, incarnation));
+}
+
+/// Decodes an item of Membership::info, as sent in response to MEMBERS, ```{addr}:{state}:{incarnation}```
+pub fn decode_member(item: &str) -> Result<(String, MemberState, u64), RemError> {
+    let parts: Vec<&str> = item.rsplitn(3, ':').collect();
+    if parts.len() != 3 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let state = try!(MemberState::from_name(parts[1]).ok_or(RemError::with_reason_str(REM_00015)));
+    let incarnation = try!(op::parse_integer(parts[0].as_bytes())) as u64;
+    return Ok((String::from(parts[2]), state, incarnation));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Membership, MemberState, ACK, DEAD_TIMEOUT_MS, PING, PING_REQ};
+    use rem::hmac;
+    use rem::op;
+
+    fn membership(id: &str) -> Membership {
+        return Membership::new(String::from(id), Vec::new(), 10000, String::from("secret"));
+    }
+
+    fn kind_of(message: &[u8]) -> Vec<u8> {
+        return op::decode_list(&message[hmac::MAC_LEN..]).unwrap()[0].clone();
+    }
+
+    #[test]
+    fn messages_signed_with_another_secret_are_dropped() {
+        let mut a = membership("127.0.0.1:1");
+        let mut b = Membership::new(String::from("127.0.0.1:2"), Vec::new(), 10000, String::from("other"));
+        let ping = b.message(PING, 0, "127.0.0.1:1");
+        assert!(a.handle(&ping).is_err());
+        assert!(a.handle(b"short").is_err());
+        assert_eq!(a.state_of("127.0.0.1:2"), None);
+
+        let mut tampered = membership("127.0.0.1:2").message(PING, 0, "127.0.0.1:1");
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(a.handle(&tampered).is_err());
+        assert!(b.handle(&a.message(PING, 0, "127.0.0.1:2")).is_err());
+    }
+
+    #[test]
+    fn a_ping_is_acked_and_adds_the_sender() {
+        let mut a = membership("127.0.0.1:1");
+        let mut b = membership("127.0.0.1:2");
+        let replies = a.handle(&b.message(PING, 7, "127.0.0.1:1")).unwrap();
+        assert_eq!(a.state_of("127.0.0.1:2"), Some(MemberState::ALIVE));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, "127.0.0.1:2");
+        assert_eq!(kind_of(&replies[0].1), ACK.to_vec());
+        assert!(b.handle(&replies[0].1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn an_unanswered_ping_is_relayed_through_other_members() {
+        let mut a = membership("127.0.0.1:1");
+        let mut b = membership("127.0.0.1:2");
+        let mut c = membership("127.0.0.1:3");
+        a.update("127.0.0.1:2", MemberState::ALIVE, 0);
+        a.update("127.0.0.1:3", MemberState::ALIVE, 0);
+        a.probe = Some((String::from("127.0.0.1:3"), 5));
+
+        let requests = a.probe_indirectly();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "127.0.0.1:2");
+        assert_eq!(kind_of(&requests[0].1), PING_REQ.to_vec());
+
+        let pings = b.handle(&requests[0].1).unwrap();
+        assert_eq!(pings[0].0, "127.0.0.1:3");
+        let acks = c.handle(&pings[0].1).unwrap();
+        assert_eq!(acks[0].0, "127.0.0.1:2");
+        let relayed = b.handle(&acks[0].1).unwrap();
+        assert_eq!(relayed[0].0, "127.0.0.1:1");
+        assert!(a.handle(&relayed[0].1).unwrap().is_empty());
+        assert!(a.probe.is_none());
+
+        // The member stays alive at the next round as its ack arrived
+        a.tick();
+        assert_eq!(a.state_of("127.0.0.1:3"), Some(MemberState::ALIVE));
+    }
+
+    #[test]
+    fn a_member_that_never_acks_becomes_suspect() {
+        let mut a = membership("127.0.0.1:1");
+        a.update("127.0.0.1:2", MemberState::ALIVE, 0);
+        a.tick();
+        a.tick();
+        assert_eq!(a.state_of("127.0.0.1:2"), Some(MemberState::SUSPECT));
+    }
+
+    #[test]
+    fn dead_members_are_forgotten_and_not_added_back() {
+        let mut a = membership("127.0.0.1:1");
+        a.update("127.0.0.1:2", MemberState::DEAD, 3);
+        a.members.get_mut("127.0.0.1:2").unwrap().since = Instant::now() - Duration::from_millis(DEAD_TIMEOUT_MS);
+        a.tick();
+        assert_eq!(a.state_of("127.0.0.1:2"), None);
+
+        a.merge(String::from("127.0.0.1:2"), MemberState::DEAD, 3);
+        assert_eq!(a.state_of("127.0.0.1:2"), None);
+        a.merge(String::from("127.0.0.1:2"), MemberState::ALIVE, 4);
+        assert_eq!(a.state_of("127.0.0.1:2"), Some(MemberState::ALIVE));
+    }
+}
@@ -0,0 +1,178 @@
+use std::vec::Vec;
+
+/// The length of a SHA-256 digest and so of a MAC
+pub const MAC_LEN: usize = 32;
+
+const BLOCK_LEN: usize = 64;
+
+const K: [u32; 64] = [0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+                      0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+                      0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+                      0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+                      0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+                      0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+                      0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+                      0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2];
+
+const H0: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+
+/// Returns the SHA-256 digest of the parts as if they were one message
+pub fn sha256(parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    let mut state = H0;
+    let mut block = [0u8; BLOCK_LEN];
+    let mut filled = 0;
+    let mut total: u64 = 0;
+    for part in parts {
+        for byte in part.iter() {
+            block[filled] = *byte;
+            filled += 1;
+            if filled == BLOCK_LEN {
+                compress(&mut state, &block);
+                filled = 0;
+            }
+        }
+        total += part.len() as u64;
+    }
+
+    // Pads with a 1 bit, zeros and the length in bits so the message fills whole blocks
+    block[filled] = 0x80;
+    filled += 1;
+    if filled > BLOCK_LEN - 8 {
+        for byte in block[filled..].iter_mut() {
+            *byte = 0;
+        }
+        compress(&mut state, &block);
+        filled = 0;
+    }
+    for byte in block[filled..BLOCK_LEN - 8].iter_mut() {
+        *byte = 0;
+    }
+    let bits = total.wrapping_mul(8);
+    for idx in 0..8 {
+        block[BLOCK_LEN - 8 + idx] = (bits >> (56 - 8 * idx)) as u8;
+    }
+    compress(&mut state, &block);
+
+    let mut digest = [0u8; MAC_LEN];
+    for (idx, word) in state.iter().enumerate() {
+        for shift in 0..4 {
+            digest[idx * 4 + shift] = (word >> (24 - 8 * shift)) as u8;
+        }
+    }
+    return digest;
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; BLOCK_LEN]) {
+    let mut w = [0u32; 64];
+    for idx in 0..16 {
+        w[idx] = (block[idx * 4] as u32) << 24 | (block[idx * 4 + 1] as u32) << 16 |
+                 (block[idx * 4 + 2] as u32) << 8 | block[idx * 4 + 3] as u32;
+    }
+    for idx in 16..64 {
+        let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+        let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+        w[idx] = w[idx - 16].wrapping_add(s0).wrapping_add(w[idx - 7]).wrapping_add(s1);
+    }
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+    let (mut e, mut f, mut g, mut h) = (state[4], state[5], state[6], state[7]);
+    for idx in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[idx]).wrapping_add(w[idx]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    let words = [a, b, c, d, e, f, g, h];
+    for idx in 0..8 {
+        state[idx] = state[idx].wrapping_add(words[idx]);
+    }
+}
+
+/// Returns the HMAC-SHA256 of a message, as in RFC 2104
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; MAC_LEN] {
+    let mut padded = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        padded[..MAC_LEN].copy_from_slice(&sha256(&[key]));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+    let inner_key: Vec<u8> = padded.iter().map(|byte| byte ^ 0x36).collect();
+    let outer_key: Vec<u8> = padded.iter().map(|byte| byte ^ 0x5c).collect();
+    let inner = sha256(&[&inner_key, message]);
+    return sha256(&[&outer_key, &inner]);
+}
+
+/// Checks a MAC in constant time so the time taken doesn't tell how much of it matched
+pub fn verify(key: &[u8], message: &[u8], mac: &[u8]) -> bool {
+    if mac.len() != MAC_LEN {
+        return false;
+    }
+    let expected = hmac_sha256(key, message);
+    let mut diff = 0u8;
+    for (a, b) in expected.iter().zip(mac.iter()) {
+        diff |= a ^ b;
+    }
+    return diff == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_sha256, sha256, verify};
+
+    fn hex(bytes: &[u8]) -> String {
+        return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    }
+
+    #[test]
+    fn sha256_matches_the_fips_examples() {
+        assert_eq!(hex(&sha256(&[b""])),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(&[b"abc"])),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"])),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        let million = vec![b'a'; 1000000];
+        assert_eq!(hex(&sha256(&[&million])),
+                   "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn sha256_of_parts_is_the_sha256_of_the_whole() {
+        let whole = b"The quick brown fox jumps over the lazy dog, then over the lazy cat";
+        assert_eq!(sha256(&[&whole[..10], &whole[10..63], &whole[63..]]), sha256(&[whole]));
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        // Test case 1
+        assert_eq!(hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+                   "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        // Test case 2
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // Test case 3
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 20], &[0xdd; 50])),
+                   "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe");
+        // Test case 6, a key longer than a block is hashed first
+        assert_eq!(hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn verify_rejects_a_changed_message_or_key() {
+        let mac = hmac_sha256(b"secret", b"ping");
+        assert!(verify(b"secret", b"ping", &mac));
+        assert!(!verify(b"secret", b"pong", &mac));
+        assert!(!verify(b"other", b"ping", &mac));
+        assert!(!verify(b"secret", b"ping", &mac[..31]));
+    }
+}
@@ -0,0 +1,75 @@
+use std::env;
+use std::sync::{Arc, RwLock};
+
+use env_logger::{LogBuilder, Logger};
+use log::{self, Log, LogMetadata, LogRecord, MaxLogLevelFilter};
+
+use rem::error::*;
+
+/// The logger, its filters can be replaced while the server runs
+///
+/// Filters use the same syntax as RUST_LOG, ex: ```info``` or ```rem::raft=debug```
+#[derive(Clone)]
+pub struct LogHandle {
+    inner: Arc<Filtered>
+}
+
+struct Filtered {
+    logger: RwLock<Logger>,
+    max_level: MaxLogLevelFilter
+}
+
+/// Installs the logger, filtered by RUST_LOG until other filters are set
+pub fn init() -> Result<LogHandle, RemError> {
+    let mut handle = None;
+    let installed = log::set_logger(|max_level| {
+        let logger = build(None);
+        max_level.set(logger.filter());
+        let log = LogHandle {
+            inner: Arc::new(Filtered {
+                logger: RwLock::new(logger),
+                max_level: max_level
+            })
+        };
+        handle = Some(log.clone());
+        return Box::new(log);
+    });
+    if let Err(why) = installed {
+        return Err(RemError::with_reason(format!("{}: {}", REM_00055, why)));
+    }
+    return Ok(handle.unwrap());
+}
+
+impl LogHandle {
+    /// Replaces the filters, RUST_LOG is used again when filters is None
+    pub fn set_filters(&self, filters: Option<&str>) {
+        let logger = build(filters);
+        self.inner.max_level.set(logger.filter());
+        *self.inner.logger.write().unwrap() = logger;
+    }
+}
+
+impl Log for LogHandle {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        return self.inner.logger.read().unwrap().enabled(metadata);
+    }
+
+    fn log(&self, record: &LogRecord) {
+        self.inner.logger.read().unwrap().log(record);
+    }
+}
+
+fn build(filters: Option<&str>) -> Logger {
+    let mut builder = LogBuilder::new();
+    match filters {
+        Some(filters) => {
+            builder.parse(filters);
+        }
+        None => {
+            if let Ok(filters) = env::var("RUST_LOG") {
+                builder.parse(&filters);
+            }
+        }
+    }
+    return builder.build();
+}
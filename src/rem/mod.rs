@@ -7,4 +7,20 @@ pub mod codec;
 pub mod proto;
 pub mod service;
 pub mod tcp_stream;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod script;
+pub mod output;
+pub mod rem_client;
+pub mod ring;
+pub mod sharded_client;
+pub mod glob;
+pub mod value;
+pub mod pubsub;
+pub mod replication;
+pub mod raft;
+pub mod slots;
+pub mod gossip;
+pub mod hmac;
+pub mod anti_entropy;
+pub mod logging;
+pub mod reload;
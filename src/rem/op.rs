@@ -1,17 +1,23 @@
 use std::io::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::str;
 use std::string::String;
 use std::vec::Vec;
-use std::sync::{Mutex};
 
 use rem::tcp_stream::TcpStream;
-use rem::cache::Cache;
+use rem::cache::{Cache, WriteCondition};
+use rem::value::{self, Value, ListEnd};
+use rem::service::ERROR;
 use rem::error::*;
 
+/// The cursor used to start a scan, also returned when a scan is complete
+pub const SCAN_START: &'static str = "0";
+
 
 pub fn read_value_from_cache(key: String,
-                         cache_mtx: &Mutex<Cache>)
+                         cache: &Cache)
                          -> Result<(Vec<u8>), RemError> {
-    let cache = cache_mtx.lock().unwrap();
     let cache_opt: Option<Box<Vec<u8>>> = try!(cache.read_item(key));
     match cache_opt {
         Some(boxed_val) => {
@@ -25,66 +31,689 @@ pub fn read_value_from_cache(key: String,
 }
 
 /// Parses a TCP input stream and extracts the data
-/// Allocates a 64 byte buffer which is used to read the input info from the stream
+/// The size descriptor is read one byte at a time so that nothing past the end of the
+/// message is consumed, this allows several responses to be pipelined on the same stream
 /// The expected format is ```{size}|{content}```
 /// Ex. ```5|W$a:b```
-pub fn string_from_stream(stream: &mut TcpStream) -> Result<String, RemError> {
-    //Read in the first 54 bytes of the stram
-    //try!(stream.set_nodelay(true));
-    let mut buf_arr: [u8; 64] = [0; 64];
-    try!(stream.read(&mut buf_arr));
+pub fn bytes_from_stream(stream: &mut TcpStream) -> Result<Vec<u8>, RemError> {
     // Parse the message size
     let mut size_str = String::new();
-    let mut buf_size: usize = 0;
-    for i in 0..64 {
-        buf_size += 1;
-        if buf_arr[i] == '|' as u8 {
+    let mut byte: [u8; 1] = [0; 1];
+    loop {
+        try!(stream.read_exact(&mut byte));
+        if byte[0] == '|' as u8 {
             break;
         }
-        size_str.push(buf_arr[i as usize] as char);
+        size_str.push(byte[0] as char);
     }
 
-    // Convert the size string to a usize so it can be used to drain the buffer
-    let upper_idx: usize = try!(size_str.parse::<i32>()) as usize;
-    let mut buf_temp: Vec<u8> = buf_arr.to_vec();
-    // Create a new buffer using the parsed indicies
-    let buf: Vec<u8> = buf_temp.drain(buf_size..upper_idx + buf_size).collect();
+    // Convert the size string to a usize so it can be used to size the buffer
+    let size: usize = try!(size_str.parse::<i32>()) as usize;
+    let mut buf: Vec<u8> = vec![0; size];
+    try!(stream.read_exact(&mut buf));
+    return Ok(buf);
+}
 
-    stream.flush().unwrap();
+/// Writes a request value of the form ```{key}:{value}``` to the cache
+/// Everything after the first : is the value and is stored as is
+pub fn write_bytes_to_cache(bytes: Vec<u8>,
+                            cache: &mut Cache)
+                            -> Result<(), RemError> {
+    let split = bytes.iter().position(|&b| b == b':').unwrap_or(bytes.len());
+    let key: String = match str::from_utf8(&bytes[..split]) {
+        Ok(key) => String::from(key),
+        Err(_) => return Err(RemError::with_reason_str(REM_00005)),
+    };
+    let val: Vec<u8> = if split < bytes.len() {
+        bytes[split + 1..].to_vec()
+    } else {
+        Vec::new()
+    };
+    return cache.cache_item(key.as_str(), val);
+}
 
-    // Return the value as a string
-    let buf_str: String = String::from_utf8(buf).unwrap();
-    return Ok(buf_str);
+pub fn delete_value_from_cache(key: String, cache: &mut Cache) -> Result<(), RemError> {
+    return cache.delete_item(key);
 }
 
-pub fn write_stream_str_to_cache(stream_str: String,
-                             cache_mtx: &Mutex<Cache>)
-                             -> Result<(), RemError> {
-    let mut key: String = String::new();
-    let mut val: String = String::new();
-    let mut idx = 0;
-    let chars_iter = stream_str.chars();
-    for c in chars_iter {
-        idx += 1;
-        if c == ':' {
-            val = String::from(&stream_str[idx..]);
-            break;
+/// Reads a value and its version from the cache
+pub fn read_value_and_version_from_cache(key: String,
+                                         cache: &Cache)
+                                         -> Result<(u64, Vec<u8>), RemError> {
+    match try!(cache.read_item(key.clone())) {
+        Some(boxed_val) => Ok((cache.version(key.as_str()), *boxed_val)),
+        None => Err(RemError::with_reason(String::from(REM_00005))),
+    }
+}
+
+/// Writes a value to the cache if the condition holds and returns the new version
+pub fn write_value_to_cache_if(key: String,
+                               val: Vec<u8>,
+                               condition: WriteCondition,
+                               cache: &mut Cache)
+                               -> Result<u64, RemError> {
+    return cache.cache_item_if(key.as_str(), val, condition);
+}
+
+/// Adds delta to the integer stored under key and returns the new value
+///
+/// The caller holds the cache lock for the read and the write so concurrent increments are never lost
+/// A missing key is treated as 0, a value that is not a base 10 integer is an error
+pub fn increment_value_in_cache(key: String,
+                                delta: i64,
+                                cache: &mut Cache)
+                                -> Result<i64, RemError> {
+    let current: i64 = match try!(cache.read_item(key.clone())) {
+        Some(boxed_val) => try!(parse_integer(&boxed_val)),
+        None => 0,
+    };
+    let next: i64 = match current.checked_add(delta) {
+        Some(next) => next,
+        None => return Err(RemError::with_reason_str(REM_00013)),
+    };
+    try!(cache.cache_item(key.as_str(), next.to_string().into_bytes()));
+    return Ok(next);
+}
+
+/// Reads every key, each result is the value or an error for a missing key
+pub fn read_values_from_cache(keys: Vec<String>,
+                              cache: &Cache)
+                              -> Vec<Result<Vec<u8>, RemError>> {
+    return keys.into_iter()
+        .map(|key| {
+            match try!(cache.read_item(key)) {
+                Some(boxed_val) => Ok(*boxed_val),
+                None => Err(RemError::with_reason(String::from(REM_00005))),
+            }
+        })
+        .collect();
+}
+
+/// Writes every key value pair
+pub fn write_values_to_cache(pairs: Vec<(String, Vec<u8>)>,
+                             cache: &mut Cache)
+                             -> Vec<Result<(), RemError>> {
+    return pairs.into_iter()
+        .map(|(key, val)| cache.cache_item(key.as_str(), val))
+        .collect();
+}
+
+/// Deletes every key, each result is true if the key had a value
+pub fn delete_values_from_cache(keys: Vec<String>,
+                                cache: &mut Cache)
+                                -> Vec<Result<bool, RemError>> {
+    return keys.into_iter()
+        .map(|key| {
+            let existed = cache.contains_key(key.as_str());
+            try!(cache.delete_item(key));
+            Ok(existed)
+        })
+        .collect();
+}
+
+/// Reads the list stored under key, a missing key is treated as an empty list
+fn read_list(key: &str, cache: &Cache) -> Result<VecDeque<Vec<u8>>, RemError> {
+    match try!(cache.read_value(key)) {
+        Some(Value::LIST(elements)) => Ok(elements),
+        Some(other) => Err(value::wrong_type(key, &other)),
+        None => Ok(VecDeque::new()),
+    }
+}
+
+/// Pushes the elements onto the list stored under key one at a time and returns the new length
+///
+/// Pushing a, b then c to the left end leaves the list as c, b, a
+pub fn push_to_list(key: String,
+                    end: ListEnd,
+                    elements: Vec<Vec<u8>>,
+                    cache: &mut Cache)
+                    -> Result<usize, RemError> {
+    let mut list = try!(read_list(key.as_str(), cache));
+    for element in elements {
+        match end {
+            ListEnd::LEFT => list.push_front(element),
+            ListEnd::RIGHT => list.push_back(element),
         }
-        key.push(c);
     }
-    let bytes = val.into_bytes();
-    let mut cache = cache_mtx.lock().unwrap();
-    return cache.cache_item(key.as_str(), bytes);
+    let len = list.len();
+    try!(cache.cache_value(key.as_str(), Value::LIST(list)));
+    return Ok(len);
 }
 
-pub fn delete_value_from_cache(key: String, cache_mtx: &Mutex<Cache>) -> Result<(), RemError> {
-    let mut cache = cache_mtx.lock().unwrap();
-    return cache.delete_item(key);
+/// Removes and returns an element from one end of the list stored under key
+///
+/// The key is deleted once its last element is removed
+pub fn pop_from_list(key: String, end: ListEnd, cache: &mut Cache) -> Result<Vec<u8>, RemError> {
+    let mut list = try!(read_list(key.as_str(), cache));
+    let element = match end {
+        ListEnd::LEFT => list.pop_front(),
+        ListEnd::RIGHT => list.pop_back(),
+    };
+    match element {
+        Some(element) => {
+            if list.is_empty() {
+                try!(cache.delete_item(key));
+            } else {
+                try!(cache.cache_value(key.as_str(), Value::LIST(list)));
+            }
+            return Ok(element);
+        }
+        None => return Err(RemError::with_reason(format!("{}: {}", REM_00023, key))),
+    }
+}
+
+/// Returns the elements of the list stored under key from start to stop inclusive
+///
+/// Negative indexes count from the end of the list, -1 is the last element
+pub fn range_from_list(key: String,
+                       start: i64,
+                       stop: i64,
+                       cache: &Cache)
+                       -> Result<Vec<Vec<u8>>, RemError> {
+    let list = try!(read_list(key.as_str(), cache));
+    let (skip, take) = index_range(start, stop, list.len());
+    return Ok(list.into_iter().skip(skip).take(take).collect());
+}
+
+/// Returns how many elements to skip and then take to select start to stop inclusive from a
+/// list of len elements, negative indexes count from the end
+///
+/// A start before the beginning of the list is the beginning and a stop before it selects nothing
+fn index_range(start: i64, stop: i64, len: usize) -> (usize, usize) {
+    let start = value::list_index(start, len).unwrap_or(0);
+    return match value::list_index(stop, len) {
+        Some(stop) => (start, stop.saturating_add(1).saturating_sub(start)),
+        None => (start, 0),
+    };
+}
+
+/// Returns the number of elements in the list stored under key
+pub fn list_length(key: String, cache: &Cache) -> Result<usize, RemError> {
+    return Ok(try!(read_list(key.as_str(), cache)).len());
+}
+
+/// Reads the hash stored under key, a missing key is treated as an empty hash
+fn read_hash(key: &str, cache: &Cache) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, RemError> {
+    match try!(cache.read_value(key)) {
+        Some(Value::HASH(fields)) => Ok(fields),
+        Some(other) => Err(value::wrong_type(key, &other)),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Sets each field of the hash stored under key and returns the number of fields that are new
+pub fn set_hash_fields(key: String,
+                       pairs: Vec<(Vec<u8>, Vec<u8>)>,
+                       cache: &mut Cache)
+                       -> Result<usize, RemError> {
+    let mut fields = try!(read_hash(key.as_str(), cache));
+    let mut added = 0;
+    for (field, val) in pairs {
+        if fields.insert(field, val).is_none() {
+            added += 1;
+        }
+    }
+    try!(cache.cache_value(key.as_str(), Value::HASH(fields)));
+    return Ok(added);
+}
+
+/// Reads a single field of the hash stored under key
+pub fn read_hash_field(key: String, field: Vec<u8>, cache: &Cache) -> Result<Vec<u8>, RemError> {
+    let mut fields = try!(read_hash(key.as_str(), cache));
+    match fields.remove(&field) {
+        Some(val) => Ok(val),
+        None => {
+            Err(RemError::with_reason(format!("{}: {} has no field {}",
+                                              REM_00025,
+                                              key,
+                                              String::from_utf8_lossy(&field))))
+        }
+    }
+}
+
+/// Deletes the fields from the hash stored under key and returns the number that existed
+///
+/// The key is deleted once its last field is removed
+pub fn delete_hash_fields(key: String,
+                          to_delete: Vec<Vec<u8>>,
+                          cache: &mut Cache)
+                          -> Result<usize, RemError> {
+    let mut fields = try!(read_hash(key.as_str(), cache));
+    let removed = to_delete.iter().filter(|field| fields.remove(*field).is_some()).count();
+    if removed == 0 {
+        return Ok(0);
+    }
+    if fields.is_empty() {
+        try!(cache.delete_item(key));
+    } else {
+        try!(cache.cache_value(key.as_str(), Value::HASH(fields)));
+    }
+    return Ok(removed);
+}
+
+/// Returns every field and value of the hash stored under key, sorted by field
+pub fn read_hash_fields(key: String, cache: &Cache) -> Result<Vec<(Vec<u8>, Vec<u8>)>, RemError> {
+    return Ok(try!(read_hash(key.as_str(), cache)).into_iter().collect());
+}
+
+/// Adds delta to the integer stored in a field of the hash and returns the new value
+///
+/// A missing field is treated as 0 the same way a missing key is for increment_value_in_cache
+pub fn increment_hash_field(key: String,
+                            field: Vec<u8>,
+                            delta: i64,
+                            cache: &mut Cache)
+                            -> Result<i64, RemError> {
+    let mut fields = try!(read_hash(key.as_str(), cache));
+    let current: i64 = match fields.get(&field) {
+        Some(val) => try!(parse_integer(val)),
+        None => 0,
+    };
+    let next: i64 = match current.checked_add(delta) {
+        Some(next) => next,
+        None => return Err(RemError::with_reason_str(REM_00013)),
+    };
+    fields.insert(field, next.to_string().into_bytes());
+    try!(cache.cache_value(key.as_str(), Value::HASH(fields)));
+    return Ok(next);
+}
+
+/// Reads the set stored under key, a missing key is treated as an empty set
+fn read_set(key: &str, cache: &Cache) -> Result<BTreeSet<Vec<u8>>, RemError> {
+    match try!(cache.read_value(key)) {
+        Some(Value::SET(members)) => Ok(members),
+        Some(other) => Err(value::wrong_type(key, &other)),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+/// Adds the members to the set stored under key and returns the number that were new
+pub fn add_set_members(key: String, members: Vec<Vec<u8>>, cache: &mut Cache) -> Result<usize, RemError> {
+    let mut set = try!(read_set(key.as_str(), cache));
+    let added = members.into_iter().filter(|member| set.insert(member.clone())).count();
+    try!(cache.cache_value(key.as_str(), Value::SET(set)));
+    return Ok(added);
+}
+
+/// Removes the members from the set stored under key and returns the number that existed
+///
+/// The key is deleted once its last member is removed
+pub fn remove_set_members(key: String, members: Vec<Vec<u8>>, cache: &mut Cache) -> Result<usize, RemError> {
+    let mut set = try!(read_set(key.as_str(), cache));
+    let removed = members.iter().filter(|member| set.remove(*member)).count();
+    if removed == 0 {
+        return Ok(0);
+    }
+    if set.is_empty() {
+        try!(cache.delete_item(key));
+    } else {
+        try!(cache.cache_value(key.as_str(), Value::SET(set)));
+    }
+    return Ok(removed);
+}
+
+/// Checks if member is in the set stored under key
+pub fn is_set_member(key: String, member: Vec<u8>, cache: &Cache) -> Result<bool, RemError> {
+    return Ok(try!(read_set(key.as_str(), cache)).contains(&member));
+}
+
+/// Returns every member of the set stored under key, sorted
+pub fn set_members(key: String, cache: &Cache) -> Result<Vec<Vec<u8>>, RemError> {
+    return Ok(try!(read_set(key.as_str(), cache)).into_iter().collect());
+}
+
+/// Returns the members found in every one of the sets, sorted
+///
+/// A missing key is an empty set so the intersection with it is empty
+pub fn intersect_sets(keys: Vec<String>, cache: &Cache) -> Result<Vec<Vec<u8>>, RemError> {
+    let mut iter = keys.into_iter();
+    let mut common = match iter.next() {
+        Some(key) => try!(read_set(key.as_str(), cache)),
+        None => return Ok(Vec::new()),
+    };
+    for key in iter {
+        let set = try!(read_set(key.as_str(), cache));
+        common = common.intersection(&set).cloned().collect();
+    }
+    return Ok(common.into_iter().collect());
+}
+
+/// Reads the sorted set stored under key, a missing key is treated as an empty sorted set
+fn read_sorted_set(key: &str, cache: &Cache) -> Result<BTreeMap<Vec<u8>, f64>, RemError> {
+    match try!(cache.read_value(key)) {
+        Some(Value::ZSET(members)) => Ok(members),
+        Some(other) => Err(value::wrong_type(key, &other)),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Orders the members of a sorted set by score, members with the same score are ordered
+/// by their bytes
+fn by_score(members: BTreeMap<Vec<u8>, f64>) -> Vec<(Vec<u8>, f64)> {
+    let mut ordered: Vec<(Vec<u8>, f64)> = members.into_iter().collect();
+    ordered.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+    return ordered;
+}
+
+/// Adds the members with their scores to the sorted set stored under key, updating the score
+/// of members that already exist. Returns the number of members that were new
+pub fn add_sorted_set_members(key: String,
+                              scored: Vec<(f64, Vec<u8>)>,
+                              cache: &mut Cache)
+                              -> Result<usize, RemError> {
+    let mut members = try!(read_sorted_set(key.as_str(), cache));
+    let mut added = 0;
+    for (score, member) in scored {
+        if members.insert(member, score).is_none() {
+            added += 1;
+        }
+    }
+    try!(cache.cache_value(key.as_str(), Value::ZSET(members)));
+    return Ok(added);
+}
+
+/// Returns the members of the sorted set from rank start to stop inclusive with their scores
+///
+/// Rank 0 is the member with the lowest score, negative ranks count from the highest score
+pub fn range_sorted_set_by_rank(key: String,
+                                start: i64,
+                                stop: i64,
+                                cache: &Cache)
+                                -> Result<Vec<(Vec<u8>, f64)>, RemError> {
+    let ordered = by_score(try!(read_sorted_set(key.as_str(), cache)));
+    let (skip, take) = index_range(start, stop, ordered.len());
+    return Ok(ordered.into_iter().skip(skip).take(take).collect());
+}
+
+/// Returns the members of the sorted set with a score from min to max inclusive
+pub fn range_sorted_set_by_score(key: String,
+                                 min: f64,
+                                 max: f64,
+                                 cache: &Cache)
+                                 -> Result<Vec<(Vec<u8>, f64)>, RemError> {
+    return Ok(scored_between(by_score(try!(read_sorted_set(key.as_str(), cache))), min, max));
+}
+
+/// Keeps the members with a score from min to max inclusive
+fn scored_between(ordered: Vec<(Vec<u8>, f64)>, min: f64, max: f64) -> Vec<(Vec<u8>, f64)> {
+    return ordered.into_iter()
+        .filter(|&(_, score)| min <= score && score <= max)
+        .collect();
+}
+
+/// Adds delta to the score of a member of the sorted set and returns the new score
+///
+/// A member that doesn't exist is added with a score of delta
+pub fn increment_sorted_set_member(key: String,
+                                   member: Vec<u8>,
+                                   delta: f64,
+                                   cache: &mut Cache)
+                                   -> Result<f64, RemError> {
+    let mut members = try!(read_sorted_set(key.as_str(), cache));
+    let score = members.get(&member).cloned().unwrap_or(0.0) + delta;
+    // Adding infinities of opposite sign
+    if score.is_nan() {
+        return Err(RemError::with_reason_str(REM_00026));
+    }
+    members.insert(member, score);
+    try!(cache.cache_value(key.as_str(), Value::ZSET(members)));
+    return Ok(score);
+}
+
+/// Encodes a list of items so it can be carried in a single request or response
+/// Each item is written as ```{size}|{content}```, the same format used for messages
+/// ex: [abc, de] would be encoded as 3|abc2|de
+pub fn encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for item in items {
+        bytes.extend(format!("{}|", item.len()).into_bytes());
+        bytes.extend_from_slice(item);
+    }
+    return bytes;
+}
+
+/// Decodes a list of items encoded by encode_list
+pub fn decode_list(bytes: &[u8]) -> Result<Vec<Vec<u8>>, RemError> {
+    let mut items: Vec<Vec<u8>> = Vec::new();
+    let mut rest: &[u8] = bytes;
+    while rest.len() > 0 {
+        match decode_item(rest) {
+            Some((item, next)) => {
+                items.push(item);
+                rest = next;
+            }
+            None => return Err(RemError::with_reason_str(REM_00015)),
+        }
+    }
+    return Ok(items);
+}
+
+/// Decodes the first item of a list, returning the item and the rest of the list
+/// None is returned if the bytes don't start with a complete item
+pub fn decode_item(bytes: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let idx = match bytes.iter().position(|&b| b == b'|') {
+        Some(idx) => idx,
+        None => return None,
+    };
+    let size: usize = match str::from_utf8(&bytes[..idx]).ok().and_then(|s| s.parse::<usize>().ok()) {
+        Some(size) => size,
+        None => return None,
+    };
+    if bytes.len() < idx + 1 + size {
+        return None;
+    }
+    return Some((bytes[idx + 1..idx + 1 + size].to_vec(), &bytes[idx + 1 + size..]));
+}
+
+/// Decodes a list of keys, every key must be valid UTF-8
+pub fn decode_keys(bytes: &[u8]) -> Result<Vec<String>, RemError> {
+    let mut keys: Vec<String> = Vec::new();
+    for item in try!(decode_list(bytes)) {
+        match String::from_utf8(item) {
+            Ok(key) => keys.push(key),
+            Err(_) => return Err(RemError::with_reason_str(REM_00005)),
+        }
+    }
+    return Ok(keys);
+}
+
+/// Splits a request argument at the first :, the second part is empty if there is no :
+/// ex: 3:abc would be split into 3 and abc
+pub fn split_arg(arg: &[u8]) -> (&[u8], &[u8]) {
+    match arg.iter().position(|&b| b == b':') {
+        Some(idx) => (&arg[..idx], &arg[idx + 1..]),
+        None => (arg, &[]),
+    }
+}
+
+/// Parses a base 10 integer from a stored value or request argument
+pub fn parse_integer(bytes: &[u8]) -> Result<i64, RemError> {
+    match str::from_utf8(bytes).ok().and_then(|val| val.parse::<i64>().ok()) {
+        Some(val) => Ok(val),
+        None => Err(RemError::with_reason_str(REM_00012)),
+    }
+}
+
+pub fn keys_from_cache(prefix: String, cache: &Cache) -> Result<Vec<String>, RemError> {
+    return cache.keys_with_prefix(prefix.as_str());
+}
+
+/// Scans a page of keys matching the glob pattern
+///
+/// Cursors are the hex encoding of the last key returned so they can be passed around as
+/// plain text, 0 starts a scan and is returned once every key has been scanned
+pub fn scan_cache(cursor: String,
+                  pattern: String,
+                  count: usize,
+                  cache: &Cache)
+                  -> Result<(String, Vec<String>), RemError> {
+    let after: String = if cursor == SCAN_START {
+        String::new()
+    } else {
+        match from_hex(cursor.as_str()).and_then(|key| String::from_utf8(key).ok()) {
+            Some(key) => key,
+            None => return Err(RemError::with_reason_str(REM_00016)),
+        }
+    };
+    let (next, keys) = try!(cache.scan(after.as_str(), pattern.as_str(), count));
+    let next_cursor = if next.is_empty() {
+        String::from(SCAN_START)
+    } else {
+        to_hex(next.as_bytes())
+    };
+    return Ok((next_cursor, keys));
+}
+
+pub fn keys_matching_from_cache(pattern: String, cache: &Cache) -> Result<Vec<String>, RemError> {
+    return cache.keys_matching(pattern.as_str());
+}
+
+/// Deletes every key starting with prefix, returning the number removed
+pub fn delete_prefix_from_cache(prefix: String, cache: &mut Cache) -> Result<usize, RemError> {
+    return cache.delete_with_prefix(prefix.as_str());
+}
+
+/// Deletes every key matching the glob pattern, returning the number removed
+pub fn delete_matching_from_cache(pattern: String, cache: &mut Cache) -> Result<usize, RemError> {
+    return cache.delete_matching(pattern.as_str());
+}
+
+pub fn count_cache(cache: &Cache) -> Result<usize, RemError> {
+    return cache.count();
+}
+
+/// Describes the memory used by each type of value held in memory
+/// Each item is ```{type}:{keys}:{bytes}``` ex: list:2:140
+pub fn memory_usage_from_cache(cache: &Cache) -> Vec<String> {
+    return cache.memory_usage()
+        .into_iter()
+        .map(|(type_name, usage)| format!("{}:{}:{}", type_name, usage.keys, usage.bytes))
+        .collect();
 }
 
-pub fn write_str_to_stream_with_size(stream: &mut TcpStream, value: String) -> Result<(), RemError> {
-    let sized_val = String::from(format!("{}|{}", value.len(), value));
-    try!(stream.write(String::from(sized_val).as_bytes()));
+/// Encodes bytes as lowercase hex
+pub fn to_hex(val: &[u8]) -> String {
+    let mut hex = String::with_capacity(val.len() * 2);
+    for b in val {
+        hex.push_str(&format!("{:02x}", b));
+    }
+    return hex;
+}
+
+/// Decodes hex produced by to_hex, None if the string is not valid hex
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity(hex.len() / 2);
+    for idx in 0..hex.len() / 2 {
+        match u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16) {
+            Ok(b) => bytes.push(b),
+            Err(_) => return None,
+        }
+    }
+    return Some(bytes);
+}
+
+pub fn write_bytes_to_stream_with_size(stream: &mut TcpStream, value: &[u8]) -> Result<(), RemError> {
+    let mut sized_val: Vec<u8> = format!("{}|", value.len()).into_bytes();
+    sized_val.extend_from_slice(value);
+    try!(stream.write_all(&sized_val));
     try!(stream.flush());
     return Ok(());
-}
\ No newline at end of file
+}
+
+/// Sends a request to another server and returns the response, an error response is returned
+/// as a RemError. Used for the requests servers make to each other
+pub fn send_request(stream: &mut TcpStream, command: &str, value: &[u8]) -> Result<Vec<u8>, RemError> {
+    let mut req: Vec<u8> = format!("{}$", command).into_bytes();
+    req.extend_from_slice(value);
+    try!(write_bytes_to_stream_with_size(stream, &req));
+    let res = try!(bytes_from_stream(stream));
+    if res.starts_with(ERROR.as_bytes()) {
+        return Err(RemError::with_reason(String::from_utf8_lossy(&res[ERROR.len()..])
+            .trim_left_matches(':')
+            .to_string()));
+    }
+    return Ok(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::f64;
+
+    use super::{by_score, index_range, scored_between};
+
+    fn range(items: &[&'static str], start: i64, stop: i64) -> Vec<&'static str> {
+        let (skip, take) = index_range(start, stop, items.len());
+        return items.iter().cloned().skip(skip).take(take).collect();
+    }
+
+    #[test]
+    fn list_ranges_include_both_ends() {
+        let list = ["a", "b", "c", "d"];
+        assert_eq!(range(&list, 0, -1), vec!["a", "b", "c", "d"]);
+        assert_eq!(range(&list, 1, 2), vec!["b", "c"]);
+        assert_eq!(range(&list, -2, -1), vec!["c", "d"]);
+        assert_eq!(range(&list, 2, 1), Vec::<&str>::new());
+        assert_eq!(range(&list, 0, 100), vec!["a", "b", "c", "d"]);
+        assert_eq!(range(&list, -100, 0), vec!["a"]);
+        assert_eq!(range(&list, 0, -5), Vec::<&str>::new());
+        assert_eq!(range(&[], 0, -1), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn list_ranges_handle_the_extreme_indexes() {
+        let list = ["a", "b"];
+        assert_eq!(range(&list, i64::min_value(), i64::max_value()), vec!["a", "b"]);
+        assert_eq!(range(&list, 0, i64::min_value()), Vec::<&str>::new());
+        assert_eq!(range(&list, i64::max_value(), i64::max_value()), Vec::<&str>::new());
+        assert_eq!(range(&list, i64::min_value(), i64::min_value()), Vec::<&str>::new());
+    }
+
+    fn sorted_set() -> Vec<(Vec<u8>, f64)> {
+        let mut members = BTreeMap::new();
+        members.insert(b"c".to_vec(), 2.0);
+        members.insert(b"a".to_vec(), 1.5);
+        members.insert(b"d".to_vec(), -1.0);
+        members.insert(b"b".to_vec(), 2.0);
+        members.insert(b"e".to_vec(), f64::INFINITY);
+        return by_score(members);
+    }
+
+    fn names(members: Vec<(Vec<u8>, f64)>) -> Vec<String> {
+        return members.into_iter().map(|(member, _)| String::from_utf8(member).unwrap()).collect();
+    }
+
+    fn by_rank(start: i64, stop: i64) -> Vec<String> {
+        let ordered = sorted_set();
+        let (skip, take) = index_range(start, stop, ordered.len());
+        return names(ordered.into_iter().skip(skip).take(take).collect());
+    }
+
+    #[test]
+    fn sorted_sets_are_ordered_by_score_then_member() {
+        assert_eq!(names(sorted_set()), vec!["d", "a", "b", "c", "e"]);
+    }
+
+    #[test]
+    fn rank_ranges_count_negative_ranks_from_the_highest_score() {
+        assert_eq!(by_rank(0, 0), vec!["d"]);
+        assert_eq!(by_rank(1, 2), vec!["a", "b"]);
+        assert_eq!(by_rank(-2, -1), vec!["c", "e"]);
+        assert_eq!(by_rank(0, -1).len(), 5);
+        assert_eq!(by_rank(3, 1), Vec::<String>::new());
+        assert_eq!(by_rank(0, i64::min_value()), Vec::<String>::new());
+        assert_eq!(by_rank(i64::min_value(), i64::max_value()).len(), 5);
+    }
+
+    #[test]
+    fn score_ranges_include_both_ends() {
+        assert_eq!(names(scored_between(sorted_set(), 1.5, 2.0)), vec!["a", "b", "c"]);
+        assert_eq!(names(scored_between(sorted_set(), 2.0, 2.0)), vec!["b", "c"]);
+        assert_eq!(names(scored_between(sorted_set(), f64::NEG_INFINITY, 0.0)), vec!["d"]);
+        assert_eq!(names(scored_between(sorted_set(), 3.0, f64::INFINITY)), vec!["e"]);
+        assert_eq!(names(scored_between(sorted_set(), 2.0, 1.0)), Vec::<String>::new());
+    }
+}
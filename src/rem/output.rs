@@ -0,0 +1,275 @@
+use std::io::prelude::*;
+use std::io;
+use std::string::String;
+use std::vec::Vec;
+use std::error::Error;
+
+use serde_json;
+use base64;
+
+use rem::op;
+use rem::service::{OK, ERROR};
+use rem::error::*;
+
+/// The formats the client can write server responses in
+#[derive(Clone, Copy, Debug)]
+pub enum OutputFormat {
+    /// Values are written exactly as they were received
+    RAW,
+    /// Every response is written as a single line JSON object
+    JSON,
+    /// Values are written as lowercase hex
+    HEX,
+    /// Values are written as standard base64
+    BASE64,
+}
+
+impl OutputFormat {
+    /// Returns the output format with the provided name. One of [raw, json, hex, base64]
+    pub fn from_name(name: &str) -> Result<OutputFormat, RemError> {
+        match name {
+            "raw" => Ok(OutputFormat::RAW),
+            "json" => Ok(OutputFormat::JSON),
+            "hex" => Ok(OutputFormat::HEX),
+            "base64" => Ok(OutputFormat::BASE64),
+            _ => {
+                Err(RemError::with_reason(format!("{}: {} is not one of [raw, json, hex, base64]",
+                                                  REM_00011,
+                                                  name)))
+            }
+        }
+    }
+}
+
+/// A response from the server classified by its status
+pub enum Response {
+    /// The operation succeeded without returning a value
+    OK,
+    /// The operation succeeded and returned a value
+    VALUE(Vec<u8>),
+    /// The operation failed, holds the error code if the server provided one and the message
+    ERROR(Option<String>, String),
+    /// A multi key operation succeeded, holds the result for each key in request order
+    LIST(Vec<Response>),
+}
+
+impl Response {
+    /// Classifies the raw bytes of a response
+    ///
+    /// Errors are of the form ```ERROR:{code}: {message}```, anything other than OK is a value
+    pub fn from_bytes(res: Vec<u8>) -> Response {
+        if res.starts_with(ERROR.as_bytes()) {
+            let desc = String::from_utf8_lossy(&res[ERROR.len()..]).into_owned();
+            let desc = desc.trim_left_matches(':');
+            // Server errors start with a REM code ex: REM_00005: Invalid key
+            if desc.starts_with("REM_") {
+                if let Some(idx) = desc.find(':') {
+                    return Response::ERROR(Some(String::from(&desc[..idx])),
+                                           String::from(desc[idx + 1..].trim()));
+                }
+            }
+            return Response::ERROR(None, String::from(desc));
+        }
+        if res.as_slice() == OK.as_bytes() {
+            return Response::OK;
+        }
+        return Response::VALUE(res);
+    }
+
+    /// Classifies the raw bytes of a response to a multi key operation
+    ///
+    /// Each item in the list is classified the same way as a single response
+    pub fn from_list_bytes(res: Vec<u8>) -> Response {
+        if res.starts_with(ERROR.as_bytes()) {
+            return Response::from_bytes(res);
+        }
+        match op::decode_list(&res) {
+            Ok(items) => Response::LIST(items.into_iter().map(Response::from_bytes).collect()),
+            Err(why) => Response::ERROR(None, String::from(why.description())),
+        }
+    }
+}
+
+/// The structure written for each response when using the JSON output format
+#[derive(Serialize)]
+struct JsonResponse<'a> {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    /// Set to base64 when the value is not valid UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    /// The result for each key of a multi key operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<Vec<JsonResponse<'a>>>,
+}
+
+/// Writes a response followed by a newline using the provided format
+///
+/// Only values are encoded, OK and errors are written as is for every format other than JSON
+pub fn write_response<W: Write>(format: OutputFormat, res: &Response, out: &mut W) -> io::Result<()> {
+    if let OutputFormat::JSON = format {
+        let json = try!(serde_json::to_string(&to_json(res))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+        try!(out.write_all(json.as_bytes()));
+        return out.write_all(b"\n");
+    }
+    match *res {
+        Response::OK => try!(out.write_all(OK.as_bytes())),
+        Response::ERROR(ref code, ref message) => {
+            match *code {
+                Some(ref code) => try!(write!(out, "{}:{}: {}", ERROR, code, message)),
+                None => try!(write!(out, "{}:{}", ERROR, message)),
+            }
+        }
+        Response::VALUE(ref val) => {
+            match format {
+                OutputFormat::HEX => try!(out.write_all(op::to_hex(val).as_bytes())),
+                OutputFormat::BASE64 => try!(out.write_all(base64::encode(val).as_bytes())),
+                _ => try!(out.write_all(val)),
+            }
+        }
+        // Each result is written on its own line
+        Response::LIST(ref results) => {
+            for result in results {
+                try!(write_response(format, result, out));
+            }
+            return Ok(());
+        }
+    }
+    return out.write_all(b"\n");
+}
+
+/// Writes a response to stdout using the provided format
+pub fn print_response(format: OutputFormat, res: &Response) -> Result<(), RemError> {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    try!(write_response(format, res, &mut handle));
+    try!(handle.flush());
+    return Ok(());
+}
+
+fn to_json<'a>(res: &'a Response) -> JsonResponse<'a> {
+    let mut json = JsonResponse {
+        status: "ok",
+        code: None,
+        message: None,
+        value: None,
+        encoding: None,
+        results: None,
+    };
+    match *res {
+        Response::OK => (),
+        Response::ERROR(ref code, ref message) => {
+            json.status = "error";
+            json.code = code.as_ref().map(|c| c.as_str());
+            json.message = Some(message.as_str());
+        }
+        Response::VALUE(ref val) => {
+            match String::from_utf8(val.clone()) {
+                Ok(text) => json.value = Some(text),
+                Err(_) => {
+                    json.value = Some(base64::encode(val));
+                    json.encoding = Some("base64");
+                }
+            }
+        }
+        Response::LIST(ref results) => {
+            json.results = Some(results.iter().map(to_json).collect());
+        }
+    }
+    return json;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_response, OutputFormat, Response};
+
+    fn written(format: OutputFormat, res: &Response) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        write_response(format, res, &mut out).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    fn error(code: Option<&str>, message: &str) -> Response {
+        return Response::ERROR(code.map(String::from), String::from(message));
+    }
+
+    #[test]
+    fn responses_are_classified_by_status() {
+        assert!(match Response::from_bytes(b"OK".to_vec()) { Response::OK => true, _ => false });
+        assert!(match Response::from_bytes(b"abc".to_vec()) { Response::VALUE(ref val) => val == b"abc", _ => false });
+        match Response::from_bytes(b"ERROR:REM_00005: Invalid key".to_vec()) {
+            Response::ERROR(code, message) => {
+                assert_eq!(code, Some(String::from("REM_00005")));
+                assert_eq!(message, "Invalid key");
+            }
+            _ => panic!("not an error"),
+        }
+        match Response::from_bytes(b"ERROR:Something failed".to_vec()) {
+            Response::ERROR(code, message) => {
+                assert_eq!(code, None);
+                assert_eq!(message, "Something failed");
+            }
+            _ => panic!("not an error"),
+        }
+    }
+
+    #[test]
+    fn json_describes_each_status() {
+        assert_eq!(written(OutputFormat::JSON, &Response::OK), "{\"status\":\"ok\"}\n");
+        assert_eq!(written(OutputFormat::JSON, &Response::VALUE(b"abc".to_vec())),
+                   "{\"status\":\"ok\",\"value\":\"abc\"}\n");
+        assert_eq!(written(OutputFormat::JSON, &error(Some("REM_00005"), "Invalid key")),
+                   "{\"status\":\"error\",\"code\":\"REM_00005\",\"message\":\"Invalid key\"}\n");
+        assert_eq!(written(OutputFormat::JSON, &error(None, "Something failed")),
+                   "{\"status\":\"error\",\"message\":\"Something failed\"}\n");
+    }
+
+    #[test]
+    fn json_nests_the_results_of_a_list() {
+        let list = Response::LIST(vec![Response::VALUE(b"1".to_vec()),
+                                       error(Some("REM_00005"), "Invalid key"),
+                                       Response::LIST(vec![Response::OK])]);
+        assert_eq!(written(OutputFormat::JSON, &list),
+                   "{\"status\":\"ok\",\"results\":[{\"status\":\"ok\",\"value\":\"1\"},\
+                    {\"status\":\"error\",\"code\":\"REM_00005\",\"message\":\"Invalid key\"},\
+                    {\"status\":\"ok\",\"results\":[{\"status\":\"ok\"}]}]}\n");
+    }
+
+    #[test]
+    fn json_falls_back_to_base64_for_values_that_are_not_utf8() {
+        assert_eq!(written(OutputFormat::JSON, &Response::VALUE(vec![0xff, 0x00, 0x41])),
+                   "{\"status\":\"ok\",\"value\":\"/wBB\",\"encoding\":\"base64\"}\n");
+    }
+
+    #[test]
+    fn only_values_are_encoded() {
+        let val = Response::VALUE(vec![0xff, 0x00, 0x41]);
+        assert_eq!(written(OutputFormat::HEX, &val), "ff0041\n");
+        assert_eq!(written(OutputFormat::BASE64, &val), "/wBB\n");
+        assert_eq!(written(OutputFormat::HEX, &Response::OK), "OK\n");
+        assert_eq!(written(OutputFormat::BASE64, &error(Some("REM_00005"), "Invalid key")),
+                   "ERROR:REM_00005: Invalid key\n");
+        assert_eq!(written(OutputFormat::RAW, &error(None, "Something failed")), "ERROR:Something failed\n");
+        assert_eq!(written(OutputFormat::RAW, &Response::VALUE(b"abc".to_vec())), "abc\n");
+    }
+
+    #[test]
+    fn lists_are_written_one_result_per_line() {
+        let list = Response::LIST(vec![Response::VALUE(b"ab".to_vec()), Response::OK]);
+        assert_eq!(written(OutputFormat::HEX, &list), "6162\nOK\n");
+        assert_eq!(written(OutputFormat::RAW, &Response::LIST(Vec::new())), "");
+    }
+
+    #[test]
+    fn format_names_are_parsed() {
+        assert!(OutputFormat::from_name("json").is_ok());
+        assert!(OutputFormat::from_name("hex").is_ok());
+        assert!(OutputFormat::from_name("xml").is_err());
+    }
+}
@@ -1,22 +1,38 @@
-use tokio_proto::pipeline::ServerProto;
+use tokio_proto::streaming::pipeline::ServerProto;
 use tokio_io::codec::{Framed};
 use tokio_io::{AsyncRead, AsyncWrite};
 use rem::codec::CacheCodec;
 use std::io;
 
-pub struct CacheProto {}
+/// A streaming pipeline protocol
+///
+/// Every request gets exactly one response in the order the requests were sent. A response
+/// may be followed by a body of messages pushed by the server, this is used for the messages
+/// of a subscription
+pub struct CacheProto {
+    /// The largest request a connection may send
+    pub max_request_bytes: usize
+}
 
 impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CacheProto {
     /// For this protocol style, `Request` matches the codec `In` type
-    type Request = String;
+    type Request = Vec<u8>;
+
+    /// Requests never have a body
+    type RequestBody = Vec<u8>;
 
     /// For this protocol style, `Response` matches the coded `Out` type
-    type Response = String;
+    type Response = Vec<u8>;
+
+    /// Each message pushed to a subscriber is a body chunk
+    type ResponseBody = Vec<u8>;
+
+    type Error = io::Error;
 
     /// A bit of boilerplate to hook in the codec:
     type Transport = Framed<T, CacheCodec>;
     type BindTransport = Result<Self::Transport, io::Error>;
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(CacheCodec{}))
+        Ok(io.framed(CacheCodec{ max_request_bytes: self.max_request_bytes }))
     }
-}
\ No newline at end of file
+}
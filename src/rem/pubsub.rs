@@ -0,0 +1,275 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::string::String;
+use std::sync::{Arc, Mutex, Weak};
+use std::vec::Vec;
+
+use futures::{Poll, Stream};
+use futures::sync::mpsc;
+
+use rem::glob;
+use rem::op;
+
+/// The number of messages buffered for a subscriber that isn't reading them fast enough
+/// Messages published while the buffer is full are dropped for that subscriber
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// The stream of messages pushed to a subscribed connection, sent as the body of the
+/// response to its first SUBSCRIBE or PSUBSCRIBE
+pub type PushStream = Box<Stream<Item = Vec<u8>, Error = io::Error> + Send>;
+
+/// The push stream of a subscriber, removing the subscriber when it is dropped
+struct SubscriberStream {
+    id: u64,
+    stream: PushStream,
+    pubsub: Weak<Mutex<PubSub>>
+}
+
+impl Stream for SubscriberStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        return self.stream.poll();
+    }
+}
+
+impl Drop for SubscriberStream {
+    fn drop(&mut self) {
+        if let Some(pubsub) = self.pubsub.upgrade() {
+            pubsub.lock().unwrap().remove(self.id);
+        }
+    }
+}
+
+/// A connection that is subscribed to at least one channel or pattern
+struct Subscriber {
+    sender: mpsc::Sender<Result<Vec<u8>, io::Error>>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>
+}
+
+impl Subscriber {
+    fn count(&self) -> usize {
+        return self.channels.len() + self.patterns.len();
+    }
+
+    /// Queues a message for the subscriber
+    fn push(&mut self, items: &[Vec<u8>]) -> Delivery {
+        match self.sender.try_send(Ok(op::encode_list(items))) {
+            Ok(()) => Delivery::DELIVERED,
+            Err(ref why) if why.is_full() => {
+                warn!("Dropped a message for a subscriber that is not keeping up");
+                Delivery::DROPPED
+            }
+            Err(_) => Delivery::CLOSED,
+        }
+    }
+}
+
+/// What happened to a message pushed to a subscriber
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Delivery {
+    DELIVERED,
+    /// The subscriber's buffer was full
+    DROPPED,
+    /// The connection has gone away
+    CLOSED,
+}
+
+/// The channels and patterns every connection is subscribed to
+///
+/// Every message pushed to a subscriber is an encoded list starting with its kind:
+/// * ```[subscribe, channel, count]``` and ```[psubscribe, pattern, count]``` confirm a subscription
+/// * ```[unsubscribe, channel, count]``` and ```[punsubscribe, pattern, count]``` confirm an
+///   unsubscription, the stream ends once count reaches 0
+/// * ```[message, channel, payload]``` is a message published to a subscribed channel
+/// * ```[pmessage, pattern, channel, payload]``` is a message published to a channel matching
+///   a subscribed pattern
+#[derive(Default)]
+pub struct PubSub {
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>
+}
+
+impl PubSub {
+    /// Registers a new subscriber and returns its id and the stream its messages are pushed to
+    ///
+    /// The subscriber is removed when the stream is dropped, so a connection that closes without
+    /// unsubscribing isn't published to again. The stream must not be dropped while the
+    /// PubSub lock is held
+    pub fn register(&mut self, pubsub: &Arc<Mutex<PubSub>>) -> (u64, PushStream) {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.insert(id, Subscriber {
+            sender: sender,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new()
+        });
+        let stream = receiver.then(|res| {
+            match res {
+                Ok(msg) => msg,
+                Err(()) => Err(io::Error::new(io::ErrorKind::Other, "Subscription closed")),
+            }
+        });
+        return (id, Box::new(SubscriberStream {
+            id: id,
+            stream: Box::new(stream),
+            pubsub: Arc::downgrade(pubsub)
+        }));
+    }
+
+    /// Subscribes to each channel, or each glob pattern if patterns is true
+    pub fn subscribe(&mut self, id: u64, names: Vec<String>, patterns: bool) {
+        let kind: &[u8] = if patterns { b"psubscribe" } else { b"subscribe" };
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            for name in names {
+                if patterns {
+                    subscriber.patterns.insert(name.clone());
+                } else {
+                    subscriber.channels.insert(name.clone());
+                }
+                let count = subscriber.count().to_string().into_bytes();
+                subscriber.push(&[kind.to_vec(), name.into_bytes(), count]);
+            }
+        }
+    }
+
+    /// Unsubscribes from each channel, or each pattern if patterns is true. Every channel or
+    /// pattern is unsubscribed from when names is empty
+    ///
+    /// Returns true if the subscriber is still subscribed to something, otherwise it is removed
+    /// which ends its stream
+    pub fn unsubscribe(&mut self, id: u64, names: Vec<String>, patterns: bool) -> bool {
+        let kind: &[u8] = if patterns { b"punsubscribe" } else { b"unsubscribe" };
+        let remaining = match self.subscribers.get_mut(&id) {
+            Some(subscriber) => {
+                let names: Vec<String> = if !names.is_empty() {
+                    names
+                } else if patterns {
+                    subscriber.patterns.iter().cloned().collect()
+                } else {
+                    subscriber.channels.iter().cloned().collect()
+                };
+                for name in names {
+                    if patterns {
+                        subscriber.patterns.remove(&name);
+                    } else {
+                        subscriber.channels.remove(&name);
+                    }
+                    let count = subscriber.count().to_string().into_bytes();
+                    subscriber.push(&[kind.to_vec(), name.into_bytes(), count]);
+                }
+                subscriber.count()
+            }
+            None => 0,
+        };
+        if remaining == 0 {
+            self.remove(id);
+        }
+        return remaining > 0;
+    }
+
+    /// Removes a subscriber, dropping its sender ends its stream
+    pub fn remove(&mut self, id: u64) {
+        self.subscribers.remove(&id);
+    }
+
+    /// Pushes the message to every subscriber of the channel and every subscriber with a
+    /// pattern matching the channel. Returns the number of times it was delivered, a message
+    /// dropped because a subscriber's buffer is full isn't counted
+    ///
+    /// A subscriber receives the message once for the channel and once for each matching pattern
+    pub fn publish(&mut self, channel: &str, message: &[u8]) -> usize {
+        let mut receivers = 0;
+        let mut closed: Vec<u64> = Vec::new();
+        for (id, subscriber) in self.subscribers.iter_mut() {
+            let mut delivery = Delivery::DELIVERED;
+            if subscriber.channels.contains(channel) {
+                delivery = subscriber.push(&[b"message".to_vec(),
+                                             channel.as_bytes().to_vec(),
+                                             message.to_vec()]);
+                if delivery == Delivery::DELIVERED {
+                    receivers += 1;
+                }
+            }
+            let patterns: Vec<String> = subscriber.patterns
+                .iter()
+                .filter(|pattern| glob::matches(pattern, channel))
+                .cloned()
+                .collect();
+            for pattern in patterns {
+                if delivery == Delivery::CLOSED {
+                    break;
+                }
+                delivery = subscriber.push(&[b"pmessage".to_vec(),
+                                             pattern.into_bytes(),
+                                             channel.as_bytes().to_vec(),
+                                             message.to_vec()]);
+                if delivery == Delivery::DELIVERED {
+                    receivers += 1;
+                }
+            }
+            // The connection went away while its stream was still held
+            if delivery == Delivery::CLOSED {
+                closed.push(*id);
+            }
+        }
+        for id in closed {
+            self.subscribers.remove(&id);
+        }
+        return receivers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+
+    use futures::sync::mpsc;
+
+    use super::{PubSub, Subscriber, SUBSCRIBER_BUFFER};
+
+    #[test]
+    fn publish_counts_each_delivery() {
+        let pubsub = Arc::new(Mutex::new(PubSub::default()));
+        let (id, stream) = pubsub.lock().unwrap().register(&pubsub);
+        pubsub.lock().unwrap().subscribe(id, vec![String::from("news")], false);
+        pubsub.lock().unwrap().subscribe(id, vec![String::from("n*"), String::from("x*")], true);
+        assert_eq!(pubsub.lock().unwrap().publish("news", b"hi"), 2);
+        assert_eq!(pubsub.lock().unwrap().publish("other", b"hi"), 0);
+        drop(stream);
+        assert_eq!(pubsub.lock().unwrap().publish("news", b"hi"), 0);
+    }
+
+    #[test]
+    fn messages_dropped_for_a_full_buffer_are_not_counted() {
+        let pubsub = Arc::new(Mutex::new(PubSub::default()));
+        let (id, _stream) = pubsub.lock().unwrap().register(&pubsub);
+        let mut pubsub = pubsub.lock().unwrap();
+        pubsub.subscribe(id, vec![String::from("news")], false);
+        let delivered: usize = (0..SUBSCRIBER_BUFFER * 2).map(|_| pubsub.publish("news", b"hi")).sum();
+        assert!(delivered < SUBSCRIBER_BUFFER * 2);
+        assert_eq!(pubsub.publish("news", b"hi"), 0);
+        // A subscriber that isn't keeping up is still subscribed
+        assert!(pubsub.subscribers.contains_key(&id));
+    }
+
+    #[test]
+    fn a_closed_subscriber_is_not_counted_and_is_removed() {
+        let mut pubsub = PubSub::default();
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        drop(receiver);
+        let mut channels = BTreeSet::new();
+        channels.insert(String::from("news"));
+        pubsub.subscribers.insert(7, Subscriber {
+            sender: sender,
+            channels: channels,
+            patterns: BTreeSet::new()
+        });
+        assert_eq!(pubsub.publish("news", b"hi"), 0);
+        assert!(pubsub.subscribers.is_empty());
+    }
+}
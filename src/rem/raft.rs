@@ -0,0 +1,1341 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use futures::sync::oneshot;
+
+use rem::cache::{Cache, CacheOperation};
+use rem::config::Config;
+use rem::op;
+use rem::pubsub::PubSub;
+use rem::replication::{self, StreamEntry};
+use rem::service;
+use rem::tcp_stream::TcpStream;
+use rem::error::*;
+
+/// The directory the term, vote, log and applied index of a node are kept in
+pub const RAFT_DIR: &'static str = "_raft";
+
+/// A leader that hasn't heard from a majority of the cluster for this long steps down, so a
+/// leader cut off from the rest of the cluster stops taking writes it can't commit
+pub const LEADER_LEASE_MS: u64 = ELECTION_TIMEOUT_MAX_MS;
+
+/// A leader only serves reads while a majority of the cluster has responded within this time.
+/// It is shorter than the election timeout so no other leader can have been elected since, and
+/// longer than the heartbeat interval so a leader in touch with the cluster always serves them
+pub const READ_LEASE_MS: u64 = 250;
+
+/// A write that hasn't been committed this long after it was proposed fails. It stays in the
+/// log so it may still be committed later
+pub const PROPOSAL_TIMEOUT_MS: u64 = 5000;
+
+/// The log is compacted once it holds this many entries that have been applied, their effects
+/// are in the cache so they are dropped
+pub const COMPACT_AFTER_ENTRIES: u64 = 10000;
+
+/// The most bytes of keys sent to a follower in a single RAFTSNAPSHOT
+pub const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// A follower that hears nothing from a leader for a random time in this range starts an election
+pub const ELECTION_TIMEOUT_MIN_MS: u64 = 300;
+pub const ELECTION_TIMEOUT_MAX_MS: u64 = 600;
+
+/// How often a leader sends entries or an empty heartbeat to each follower
+pub const HEARTBEAT_INTERVAL_MS: u64 = 100;
+
+/// The most entries sent to a follower in a single RAFTAPPEND
+pub const MAX_ENTRIES_PER_APPEND: usize = 1000;
+
+/// The commands that can't run in cluster mode since they depend on state held by a single node
+pub const UNSUPPORTED_COMMANDS: &'static [&'static str] = &["MULTI", "EXEC", "DISCARD", "WATCH", "UNWATCH",
+                                                            "BLPOP", "BRPOP"];
+
+/// The role of a node in the cluster
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    FOLLOWER,
+    CANDIDATE,
+    LEADER,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Role::FOLLOWER => "follower",
+            Role::CANDIDATE => "candidate",
+            Role::LEADER => "leader",
+        }
+    }
+}
+
+/// An entry in the operation log, the data is the bytes of a write request
+/// A leader appends an entry with no data when it is elected so entries from earlier terms
+/// can be committed
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub data: Vec<u8>
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        return op::encode_list(&[self.term.to_string().into_bytes(), self.data.clone()]);
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Entry, RemError> {
+        let mut items = try!(op::decode_list(bytes)).into_iter();
+        let term = try!(op::parse_integer(&items.next().unwrap_or(Vec::new()))) as u64;
+        return Ok(Entry {
+            term: term,
+            data: items.next().unwrap_or(Vec::new())
+        });
+    }
+}
+
+/// Another node in the cluster and how much of the log it is known to hold
+struct Peer {
+    addr: String,
+    /// The index of the next entry the leader sends it
+    next_index: u64,
+    /// The index of the last entry it is known to hold
+    match_index: u64,
+    /// Wakes the thread sending it entries as soon as there is a new entry
+    nudge: std_mpsc::Sender<()>,
+    /// When it last responded to this node as the leader
+    last_ack: Instant
+}
+
+/// The chunks of a snapshot received so far from the leader
+struct IncomingSnapshot {
+    index: u64,
+    term: u64,
+    next_chunk: u64,
+    changes: Vec<StreamEntry>
+}
+
+/// The state of a node in a cluster that agrees on an operation log using Raft
+///
+/// Every write is appended to the log by the leader and sent to the followers. Once a majority
+/// of the cluster holds an entry it is committed and each node applies it to its cache in log
+/// order. When the leader stops sending heartbeats the followers elect a new one, a node only
+/// votes for a candidate whose log holds every entry it has, so every committed entry survives
+///
+/// The log is indexed from 1, an index of 0 means no entry. Once enough entries have been
+/// applied the log is compacted, the applied entries are dropped and the cache stands in for
+/// them as a snapshot. A follower that is missing entries the leader has dropped is sent every
+/// key in the leader's cache instead
+pub struct Raft {
+    /// The address of this node, which is also how the other nodes and clients reach it
+    pub id: String,
+    peers: Vec<Peer>,
+    role: Role,
+    current_term: u64,
+    voted_for: Option<String>,
+    /// The votes received by this node as a candidate in the current term
+    votes: usize,
+    /// The entries after the snapshot, the entry at index is at index - snapshot_index - 1
+    log: Vec<Entry>,
+    /// The index and term of the last entry dropped from the log
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// The length of the log file's header, which holds the snapshot index and term
+    header_len: u64,
+    /// The offset in the log file where each entry ends so the file can be cut short without
+    /// writing it again
+    log_ends: Vec<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    leader: Option<String>,
+    election_deadline: Instant,
+    /// The clients waiting for their write to be applied and when their write times out,
+    /// by log index
+    waiting: HashMap<u64, (Instant, oneshot::Sender<Vec<u8>>)>,
+    /// The snapshot being received from the leader
+    incoming: Option<IncomingSnapshot>
+}
+
+impl Raft {
+    /// Loads the state of the node from RAFT_DIR, a node with no saved state starts at term 0
+    /// with an empty log. Entries up to the applied index are already in the cache
+    ///
+    /// rolled_back is true when the cache rolled back an apply that was interrupted, the
+    /// entries it was applying are applied again
+    pub fn load(id: String,
+                peers: Vec<String>,
+                rolled_back: bool)
+                -> Result<(Raft, Vec<std_mpsc::Receiver<()>>), RemError> {
+        try!(fs::create_dir_all(RAFT_DIR));
+        let mut current_term = 0;
+        let mut voted_for: Option<String> = None;
+        if let Some(bytes) = try!(read_file("state")) {
+            let mut items = try!(op::decode_keys(&bytes)).into_iter();
+            current_term = try!(op::parse_integer(items.next().unwrap_or(String::new()).as_bytes())) as u64;
+            voted_for = items.next().and_then(|vote| if vote.is_empty() { None } else { Some(vote) });
+        }
+        let mut receivers: Vec<std_mpsc::Receiver<()>> = Vec::new();
+        let peers: Vec<Peer> = peers.into_iter()
+            .map(|addr| {
+                let (nudge, receiver) = std_mpsc::channel();
+                receivers.push(receiver);
+                Peer {
+                    addr: addr,
+                    next_index: 1,
+                    match_index: 0,
+                    nudge: nudge,
+                    last_ack: Instant::now()
+                }
+            })
+            .collect();
+        let mut raft = Raft {
+            id: id,
+            peers: peers,
+            role: Role::FOLLOWER,
+            current_term: current_term,
+            voted_for: voted_for,
+            votes: 0,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            header_len: 0,
+            log_ends: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            leader: None,
+            election_deadline: election_deadline(),
+            waiting: HashMap::new(),
+            incoming: None
+        };
+        try!(raft.load_log());
+        let (applied, applied_term) = match try!(read_file("applied")) {
+            Some(bytes) => {
+                let items = try!(op::decode_keys(&bytes));
+                if items.len() != 3 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                if rolled_back {
+                    let base = try!(op::parse_integer(items[2].as_bytes())) as u64;
+                    (base, raft.term_at(base))
+                } else {
+                    (try!(op::parse_integer(items[0].as_bytes())) as u64,
+                     try!(op::parse_integer(items[1].as_bytes())) as u64)
+                }
+            }
+            None => (raft.snapshot_index, raft.snapshot_term),
+        };
+        // A snapshot was installed but the node stopped before the log was compacted to match it
+        if applied > raft.last_index() || raft.term_at(applied) != applied_term {
+            warn!("The log does not hold the applied index {}, it is compacted up to it", applied);
+            try!(raft.compact(applied, applied_term));
+        }
+        raft.commit_index = applied;
+        raft.last_applied = applied;
+        return Ok((raft, receivers));
+    }
+
+    pub fn is_leader(&self) -> bool {
+        return self.role == Role::LEADER;
+    }
+
+    /// Returns the error sent to a client that made a request to a node that isn't the leader
+    /// It names the leader so the client can send the request there instead
+    pub fn redirect(&self) -> RemError {
+        match self.leader {
+            Some(ref leader) => RemError::with_reason(format!("{} {}", REM_00034, leader)),
+            None => RemError::with_reason_str(REM_00035),
+        }
+    }
+
+    /// Describes the state of the node, each item is ```{name}:{value}```
+    pub fn info(&self) -> Vec<String> {
+        let mut info: Vec<String> = Vec::new();
+        info.push(format!("id:{}", self.id));
+        info.push(format!("role:{}", self.role.name()));
+        info.push(format!("term:{}", self.current_term));
+        info.push(format!("leader:{}", self.leader.clone().unwrap_or(String::new())));
+        info.push(format!("snapshot_index:{}", self.snapshot_index));
+        info.push(format!("last_index:{}", self.last_index()));
+        info.push(format!("commit_index:{}", self.commit_index));
+        info.push(format!("last_applied:{}", self.last_applied));
+        for peer in &self.peers {
+            if self.role == Role::LEADER {
+                info.push(format!("peer:{}:match_index={}", peer.addr, peer.match_index));
+            } else {
+                info.push(format!("peer:{}", peer.addr));
+            }
+        }
+        return info;
+    }
+
+    fn last_index(&self) -> u64 {
+        return self.snapshot_index + self.log.len() as u64;
+    }
+
+    /// Returns the term of the entry at index, the snapshot term for the last entry dropped
+    /// from the log and 0 for index 0, an earlier dropped entry or an index past the end of
+    /// the log
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.snapshot_index {
+            return self.snapshot_term;
+        }
+        if index < self.snapshot_index || index > self.last_index() {
+            return 0;
+        }
+        return self.log[(index - self.snapshot_index - 1) as usize].term;
+    }
+
+    /// Returns the entries from index first to index last, both after the snapshot
+    fn entries(&self, first: u64, last: u64) -> &[Entry] {
+        if first > last {
+            return &[];
+        }
+        return &self.log[(first - self.snapshot_index - 1) as usize..(last - self.snapshot_index) as usize];
+    }
+
+    fn quorum(&self) -> usize {
+        return (self.peers.len() + 1) / 2 + 1;
+    }
+
+    /// Returns true if a majority of the cluster, counting this node, has responded within
+    /// the leader lease
+    fn has_quorum(&self) -> bool {
+        return self.acked_within(LEADER_LEASE_MS);
+    }
+
+    /// Returns true if this node is the leader, a majority of the cluster has responded within
+    /// the read lease and it has applied an entry from its own term, so no other node can have
+    /// been elected and its cache holds every committed write
+    pub fn can_serve_reads(&self) -> bool {
+        return self.is_leader() && self.acked_within(READ_LEASE_MS) &&
+               self.term_at(self.last_applied) == self.current_term;
+    }
+
+    fn acked_within(&self, lease_ms: u64) -> bool {
+        let lease = Duration::from_millis(lease_ms);
+        let acked = 1 + self.peers.iter().filter(|peer| peer.last_ack.elapsed() < lease).count();
+        return acked >= self.quorum();
+    }
+
+    /// Fails the waiting writes that have timed out
+    fn expire_proposals(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self.waiting
+            .iter()
+            .filter(|&(_, &(deadline, _))| deadline <= now)
+            .map(|(index, _)| *index)
+            .collect();
+        for index in expired {
+            if let Some((_, waiter)) = self.waiting.remove(&index) {
+                let _ = waiter.send(service::response_bytes(Err(RemError::with_reason_str(REM_00056))));
+            }
+        }
+    }
+
+    /// Moves to a later term as a follower, a leader that steps down fails its waiting writes
+    fn step_down(&mut self, term: u64) -> Result<(), RemError> {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            try!(self.save_state());
+        }
+        if self.role == Role::LEADER {
+            info!("Stepping down as leader in term {}", self.current_term);
+            self.leader = None;
+            for (_, (_, waiter)) in self.waiting.drain() {
+                let _ = waiter.send(service::response_bytes(Err(RemError::with_reason_str(REM_00036))));
+            }
+        }
+        self.role = Role::FOLLOWER;
+        self.election_deadline = election_deadline();
+        return Ok(());
+    }
+
+    fn become_leader(&mut self) -> Result<(), RemError> {
+        info!("Elected leader for term {}", self.current_term);
+        self.role = Role::LEADER;
+        self.leader = Some(self.id.clone());
+        let next_index = self.last_index() + 1;
+        for peer in self.peers.iter_mut() {
+            peer.next_index = next_index;
+            peer.match_index = 0;
+            peer.last_ack = Instant::now();
+        }
+        // Committing an entry from the new term also commits every entry before it
+        let term = self.current_term;
+        try!(self.append(Entry {
+            term: term,
+            data: Vec::new()
+        }));
+        // A cluster of one commits straight away
+        self.advance_commit();
+        return Ok(());
+    }
+
+    /// Appends an entry to the log and wakes the threads sending entries to the followers
+    fn append(&mut self, entry: Entry) -> Result<u64, RemError> {
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(raft_file("log")));
+        let framed = frame(&entry.encode());
+        try!(file.write_all(&framed));
+        try!(file.sync_data());
+        let end = self.log_len() + framed.len() as u64;
+        self.log.push(entry);
+        self.log_ends.push(end);
+        for peer in &self.peers {
+            let _ = peer.nudge.send(());
+        }
+        return Ok(self.last_index());
+    }
+
+    /// Drops every entry after index, used when a follower's log conflicts with the leader's
+    ///
+    /// The log file is cut short at the end of the entry at index
+    fn truncate(&mut self, index: u64) -> Result<(), RemError> {
+        let keep = (index - self.snapshot_index) as usize;
+        self.log.truncate(keep);
+        self.log_ends.truncate(keep);
+        return self.cut_log_file();
+    }
+
+    /// The length of the log file up to the end of its last entry
+    fn log_len(&self) -> u64 {
+        return self.log_ends.last().cloned().unwrap_or(self.header_len);
+    }
+
+    fn cut_log_file(&self) -> Result<(), RemError> {
+        let file = try!(OpenOptions::new().write(true).open(raft_file("log")));
+        try!(file.set_len(self.log_len()));
+        try!(file.sync_data());
+        return Ok(());
+    }
+
+    /// Reads the log file, which starts with the snapshot index and term followed by the entries
+    /// after them. An entry left partly written by a crash is cut off the end of the file
+    fn load_log(&mut self) -> Result<(), RemError> {
+        let bytes = match try!(read_file("log")) {
+            Some(bytes) => bytes,
+            None => return self.compact(0, 0),
+        };
+        let mut rest: &[u8] = &bytes;
+        match op::decode_item(rest) {
+            Some((header, next)) => {
+                let items = try!(op::decode_keys(&header));
+                if items.len() != 2 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                self.snapshot_index = try!(op::parse_integer(items[0].as_bytes())) as u64;
+                self.snapshot_term = try!(op::parse_integer(items[1].as_bytes())) as u64;
+                rest = next;
+            }
+            None => return self.compact(0, 0),
+        }
+        self.header_len = (bytes.len() - rest.len()) as u64;
+        while let Some((entry, next)) = op::decode_item(rest) {
+            self.log.push(try!(Entry::decode(&entry)));
+            rest = next;
+            self.log_ends.push((bytes.len() - rest.len()) as u64);
+        }
+        if !rest.is_empty() {
+            warn!("Dropping a partly written entry from the end of the log");
+            try!(self.cut_log_file());
+        }
+        return Ok(());
+    }
+
+    /// Drops the entries up to index from the log, the cache already holds their effects
+    ///
+    /// The entries after index are kept if the log holds the entry at index with the term,
+    /// otherwise the log conflicts with the snapshot and every entry is dropped. The log file
+    /// is written again with just the entries kept
+    fn compact(&mut self, index: u64, term: u64) -> Result<(), RemError> {
+        let kept: Vec<Entry> = if index >= self.snapshot_index && index <= self.last_index() &&
+                                   self.term_at(index) == term {
+            self.log[(index - self.snapshot_index) as usize..].to_vec()
+        } else {
+            Vec::new()
+        };
+        let mut bytes = frame(&op::encode_list(&[index.to_string().into_bytes(), term.to_string().into_bytes()]));
+        let header_len = bytes.len() as u64;
+        let mut log_ends: Vec<u64> = Vec::new();
+        for entry in &kept {
+            bytes.extend(frame(&entry.encode()));
+            log_ends.push(bytes.len() as u64);
+        }
+        try!(write_file("log", &bytes));
+        if index > 0 {
+            info!("Compacted the log up to index {}, {} entries are kept", index, kept.len());
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.header_len = header_len;
+        self.log = kept;
+        self.log_ends = log_ends;
+        return Ok(());
+    }
+
+    fn save_state(&self) -> Result<(), RemError> {
+        let vote = self.voted_for.clone().unwrap_or(String::new());
+        return write_file("state",
+                          &op::encode_list(&[self.current_term.to_string().into_bytes(), vote.into_bytes()]));
+    }
+
+    /// Returns true if this node votes for the candidate in term, which it does when it hasn't
+    /// voted for another candidate in that term and the candidate's log is at least as up to
+    /// date as its own
+    fn grants_vote(&self, term: u64, candidate: &str, last_index: u64, last_term: u64) -> bool {
+        let my_last_term = self.term_at(self.last_index());
+        let up_to_date = last_term > my_last_term || (last_term == my_last_term && last_index >= self.last_index());
+        let can_vote = self.voted_for.as_ref().map(|vote| vote == candidate).unwrap_or(true);
+        return term == self.current_term && can_vote && up_to_date;
+    }
+
+    /// Returns None when the log holds the entry the leader's entries follow, otherwise the
+    /// index the leader should go back to
+    ///
+    /// Entries up to the snapshot were committed so they can't conflict with the leader's
+    fn mismatch_hint(&self, prev_index: u64, prev_term: u64) -> Option<u64> {
+        if prev_index < self.snapshot_index {
+            return None;
+        }
+        if prev_index > self.last_index() {
+            return Some(self.last_index());
+        }
+        if self.term_at(prev_index) != prev_term {
+            return Some(prev_index - 1);
+        }
+        return None;
+    }
+
+    /// Compares the leader's entries after prev_index with the log, returns the index of the
+    /// first entry that conflicts, which is dropped with every entry after it, and the position
+    /// in entries of the first one to append
+    ///
+    /// Entries the log already holds are skipped so an old append doesn't drop newer entries
+    fn new_entries(&self, prev_index: u64, entries: &[Entry]) -> (Option<u64>, usize) {
+        for (pos, entry) in entries.iter().enumerate() {
+            let index = prev_index + 1 + pos as u64;
+            if index <= self.snapshot_index {
+                continue;
+            }
+            if index > self.last_index() {
+                return (None, pos);
+            }
+            if self.term_at(index) != entry.term {
+                return (Some(index), pos);
+            }
+        }
+        return (None, entries.len());
+    }
+
+    /// Commits up to the leader's commit index, but no further than the last entry received
+    /// from it. Returns true if the commit index moved
+    fn follow_commit(&mut self, leader_commit: u64, last_new: u64) -> bool {
+        let commit_index = if leader_commit < last_new { leader_commit } else { last_new };
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+            return true;
+        }
+        return false;
+    }
+
+    /// Commits the latest entry from the current term that a majority of the cluster holds
+    /// Returns true if the commit index moved
+    fn advance_commit(&mut self) -> bool {
+        let mut index = self.last_index();
+        while index > self.commit_index && self.term_at(index) == self.current_term {
+            let holders = 1 + self.peers.iter().filter(|peer| peer.match_index >= index).count();
+            if holders >= self.quorum() {
+                self.commit_index = index;
+                return true;
+            }
+            index -= 1;
+        }
+        return false;
+    }
+}
+
+/// Starts the threads that run elections and send entries to every peer
+pub fn start(config: Config,
+             raft: Arc<Mutex<Raft>>,
+             nudges: Vec<std_mpsc::Receiver<()>>,
+             cache: Arc<Mutex<Cache>>,
+             pubsub: Arc<Mutex<PubSub>>) {
+    for (idx, nudge) in nudges.into_iter().enumerate() {
+        let config = config.clone();
+        let raft = raft.clone();
+        let cache = cache.clone();
+        let pubsub = pubsub.clone();
+        thread::spawn(move || replicate_to_peer(config, idx, nudge, raft, cache, pubsub));
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(20));
+            if let Err(why) = check_election(&config, &raft, &cache, &pubsub) {
+                why.log();
+            }
+        }
+    });
+}
+
+/// Appends a write to the log and returns a receiver for its response, which is sent once the
+/// entry is committed and applied. The write fails if it isn't committed within
+/// PROPOSAL_TIMEOUT_MS or the node stops being the leader first
+///
+/// The node must be the leader
+pub fn propose(raft_mtx: &Mutex<Raft>,
+               cache_mtx: &Mutex<Cache>,
+               pubsub_mtx: &Mutex<PubSub>,
+               data: Vec<u8>)
+               -> Result<oneshot::Receiver<Vec<u8>>, RemError> {
+    let (sender, receiver) = oneshot::channel();
+    let committed = {
+        let mut raft = raft_mtx.lock().unwrap();
+        if !raft.is_leader() {
+            return Err(raft.redirect());
+        }
+        let term = raft.current_term;
+        let index = try!(raft.append(Entry {
+            term: term,
+            data: data
+        }));
+        let deadline = Instant::now() + Duration::from_millis(PROPOSAL_TIMEOUT_MS);
+        raft.waiting.insert(index, (deadline, sender));
+        // A cluster of one commits straight away
+        raft.advance_commit()
+    };
+    if committed {
+        try!(apply_committed(raft_mtx, cache_mtx, pubsub_mtx));
+    }
+    return Ok(receiver);
+}
+
+/// Applies the committed entries that have not been applied yet, in log order, and sends the
+/// responses to any clients waiting for them
+///
+/// The cache lock is held throughout so entries are applied in order even when several
+/// threads apply at once. The entries are applied in a cache transaction and the new applied
+/// index is written before it is committed, if the server stops part way through the cache is
+/// rolled back and the entries are applied again from the index the apply started at
+pub fn apply_committed(raft_mtx: &Mutex<Raft>,
+                       cache_mtx: &Mutex<Cache>,
+                       pubsub_mtx: &Mutex<PubSub>)
+                       -> Result<(), RemError> {
+    let mut cache = cache_mtx.lock().unwrap();
+    let (base, base_term, entries, mut waiting) = {
+        let mut raft = raft_mtx.lock().unwrap();
+        let base = raft.last_applied;
+        let entries: Vec<Entry> = raft.entries(base + 1, raft.commit_index).to_vec();
+        raft.last_applied = raft.commit_index;
+        let mut waiting: HashMap<u64, oneshot::Sender<Vec<u8>>> = HashMap::new();
+        for index in base + 1..raft.commit_index + 1 {
+            if let Some((_, waiter)) = raft.waiting.remove(&index) {
+                waiting.insert(index, waiter);
+            }
+        }
+        (base, raft.term_at(base), entries, waiting)
+    };
+    let (applied, applied_term) = match entries.last() {
+        Some(entry) => (base + entries.len() as u64, entry.term),
+        None => return Ok(()),
+    };
+    // Written first so the index the apply started at is known if it is rolled back
+    try!(write_applied(base, base_term, base));
+    try!(cache.begin_transaction());
+    let mut responses: Vec<(oneshot::Sender<Vec<u8>>, Vec<u8>)> = Vec::new();
+    for (offset, entry) in entries.into_iter().enumerate() {
+        let index = base + 1 + offset as u64;
+        if entry.data.is_empty() {
+            continue;
+        }
+        let res = service::response_bytes(service::execute(CacheOperation::new_from_bytes(&entry.data),
+                                                           &mut cache));
+        if let Some(waiter) = waiting.remove(&index) {
+            responses.push((waiter, res));
+        }
+    }
+    try!(write_applied(applied, applied_term, base));
+    try!(cache.commit_transaction());
+    for (waiter, res) in responses {
+        let _ = waiter.send(res);
+    }
+    service::publish_key_events(pubsub_mtx, &mut cache);
+    let mut raft = raft_mtx.lock().unwrap();
+    if raft.last_applied - raft.snapshot_index >= COMPACT_AFTER_ENTRIES {
+        let (index, term) = (raft.last_applied, raft.term_at(raft.last_applied));
+        try!(raft.compact(index, term));
+    }
+    return Ok(());
+}
+
+/// Handles a RAFTVOTE request from a candidate
+///
+/// The request is ```[term, candidate, last log index, last log term]``` and the response is
+/// ```[term, 1 if the vote was granted otherwise 0]```
+pub fn handle_vote(raft_mtx: &Mutex<Raft>, req: &[u8]) -> Result<Vec<u8>, RemError> {
+    let items = try!(op::decode_keys(req));
+    if items.len() != 4 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let term = try!(op::parse_integer(items[0].as_bytes())) as u64;
+    let candidate = items[1].clone();
+    let last_index = try!(op::parse_integer(items[2].as_bytes())) as u64;
+    let last_term = try!(op::parse_integer(items[3].as_bytes())) as u64;
+
+    let mut raft = raft_mtx.lock().unwrap();
+    if term > raft.current_term {
+        try!(raft.step_down(term));
+    }
+    let granted = raft.grants_vote(term, candidate.as_str(), last_index, last_term);
+    if granted {
+        raft.voted_for = Some(candidate);
+        try!(raft.save_state());
+        raft.election_deadline = election_deadline();
+    }
+    let granted_str = if granted { "1" } else { "0" };
+    return Ok(op::encode_list(&[raft.current_term.to_string().into_bytes(), granted_str.as_bytes().to_vec()]));
+}
+
+/// Handles a RAFTAPPEND request from the leader
+///
+/// The request is ```[term, leader, previous index, previous term, leader commit, entries...]```
+/// and the response is ```[term, 1 on success otherwise 0, last matching index]```. When the
+/// follower's log doesn't hold the previous entry the last matching index is a hint for where
+/// the leader should go back to
+pub fn handle_append(raft_mtx: &Mutex<Raft>,
+                     cache_mtx: &Mutex<Cache>,
+                     pubsub_mtx: &Mutex<PubSub>,
+                     req: &[u8])
+                     -> Result<Vec<u8>, RemError> {
+    let items = try!(op::decode_list(req));
+    if items.len() < 5 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let term = try!(op::parse_integer(&items[0])) as u64;
+    let leader = String::from_utf8_lossy(&items[1]).into_owned();
+    let prev_index = try!(op::parse_integer(&items[2])) as u64;
+    let prev_term = try!(op::parse_integer(&items[3])) as u64;
+    let leader_commit = try!(op::parse_integer(&items[4])) as u64;
+    let mut entries: Vec<Entry> = Vec::new();
+    for item in &items[5..] {
+        entries.push(try!(Entry::decode(item)));
+    }
+
+    let (res, committed) = {
+        let mut raft = raft_mtx.lock().unwrap();
+        if term < raft.current_term {
+            let current_term = raft.current_term;
+            return Ok(append_response(current_term, false, 0));
+        }
+        if term > raft.current_term || raft.role != Role::FOLLOWER {
+            try!(raft.step_down(term));
+        }
+        raft.leader = Some(leader);
+        raft.election_deadline = election_deadline();
+
+        if let Some(hint) = raft.mismatch_hint(prev_index, prev_term) {
+            return Ok(append_response(term, false, hint));
+        }
+        let index = prev_index + entries.len() as u64;
+        let (conflict, first_new) = raft.new_entries(prev_index, &entries);
+        if let Some(conflict) = conflict {
+            try!(raft.truncate(conflict - 1));
+        }
+        for entry in entries.into_iter().skip(first_new) {
+            try!(raft.append(entry));
+        }
+        let committed = raft.follow_commit(leader_commit, index);
+        (append_response(term, true, index), committed)
+    };
+    if committed {
+        try!(apply_committed(raft_mtx, cache_mtx, pubsub_mtx));
+    }
+    return Ok(res);
+}
+
+/// Handles a RAFTSNAPSHOT request from the leader
+///
+/// The request is ```[term, leader, index, term of index, chunk, 1 for the last chunk
+/// otherwise 0, changes...]``` where each change sets a key as it is sent to a replica. The
+/// response is the same as for RAFTAPPEND. Chunks are held until the last one arrives, then the
+/// cache is replaced with the keys they hold and the log is compacted up to the index
+pub fn handle_snapshot(raft_mtx: &Mutex<Raft>,
+                       cache_mtx: &Mutex<Cache>,
+                       pubsub_mtx: &Mutex<PubSub>,
+                       req: &[u8])
+                       -> Result<Vec<u8>, RemError> {
+    let items = try!(op::decode_list(req));
+    if items.len() < 6 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let term = try!(op::parse_integer(&items[0])) as u64;
+    let leader = String::from_utf8_lossy(&items[1]).into_owned();
+    let index = try!(op::parse_integer(&items[2])) as u64;
+    let snapshot_term = try!(op::parse_integer(&items[3])) as u64;
+    let chunk = try!(op::parse_integer(&items[4])) as u64;
+    let last = items[5] == b"1";
+    let mut changes: Vec<StreamEntry> = Vec::new();
+    for item in &items[6..] {
+        changes.push(try!(StreamEntry::decode(item)));
+    }
+
+    let snapshot = {
+        let mut raft = raft_mtx.lock().unwrap();
+        if term < raft.current_term {
+            let current_term = raft.current_term;
+            return Ok(append_response(current_term, false, 0));
+        }
+        if term > raft.current_term || raft.role != Role::FOLLOWER {
+            try!(raft.step_down(term));
+        }
+        raft.leader = Some(leader);
+        raft.election_deadline = election_deadline();
+        if index <= raft.last_applied {
+            raft.incoming = None;
+            return Ok(append_response(term, true, index));
+        }
+        if chunk == 0 {
+            raft.incoming = Some(IncomingSnapshot {
+                index: index,
+                term: snapshot_term,
+                next_chunk: 0,
+                changes: Vec::new()
+            });
+        }
+        let expected = raft.incoming.as_ref().map(|incoming| incoming.index == index && incoming.next_chunk == chunk);
+        if expected != Some(true) {
+            raft.incoming = None;
+            return Ok(append_response(term, false, 0));
+        }
+        if let Some(ref mut incoming) = raft.incoming {
+            incoming.next_chunk += 1;
+            incoming.changes.extend(changes);
+        }
+        if !last {
+            return Ok(append_response(term, true, 0));
+        }
+        raft.incoming.take().unwrap()
+    };
+    try!(install_snapshot(raft_mtx, cache_mtx, pubsub_mtx, snapshot));
+    return Ok(append_response(term, true, index));
+}
+
+/// Replaces the cache with the keys of a snapshot and compacts the log up to its index
+///
+/// Like an apply the keys are written in a cache transaction, so the cache is left as it was
+/// if the server stops part way through
+fn install_snapshot(raft_mtx: &Mutex<Raft>,
+                    cache_mtx: &Mutex<Cache>,
+                    pubsub_mtx: &Mutex<PubSub>,
+                    snapshot: IncomingSnapshot)
+                    -> Result<(), RemError> {
+    let mut cache = cache_mtx.lock().unwrap();
+    let (base, base_term) = {
+        let raft = raft_mtx.lock().unwrap();
+        if snapshot.index <= raft.last_applied {
+            return Ok(());
+        }
+        (raft.last_applied, raft.term_at(raft.last_applied))
+    };
+    info!("Installing a snapshot at index {} with {} keys", snapshot.index, snapshot.changes.len());
+    try!(write_applied(base, base_term, base));
+    try!(cache.begin_transaction());
+    let mut kept: HashSet<String> = HashSet::new();
+    for change in snapshot.changes {
+        if let StreamEntry::SET(_, ref key, _) = change {
+            kept.insert(key.clone());
+        }
+        try!(change.apply(&mut cache));
+    }
+    for key in try!(cache.keys()) {
+        if !kept.contains(&key) {
+            try!(cache.delete_item(key));
+        }
+    }
+    try!(write_applied(snapshot.index, snapshot.term, base));
+    try!(cache.commit_transaction());
+    service::publish_key_events(pubsub_mtx, &mut cache);
+    let mut raft = raft_mtx.lock().unwrap();
+    if snapshot.index > raft.commit_index {
+        raft.commit_index = snapshot.index;
+    }
+    raft.last_applied = snapshot.index;
+    return raft.compact(snapshot.index, snapshot.term);
+}
+
+fn append_response(term: u64, success: bool, index: u64) -> Vec<u8> {
+    let success_str = if success { "1" } else { "0" };
+    return op::encode_list(&[term.to_string().into_bytes(),
+                             success_str.as_bytes().to_vec(),
+                             index.to_string().into_bytes()]);
+}
+
+/// Decodes the response to a RAFTAPPEND or RAFTSNAPSHOT into the peer's term, whether it
+/// succeeded and its last matching index
+fn decode_append_response(res: &[u8]) -> Result<(u64, bool, u64), RemError> {
+    let items = try!(op::decode_keys(res));
+    if items.len() != 3 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let peer_term = try!(op::parse_integer(items[0].as_bytes())) as u64;
+    let match_index = try!(op::parse_integer(items[2].as_bytes())) as u64;
+    return Ok((peer_term, items[1] == "1", match_index));
+}
+
+/// Starts an election if no leader has been heard from before the election deadline
+///
+/// The candidate votes for itself and asks every peer for its vote in parallel, it becomes the
+/// leader as soon as a majority of the cluster has voted for it
+///
+/// A leader instead fails the writes that have timed out, and steps down if a majority of the
+/// cluster hasn't responded within the leader lease
+fn check_election(config: &Config,
+                  raft_arc: &Arc<Mutex<Raft>>,
+                  cache: &Arc<Mutex<Cache>>,
+                  pubsub: &Arc<Mutex<PubSub>>)
+                  -> Result<(), RemError> {
+    let (req, peers, term) = {
+        let mut raft = raft_arc.lock().unwrap();
+        if raft.role == Role::LEADER {
+            raft.expire_proposals();
+            if !raft.has_quorum() {
+                warn!("A majority of the cluster has not responded for {}ms", LEADER_LEASE_MS);
+                let term = raft.current_term;
+                try!(raft.step_down(term));
+            }
+            return Ok(());
+        }
+        if Instant::now() < raft.election_deadline {
+            return Ok(());
+        }
+        raft.role = Role::CANDIDATE;
+        raft.current_term += 1;
+        raft.voted_for = Some(raft.id.clone());
+        raft.votes = 1;
+        raft.leader = None;
+        raft.election_deadline = election_deadline();
+        try!(raft.save_state());
+        info!("Starting an election for term {}", raft.current_term);
+        if raft.votes >= raft.quorum() {
+            try!(raft.become_leader());
+            drop(raft);
+            return apply_committed(raft_arc, cache, pubsub);
+        }
+        let last_index = raft.last_index();
+        let req = op::encode_list(&[raft.current_term.to_string().into_bytes(),
+                                    raft.id.clone().into_bytes(),
+                                    last_index.to_string().into_bytes(),
+                                    raft.term_at(last_index).to_string().into_bytes()]);
+        let peers: Vec<String> = raft.peers.iter().map(|peer| peer.addr.clone()).collect();
+        (req, peers, raft.current_term)
+    };
+    for peer in peers {
+        let config = config.clone();
+        let raft_arc = raft_arc.clone();
+        let req = req.clone();
+        thread::spawn(move || {
+            let res = request_vote(&config, peer.as_str(), &req).and_then(|(peer_term, granted)| {
+                let mut raft = raft_arc.lock().unwrap();
+                if peer_term > raft.current_term {
+                    return raft.step_down(peer_term);
+                }
+                if granted && raft.role == Role::CANDIDATE && raft.current_term == term {
+                    raft.votes += 1;
+                    if raft.votes >= raft.quorum() {
+                        return raft.become_leader();
+                    }
+                }
+                return Ok(());
+            });
+            if let Err(why) = res {
+                debug!("Vote request to {} failed: {}", peer, why);
+            }
+        });
+    }
+    return Ok(());
+}
+
+fn request_vote(config: &Config, peer: &str, req: &[u8]) -> Result<(u64, bool), RemError> {
+    let mut stream = try!(TcpStream::connect(config, peer));
+    let res = try!(op::send_request(&mut stream, "RAFTVOTE", req));
+    let items = try!(op::decode_keys(&res));
+    if items.len() != 2 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let term = try!(op::parse_integer(items[0].as_bytes())) as u64;
+    return Ok((term, items[1] == "1"));
+}
+
+/// Sends entries, or an empty heartbeat, to a single peer while this node is the leader
+///
+/// Runs forever on its own thread, waking every heartbeat interval or when a new entry is
+/// appended. The connection to the peer is kept open and reopened after an error. A peer that
+/// needs entries which have been compacted out of the log is sent a snapshot instead
+fn replicate_to_peer(config: Config,
+                     idx: usize,
+                     nudge: std_mpsc::Receiver<()>,
+                     raft_arc: Arc<Mutex<Raft>>,
+                     cache: Arc<Mutex<Cache>>,
+                     pubsub: Arc<Mutex<PubSub>>) {
+    let mut stream: Option<TcpStream> = None;
+    loop {
+        let _ = nudge.recv_timeout(Duration::from_millis(HEARTBEAT_INTERVAL_MS));
+        // Several nudges may have arrived while the last request was in flight
+        while let Ok(()) = nudge.try_recv() {}
+        // The request is None when the peer needs a snapshot
+        let (addr, term, req, sent_up_to) = {
+            let raft = raft_arc.lock().unwrap();
+            if raft.role != Role::LEADER {
+                continue;
+            }
+            let peer = &raft.peers[idx];
+            let prev_index = peer.next_index - 1;
+            if prev_index < raft.snapshot_index {
+                (peer.addr.clone(), raft.current_term, None, 0)
+            } else {
+                let end = (prev_index + MAX_ENTRIES_PER_APPEND as u64).min(raft.last_index());
+                let mut items: Vec<Vec<u8>> = vec![raft.current_term.to_string().into_bytes(),
+                                                   raft.id.clone().into_bytes(),
+                                                   prev_index.to_string().into_bytes(),
+                                                   raft.term_at(prev_index).to_string().into_bytes(),
+                                                   raft.commit_index.to_string().into_bytes()];
+                for entry in raft.entries(prev_index + 1, end) {
+                    items.push(entry.encode());
+                }
+                (peer.addr.clone(), raft.current_term, Some(op::encode_list(&items)), end)
+            }
+        };
+        if stream.is_none() {
+            match TcpStream::connect(&config, addr.as_str()) {
+                Ok(connected) => stream = Some(connected),
+                Err(why) => {
+                    debug!("Could not reach {}: {}", addr, why);
+                    continue;
+                }
+            }
+        }
+        let snapshot = req.is_none();
+        let res = match (stream.as_mut(), req) {
+            (Some(stream), Some(req)) => {
+                op::send_request(stream, "RAFTAPPEND", &req).and_then(|res| decode_append_response(&res))
+            }
+            (Some(stream), None) => send_snapshot(stream, term, &raft_arc, &cache),
+            (None, _) => continue,
+        };
+        let (peer_term, success, match_index) = match res {
+            Ok(res) => res,
+            Err(why) => {
+                debug!("Append to {} failed: {}", addr, why);
+                stream = None;
+                continue;
+            }
+        };
+        let committed = {
+            let mut raft = raft_arc.lock().unwrap();
+            if peer_term > raft.current_term {
+                if let Err(why) = raft.step_down(peer_term) {
+                    why.log();
+                }
+                continue;
+            }
+            if raft.role != Role::LEADER || raft.current_term != term {
+                continue;
+            }
+            let peer = &mut raft.peers[idx];
+            peer.last_ack = Instant::now();
+            if success && snapshot {
+                // The peer now holds every entry up to the snapshot's index
+                peer.match_index = match_index;
+                peer.next_index = match_index + 1;
+            } else if success {
+                peer.match_index = sent_up_to.min(match_index);
+                peer.next_index = peer.match_index + 1;
+            } else {
+                // Go back to just after the last entry the peer may hold and try again
+                peer.next_index = (match_index + 1).min(peer.next_index - 1).max(1);
+                let _ = peer.nudge.send(());
+            }
+            success && raft.advance_commit()
+        };
+        if committed {
+            if let Err(why) = apply_committed(&raft_arc, &cache, &pubsub) {
+                why.log();
+            }
+        }
+    }
+}
+
+/// Sends every key in the cache to a peer as a snapshot at the applied index, in chunks of up
+/// to SNAPSHOT_CHUNK_BYTES, and returns the response to the last chunk
+///
+/// The keys are all read at once so every chunk is from the same index
+fn send_snapshot(stream: &mut TcpStream,
+                 term: u64,
+                 raft_mtx: &Mutex<Raft>,
+                 cache_mtx: &Mutex<Cache>)
+                 -> Result<(u64, bool, u64), RemError> {
+    let (id, index, snapshot_term, changes) = {
+        let cache = cache_mtx.lock().unwrap();
+        let raft = raft_mtx.lock().unwrap();
+        let index = raft.last_applied;
+        let mut changes: Vec<Vec<u8>> = Vec::new();
+        for key in try!(cache.keys()) {
+            if let Some(val) = try!(cache.read_value(key.as_str())) {
+                changes.push(replication::encode_change(index, key.as_str(), Some(&val)));
+            }
+        }
+        (raft.id.clone(), index, raft.term_at(index), changes)
+    };
+    info!("Sending a snapshot at index {} with {} keys", index, changes.len());
+    let mut chunks: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut chunk_bytes = 0;
+    for change in changes {
+        if chunk_bytes > 0 && chunk_bytes + change.len() > SNAPSHOT_CHUNK_BYTES {
+            chunks.push(Vec::new());
+            chunk_bytes = 0;
+        }
+        chunk_bytes += change.len();
+        chunks.last_mut().unwrap().push(change);
+    }
+    let count = chunks.len();
+    for (number, chunk) in chunks.into_iter().enumerate() {
+        let last = if number + 1 == count { "1" } else { "0" };
+        let mut items: Vec<Vec<u8>> = vec![term.to_string().into_bytes(),
+                                           id.clone().into_bytes(),
+                                           index.to_string().into_bytes(),
+                                           snapshot_term.to_string().into_bytes(),
+                                           number.to_string().into_bytes(),
+                                           last.as_bytes().to_vec()];
+        items.extend(chunk);
+        let res = try!(op::send_request(stream, "RAFTSNAPSHOT", &op::encode_list(&items)));
+        let (peer_term, success, match_index) = try!(decode_append_response(&res));
+        if !success || number + 1 == count {
+            return Ok((peer_term, success, match_index));
+        }
+    }
+    return Err(RemError::with_reason_str(REM_00015));
+}
+
+/// Picks a random deadline within the election timeout so nodes rarely time out together
+fn election_deadline() -> Instant {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.subsec_nanos()).unwrap_or(0);
+    let spread = ELECTION_TIMEOUT_MAX_MS - ELECTION_TIMEOUT_MIN_MS;
+    let timeout = ELECTION_TIMEOUT_MIN_MS + (nanos as u64 / 1000) % spread;
+    return Instant::now() + Duration::from_millis(timeout);
+}
+
+/// Writes the applied index and its term, with the index the apply in progress started at
+fn write_applied(applied: u64, term: u64, base: u64) -> Result<(), RemError> {
+    return write_file("applied",
+                      &op::encode_list(&[applied.to_string().into_bytes(),
+                                         term.to_string().into_bytes(),
+                                         base.to_string().into_bytes()]));
+}
+
+/// Prefixes bytes with their length as they are written to the log file
+fn frame(bytes: &[u8]) -> Vec<u8> {
+    let mut framed = format!("{}|", bytes.len()).into_bytes();
+    framed.extend_from_slice(bytes);
+    return framed;
+}
+
+fn raft_file(name: &str) -> String {
+    return format!("{}/{}", RAFT_DIR, name);
+}
+
+fn read_file(name: &str) -> Result<Option<Vec<u8>>, RemError> {
+    let path = raft_file(name);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let mut bytes: Vec<u8> = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    return Ok(Some(bytes));
+}
+
+/// Replaces a file by writing a temporary file and renaming it over the old one
+fn write_file(name: &str, bytes: &[u8]) -> Result<(), RemError> {
+    let tmp = raft_file(&format!("{}.tmp", name));
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(bytes));
+        try!(file.sync_data());
+    }
+    try!(fs::rename(tmp, raft_file(name)));
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::mpsc as std_mpsc;
+    use std::time::{Duration, Instant};
+
+    use super::{Entry, Peer, Raft, Role};
+
+    /// A node at term with an entry for each term in terms and the number of peers given
+    fn node(term: u64, terms: &[u64], peers: usize) -> Raft {
+        let peers = (0..peers)
+            .map(|idx| {
+                Peer {
+                    addr: format!("127.0.0.1:{}", 9000 + idx),
+                    next_index: 1,
+                    match_index: 0,
+                    nudge: std_mpsc::channel().0,
+                    last_ack: Instant::now()
+                }
+            })
+            .collect();
+        return Raft {
+            id: String::from("127.0.0.1:8999"),
+            peers: peers,
+            role: Role::FOLLOWER,
+            current_term: term,
+            voted_for: None,
+            votes: 0,
+            log: terms.iter().map(|&term| entry(term)).collect(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            header_len: 0,
+            log_ends: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            leader: None,
+            election_deadline: Instant::now(),
+            waiting: HashMap::new(),
+            incoming: None
+        };
+    }
+
+    fn entry(term: u64) -> Entry {
+        return Entry {
+            term: term,
+            data: Vec::new()
+        };
+    }
+
+    #[test]
+    fn votes_once_per_term_for_a_candidate_that_is_up_to_date() {
+        let mut raft = node(3, &[1, 2, 3], 2);
+        assert!(raft.grants_vote(3, "a", 3, 3));
+        assert!(raft.grants_vote(3, "a", 5, 3));
+        raft.voted_for = Some(String::from("a"));
+        assert!(raft.grants_vote(3, "a", 3, 3));
+        assert!(!raft.grants_vote(3, "b", 3, 3));
+        // A vote from an earlier term was stepped down from before this is asked
+        assert!(!raft.grants_vote(2, "a", 3, 3));
+    }
+
+    #[test]
+    fn refuses_a_candidate_with_an_older_log() {
+        let raft = node(3, &[1, 2, 3], 2);
+        // A shorter log with the same last term
+        assert!(!raft.grants_vote(3, "a", 2, 3));
+        // A longer log with an older last term
+        assert!(!raft.grants_vote(3, "a", 10, 2));
+        // A newer last term wins whatever the length
+        assert!(raft.grants_vote(3, "a", 1, 4));
+        assert!(node(1, &[], 2).grants_vote(1, "a", 0, 0));
+    }
+
+    #[test]
+    fn appends_only_follow_an_entry_the_log_holds() {
+        let mut raft = node(3, &[1, 1, 2], 2);
+        assert_eq!(raft.mismatch_hint(0, 0), None);
+        assert_eq!(raft.mismatch_hint(2, 1), None);
+        assert_eq!(raft.mismatch_hint(3, 2), None);
+        assert_eq!(raft.mismatch_hint(3, 3), Some(2));
+        assert_eq!(raft.mismatch_hint(6, 3), Some(3));
+
+        // Entries up to the snapshot were committed and always match
+        raft.log = vec![entry(2)];
+        raft.snapshot_index = 2;
+        raft.snapshot_term = 1;
+        assert_eq!(raft.mismatch_hint(1, 7), None);
+        assert_eq!(raft.mismatch_hint(2, 1), None);
+        assert_eq!(raft.mismatch_hint(2, 2), Some(1));
+        assert_eq!(raft.mismatch_hint(3, 2), None);
+    }
+
+    #[test]
+    fn conflicting_entries_are_dropped_and_held_ones_skipped() {
+        let raft = node(3, &[1, 1, 2, 2], 2);
+        // Every entry is already held, an old append drops nothing
+        assert_eq!(raft.new_entries(1, &[entry(1), entry(2)]), (None, 2));
+        // New entries past the end are appended
+        assert_eq!(raft.new_entries(3, &[entry(2), entry(3), entry(3)]), (None, 1));
+        // The entry at 3 conflicts so it and everything after it are replaced
+        assert_eq!(raft.new_entries(2, &[entry(3), entry(3)]), (Some(3), 0));
+        assert_eq!(raft.new_entries(1, &[entry(1), entry(3)]), (Some(3), 1));
+        assert_eq!(raft.new_entries(4, &[]), (None, 0));
+
+        let mut raft = node(3, &[2, 2], 2);
+        raft.snapshot_index = 3;
+        raft.snapshot_term = 1;
+        // Entries at or before the snapshot are skipped
+        assert_eq!(raft.new_entries(1, &[entry(9), entry(9), entry(2), entry(3)]), (Some(5), 3));
+    }
+
+    #[test]
+    fn a_follower_commits_no_further_than_the_entries_it_holds() {
+        let mut raft = node(2, &[1, 2, 2], 2);
+        assert!(!raft.follow_commit(0, 3));
+        assert!(raft.follow_commit(2, 3));
+        assert_eq!(raft.commit_index, 2);
+        assert!(raft.follow_commit(10, 3));
+        assert_eq!(raft.commit_index, 3);
+        assert!(!raft.follow_commit(1, 3));
+        assert_eq!(raft.commit_index, 3);
+    }
+
+    #[test]
+    fn the_leader_commits_entries_of_its_term_held_by_a_majority() {
+        let mut raft = node(2, &[1, 2, 2], 2);
+        raft.role = Role::LEADER;
+        assert!(!raft.advance_commit());
+        raft.peers[0].match_index = 2;
+        assert!(raft.advance_commit());
+        assert_eq!(raft.commit_index, 2);
+        raft.peers[1].match_index = 3;
+        assert!(raft.advance_commit());
+        assert_eq!(raft.commit_index, 3);
+        assert!(!raft.advance_commit());
+    }
+
+    #[test]
+    fn the_leader_never_commits_an_earlier_term_by_counting() {
+        let mut raft = node(3, &[1, 2], 2);
+        raft.role = Role::LEADER;
+        raft.peers[0].match_index = 2;
+        raft.peers[1].match_index = 2;
+        assert!(!raft.advance_commit());
+        assert_eq!(raft.commit_index, 0);
+        // Once an entry from its term is held by a majority every entry before it is committed
+        raft.log.push(entry(3));
+        raft.peers[0].match_index = 3;
+        assert!(raft.advance_commit());
+        assert_eq!(raft.commit_index, 3);
+    }
+
+    #[test]
+    fn a_leader_serves_reads_while_a_majority_responds() {
+        let mut raft = node(2, &[1, 2], 2);
+        raft.role = Role::LEADER;
+        raft.last_applied = 1;
+        // Nothing from its own term has been applied yet
+        assert!(!raft.can_serve_reads());
+        raft.last_applied = 2;
+        assert!(raft.can_serve_reads());
+        raft.peers[0].last_ack = Instant::now() - Duration::from_secs(1);
+        assert!(raft.can_serve_reads());
+        raft.peers[1].last_ack = Instant::now() - Duration::from_secs(1);
+        assert!(!raft.can_serve_reads());
+        raft.role = Role::FOLLOWER;
+        raft.peers[1].last_ack = Instant::now();
+        assert!(!raft.can_serve_reads());
+    }
+}
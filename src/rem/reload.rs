@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use native_tls::{Pkcs12, TlsAcceptor};
+use tokio_tls::proto::Server;
+
+use rem::cache::Cache;
+use rem::config::Config;
+use rem::logging::LogHandle;
+use rem::proto::CacheProto;
+use rem::replication::Replication;
+use rem::error::*;
+
+/// The settings a running server is using, reloaded from its config file on SIGHUP or
+/// CONFIG RELOAD
+///
+/// The log level, keyspace events, write syncing, connection limit, replication backlog size and
+/// shutdown timeout change straight away. A new certificate or request size limit is only used for connections accepted
+/// after the reload. The client settings aren't used by the server, clients read them when
+/// they start. Changes to any other setting need a restart, until then the running values
+/// are kept
+///
+/// rem has no memory limits or ACLs, so there are none to reload
+pub struct LiveConfig {
+    /// The directory the server was started in, relative paths in the config are resolved from it
+    dir: PathBuf,
+    file: String,
+    config: Config,
+    cert: Vec<u8>,
+    proto: Arc<Server<CacheProto>>,
+    cache: Arc<Mutex<Cache>>,
+    replication: Arc<Mutex<Replication>>,
+    log: LogHandle
+}
+
+impl LiveConfig {
+    /// Starts from the config the server was launched with, loading its certificate
+    pub fn new(dir: PathBuf,
+               file: String,
+               config: Config,
+               cache: Arc<Mutex<Cache>>,
+               replication: Arc<Mutex<Replication>>,
+               log: LogHandle)
+               -> Result<LiveConfig, RemError> {
+        let file = dir.join(file).to_string_lossy().into_owned();
+        let cert = try!(read_cert(&dir, &config));
+        let proto = try!(tls_proto(&cert, &config));
+        log.set_filters(config.server.log_level.as_ref().map(String::as_str));
+        return Ok(LiveConfig {
+            dir: dir,
+            file: file,
+            config: config,
+            cert: cert,
+            proto: Arc::new(proto),
+            cache: cache,
+            replication: replication,
+            log: log
+        });
+    }
+
+    /// The settings in use
+    pub fn config(&self) -> &Config {
+        return &self.config;
+    }
+
+    /// The protocol new connections are served with
+    pub fn proto(&self) -> Arc<Server<CacheProto>> {
+        return self.proto.clone();
+    }
+
+    /// Reads the config file again and applies the settings that changed
+    ///
+    /// Returns ```applied:{setting}``` for each setting now in use, ```restart:{setting}```
+    /// for each setting that only changes when the server is restarted and ```client:{setting}```
+    /// for each client setting that changed. Nothing is applied if the file or the certificate
+    /// it names can't be loaded
+    pub fn reload(&mut self) -> Result<Vec<String>, RemError> {
+        let mut config = try!(Config::from_file(self.file.clone()));
+        let cert = try!(read_cert(&self.dir, &config));
+        let rotate = cert != self.cert ||
+                     config.server.cert_password != self.config.server.cert_password ||
+                     config.server.max_request_bytes != self.config.server.max_request_bytes;
+        let proto = if rotate { Some(try!(tls_proto(&cert, &config))) } else { None };
+
+        let mut report = Vec::new();
+        if config.ssl != self.config.ssl {
+            report.push(String::from("restart:ssl"));
+            config.ssl = self.config.ssl;
+        }
+        if config.domain != self.config.domain {
+            report.push(String::from("restart:domain"));
+            config.domain = self.config.domain.clone();
+        }
+        if config.server.bind != self.config.server.bind {
+            report.push(String::from("restart:server.bind"));
+            config.server.bind = self.config.server.bind.clone();
+        }
+        if config.server.port != self.config.server.port {
+            report.push(String::from("restart:server.port"));
+            config.server.port = self.config.server.port;
+        }
+        if config.server.data_dir != self.config.server.data_dir {
+            report.push(String::from("restart:server.data_dir"));
+            config.server.data_dir = self.config.server.data_dir.clone();
+        }
+        if config.server.pool_size != self.config.server.pool_size {
+            report.push(String::from("restart:server.pool_size"));
+            config.server.pool_size = self.config.server.pool_size;
+        }
+        if config.server.cluster_port_offset != self.config.server.cluster_port_offset {
+            report.push(String::from("restart:server.cluster_port_offset"));
+            config.server.cluster_port_offset = self.config.server.cluster_port_offset;
+        }
+        if config.server.cluster_secret != self.config.server.cluster_secret {
+            report.push(String::from("restart:server.cluster_secret"));
+            config.server.cluster_secret = self.config.server.cluster_secret.clone();
+        }
+
+        if config.client.servers.len() != self.config.client.servers.len() ||
+           config.client.servers.iter().zip(self.config.client.servers.iter()).any(|(a, b)| a.addr != b.addr || a.weight != b.weight) {
+            report.push(String::from("client:client.servers"));
+        }
+        if config.client.virtual_nodes != self.config.client.virtual_nodes {
+            report.push(String::from("client:client.virtual_nodes"));
+        }
+
+        // The same certificate read from another path is reported even though nothing changes
+        if cert != self.cert || config.server.cert_file != self.config.server.cert_file {
+            report.push(String::from("applied:server.cert_file"));
+        }
+        if config.server.cert_password != self.config.server.cert_password {
+            report.push(String::from("applied:server.cert_password"));
+        }
+        if let Some(proto) = proto {
+            if config.server.max_request_bytes != self.config.server.max_request_bytes {
+                report.push(String::from("applied:server.max_request_bytes"));
+            }
+            self.proto = Arc::new(proto);
+            self.cert = cert;
+        }
+        if config.server.log_level != self.config.server.log_level {
+            self.log.set_filters(config.server.log_level.as_ref().map(String::as_str));
+            report.push(String::from("applied:server.log_level"));
+        }
+        if config.server.keyspace_events != self.config.server.keyspace_events {
+            self.cache.lock().unwrap().keyspace_events = config.server.keyspace_events;
+            report.push(String::from("applied:server.keyspace_events"));
+        }
+        if config.server.sync_writes != self.config.server.sync_writes {
+            self.cache.lock().unwrap().sync_writes = config.server.sync_writes;
+            report.push(String::from("applied:server.sync_writes"));
+        }
+        if config.server.replication_backlog_bytes != self.config.server.replication_backlog_bytes {
+            self.replication.lock().unwrap().set_backlog_limit(config.server.replication_backlog_bytes);
+            report.push(String::from("applied:server.replication_backlog_bytes"));
+        }
+        if config.server.max_connections != self.config.server.max_connections {
+            report.push(String::from("applied:server.max_connections"));
+        }
+        if config.server.shutdown_timeout_secs != self.config.server.shutdown_timeout_secs {
+            report.push(String::from("applied:server.shutdown_timeout_secs"));
+        }
+        self.config = config;
+
+        info!("Reloaded {}: {}", self.file, if report.is_empty() { String::from("no changes") } else { report.join(", ") });
+        return Ok(report);
+    }
+}
+
+fn read_cert(dir: &Path, config: &Config) -> Result<Vec<u8>, RemError> {
+    let mut file = try!(File::open(dir.join(&config.server.cert_file)));
+    let mut cert = vec![];
+    try!(file.read_to_end(&mut cert));
+    return Ok(cert);
+}
+
+fn tls_proto(cert: &[u8], config: &Config) -> Result<Server<CacheProto>, RemError> {
+    let pkcs12 = try!(Pkcs12::from_der(cert, &config.server.cert_password));
+    let acceptor = try!(try!(TlsAcceptor::builder(pkcs12)).build());
+    return Ok(Server::new(CacheProto{ max_request_bytes: config.server.max_request_bytes }, acceptor));
+}
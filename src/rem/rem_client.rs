@@ -0,0 +1,658 @@
+use std::string::String;
+use std::vec::Vec;
+
+use rem::tcp_stream::TcpStream;
+use rem::config::Config;
+use rem::client::{build_request, build_list_request};
+use rem::op;
+use rem::value;
+use rem::service::ERROR;
+use rem::gossip::{self, MemberState};
+use rem::error::*;
+
+/// The most times a request is redirected to another node before giving up
+pub const MAX_REDIRECTS: usize = 3;
+
+/// A client for using a REM server from code rather than from the command line
+///
+/// Every method sends a single request and waits for its response.
+/// Errors reported by the server are returned as a RemError containing the server's description
+///
+/// When a node in a cluster responds that it isn't the leader the client connects to the
+/// leader it names and sends the request again. The same happens when a node running with
+/// slots responds that another node owns the key's slot, while a slot is migrated a request
+/// for a key that has already moved is sent to the new node without reconnecting
+pub struct RemClient {
+    config: Config,
+    stream: TcpStream,
+    /// True while the server is pushing messages for a subscription
+    subscribed: bool,
+    /// The number of responses held back by the server until the subscription ends
+    deferred: usize
+}
+
+/// A message pushed by the server to a subscribed client
+#[derive(Debug, Clone)]
+pub enum PushedMessage {
+    /// Confirms a subscription to a channel or pattern, holds the number of subscriptions
+    SUBSCRIBE(String, usize),
+    /// Confirms an unsubscription, the subscription has ended when the count is 0
+    UNSUBSCRIBE(String, usize),
+    /// A message published to a subscribed channel, holds the channel and the message
+    MESSAGE(String, Vec<u8>),
+    /// A message published to a channel matching a subscribed pattern, holds the pattern,
+    /// the channel and the message
+    PMESSAGE(String, String, Vec<u8>),
+}
+
+impl RemClient {
+    pub fn connect(config: &Config, addr: &str) -> Result<RemClient, RemError> {
+        let stream = try!(TcpStream::connect(config, addr));
+        return Ok(RemClient {
+            config: config.clone(),
+            stream: stream,
+            subscribed: false,
+            deferred: 0
+        });
+    }
+
+    /// Writes the value to the cache under key
+    pub fn write(&mut self, key: &str, val: &[u8]) -> Result<(), RemError> {
+        try!(self.request(&build_request("W", &[key.as_bytes(), val])));
+        return Ok(());
+    }
+
+    /// Reads the value stored under key
+    pub fn read(&mut self, key: &str) -> Result<Vec<u8>, RemError> {
+        return self.request(&build_request("R", &[key.as_bytes()]));
+    }
+
+    /// Deletes the value stored under key
+    pub fn delete(&mut self, key: &str) -> Result<(), RemError> {
+        try!(self.request(&build_request("D", &[key.as_bytes()])));
+        return Ok(());
+    }
+
+    /// Returns every key starting with prefix
+    pub fn keys_with_prefix(&mut self, prefix: &str) -> Result<Vec<String>, RemError> {
+        let res = try!(self.request(&build_request("K", &[prefix.as_bytes()])));
+        return op::decode_keys(&res);
+    }
+
+    /// Adds 1 to the integer stored under key and returns the new value
+    pub fn incr(&mut self, key: &str) -> Result<i64, RemError> {
+        let res = try!(self.request(&build_request("INCR", &[key.as_bytes()])));
+        return op::parse_integer(&res);
+    }
+
+    /// Subtracts 1 from the integer stored under key and returns the new value
+    pub fn decr(&mut self, key: &str) -> Result<i64, RemError> {
+        let res = try!(self.request(&build_request("DECR", &[key.as_bytes()])));
+        return op::parse_integer(&res);
+    }
+
+    /// Adds delta to the integer stored under key and returns the new value
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, RemError> {
+        let delta_str = delta.to_string();
+        let res = try!(self.request(&build_request("INCRBY", &[key.as_bytes(), delta_str.as_bytes()])));
+        return op::parse_integer(&res);
+    }
+
+    /// Reads the value stored under key along with its version
+    pub fn read_with_version(&mut self, key: &str) -> Result<(u64, Vec<u8>), RemError> {
+        let res = try!(self.request(&build_request("RV", &[key.as_bytes()])));
+        let (version, val) = op::split_arg(&res);
+        return Ok((try!(parse_version(version)), val.to_vec()));
+    }
+
+    /// Writes the value only if key has no value and returns the new version
+    pub fn set_if_absent(&mut self, key: &str, val: &[u8]) -> Result<u64, RemError> {
+        let res = try!(self.request(&build_request("SETNX", &[key.as_bytes(), val])));
+        return parse_version(&res);
+    }
+
+    /// Writes the value only if key already has a value and returns the new version
+    pub fn set_if_present(&mut self, key: &str, val: &[u8]) -> Result<u64, RemError> {
+        let res = try!(self.request(&build_request("SETXX", &[key.as_bytes(), val])));
+        return parse_version(&res);
+    }
+
+    /// Writes the value only if the current version of key is version and returns the new version
+    pub fn set_if_version(&mut self, key: &str, version: u64, val: &[u8]) -> Result<u64, RemError> {
+        let version_str = version.to_string();
+        let res = try!(self.request(&build_request("SETV",
+                                                   &[key.as_bytes(), version_str.as_bytes(), val])));
+        return parse_version(&res);
+    }
+
+    /// Reads every key, returning a result for each key in the same order
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Result<Vec<u8>, RemError>>, RemError> {
+        let items: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        let results = try!(self.list_request(&build_list_request("MGET", &items)));
+        return Ok(results);
+    }
+
+    /// Writes every key value pair, returning a result for each pair in the same order
+    pub fn mset(&mut self, pairs: &[(&str, &[u8])]) -> Result<Vec<Result<(), RemError>>, RemError> {
+        let mut items: Vec<Vec<u8>> = Vec::new();
+        for &(key, val) in pairs {
+            items.push(key.as_bytes().to_vec());
+            items.push(val.to_vec());
+        }
+        let results = try!(self.list_request(&build_list_request("MSET", &items)));
+        return Ok(results.into_iter().map(|res| res.map(|_| ())).collect());
+    }
+
+    /// Deletes every key, returning whether each key had a value in the same order
+    pub fn mdel(&mut self, keys: &[&str]) -> Result<Vec<Result<bool, RemError>>, RemError> {
+        let items: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        let results = try!(self.list_request(&build_list_request("MDEL", &items)));
+        return Ok(results.into_iter().map(|res| res.map(|existed| existed == b"1")).collect());
+    }
+
+    /// Returns the next cursor and up to count keys matching the glob pattern
+    ///
+    /// Pass 0 as the cursor to start a scan, the scan is complete when 0 is returned
+    pub fn scan(&mut self,
+                cursor: &str,
+                pattern: &str,
+                count: usize)
+                -> Result<(String, Vec<String>), RemError> {
+        let items: Vec<Vec<u8>> = vec![cursor.as_bytes().to_vec(),
+                                       pattern.as_bytes().to_vec(),
+                                       count.to_string().into_bytes()];
+        let res = try!(self.request(&build_list_request("SCAN", &items)));
+        let mut keys = try!(op::decode_keys(&res)).into_iter();
+        let next = keys.next().unwrap_or(String::from(op::SCAN_START));
+        return Ok((next, keys.collect()));
+    }
+
+    /// Returns every key matching the glob pattern
+    pub fn keys(&mut self, pattern: &str) -> Result<Vec<String>, RemError> {
+        let res = try!(self.request(&build_request("KEYS", &[pattern.as_bytes()])));
+        return op::decode_keys(&res);
+    }
+
+    /// Deletes every key starting with prefix and returns the number of keys deleted
+    pub fn delete_prefix(&mut self, prefix: &str) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("DELPREFIX", &[prefix.as_bytes()])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Deletes every key matching the glob pattern and returns the number of keys deleted
+    pub fn delete_matching(&mut self, pattern: &str) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("DELMATCH", &[pattern.as_bytes()])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Returns the number of keys in the cache
+    pub fn dbsize(&mut self) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("DBSIZE", &[])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Pushes the values onto the start of the list stored under key and returns the new length
+    pub fn lpush(&mut self, key: &str, vals: &[&[u8]]) -> Result<usize, RemError> {
+        return self.count_request("LPUSH", key, vals);
+    }
+
+    /// Pushes the values onto the end of the list stored under key and returns the new length
+    pub fn rpush(&mut self, key: &str, vals: &[&[u8]]) -> Result<usize, RemError> {
+        return self.count_request("RPUSH", key, vals);
+    }
+
+    /// Removes and returns the first element of the list stored under key
+    pub fn lpop(&mut self, key: &str) -> Result<Vec<u8>, RemError> {
+        return self.request(&build_request("LPOP", &[key.as_bytes()]));
+    }
+
+    /// Removes and returns the last element of the list stored under key
+    pub fn rpop(&mut self, key: &str) -> Result<Vec<u8>, RemError> {
+        return self.request(&build_request("RPOP", &[key.as_bytes()]));
+    }
+
+    /// Removes and returns the first element of the list, waiting up to timeout seconds for
+    /// one to be pushed if the list is empty. A timeout of 0 waits forever
+    pub fn blpop(&mut self, key: &str, timeout: u64) -> Result<Vec<u8>, RemError> {
+        let timeout_str = timeout.to_string();
+        return self.request(&build_request("BLPOP", &[key.as_bytes(), timeout_str.as_bytes()]));
+    }
+
+    /// Removes and returns the last element of the list, waiting up to timeout seconds for
+    /// one to be pushed if the list is empty. A timeout of 0 waits forever
+    pub fn brpop(&mut self, key: &str, timeout: u64) -> Result<Vec<u8>, RemError> {
+        let timeout_str = timeout.to_string();
+        return self.request(&build_request("BRPOP", &[key.as_bytes(), timeout_str.as_bytes()]));
+    }
+
+    /// Returns the elements of the list from start to stop inclusive, -1 is the last element
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, RemError> {
+        let start_str = start.to_string();
+        let stop_str = stop.to_string();
+        let res = try!(self.request(&build_request("LRANGE",
+                                                   &[key.as_bytes(), start_str.as_bytes(), stop_str.as_bytes()])));
+        return op::decode_list(&res);
+    }
+
+    /// Returns the length of the list stored under key
+    pub fn llen(&mut self, key: &str) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("LLEN", &[key.as_bytes()])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Sets each field of the hash stored under key and returns the number of fields added
+    pub fn hset(&mut self, key: &str, pairs: &[(&[u8], &[u8])]) -> Result<usize, RemError> {
+        let mut items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec()];
+        for &(field, val) in pairs {
+            items.push(field.to_vec());
+            items.push(val.to_vec());
+        }
+        let res = try!(self.request(&build_list_request("HSET", &items)));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Reads a field of the hash stored under key
+    pub fn hget(&mut self, key: &str, field: &[u8]) -> Result<Vec<u8>, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(), field.to_vec()];
+        return self.request(&build_list_request("HGET", &items));
+    }
+
+    /// Deletes the fields of the hash stored under key and returns the number deleted
+    pub fn hdel(&mut self, key: &str, fields: &[&[u8]]) -> Result<usize, RemError> {
+        return self.count_request("HDEL", key, fields);
+    }
+
+    /// Returns every field of the hash stored under key with its value, sorted by field
+    pub fn hgetall(&mut self, key: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, RemError> {
+        let res = try!(self.request(&build_request("HGETALL", &[key.as_bytes()])));
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut iter = try!(op::decode_list(&res)).into_iter();
+        while let (Some(field), Some(val)) = (iter.next(), iter.next()) {
+            pairs.push((field, val));
+        }
+        return Ok(pairs);
+    }
+
+    /// Adds delta to the integer stored in a field of the hash and returns the new value
+    pub fn hincrby(&mut self, key: &str, field: &[u8], delta: i64) -> Result<i64, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(),
+                                       field.to_vec(),
+                                       delta.to_string().into_bytes()];
+        let res = try!(self.request(&build_list_request("HINCRBY", &items)));
+        return op::parse_integer(&res);
+    }
+
+    /// Adds the members to the set stored under key and returns the number added
+    pub fn sadd(&mut self, key: &str, members: &[&[u8]]) -> Result<usize, RemError> {
+        return self.count_request("SADD", key, members);
+    }
+
+    /// Removes the members from the set stored under key and returns the number removed
+    pub fn srem(&mut self, key: &str, members: &[&[u8]]) -> Result<usize, RemError> {
+        return self.count_request("SREM", key, members);
+    }
+
+    /// Checks if member is in the set stored under key
+    pub fn sismember(&mut self, key: &str, member: &[u8]) -> Result<bool, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(), member.to_vec()];
+        let res = try!(self.request(&build_list_request("SISMEMBER", &items)));
+        return Ok(res == b"1");
+    }
+
+    /// Returns every member of the set stored under key, sorted
+    pub fn smembers(&mut self, key: &str) -> Result<Vec<Vec<u8>>, RemError> {
+        let res = try!(self.request(&build_request("SMEMBERS", &[key.as_bytes()])));
+        return op::decode_list(&res);
+    }
+
+    /// Returns the members found in every one of the sets, sorted
+    pub fn sinter(&mut self, keys: &[&str]) -> Result<Vec<Vec<u8>>, RemError> {
+        let items: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        let res = try!(self.request(&build_list_request("SINTER", &items)));
+        return op::decode_list(&res);
+    }
+
+    /// Adds the members with their scores to the sorted set stored under key and returns the
+    /// number of members that were new
+    pub fn zadd(&mut self, key: &str, scored: &[(f64, &[u8])]) -> Result<usize, RemError> {
+        let mut items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec()];
+        for &(score, member) in scored {
+            items.push(value::format_score(score).into_bytes());
+            items.push(member.to_vec());
+        }
+        let res = try!(self.request(&build_list_request("ZADD", &items)));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Returns the members ranked start to stop by score along with their scores
+    pub fn zrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<(Vec<u8>, f64)>, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(),
+                                       start.to_string().into_bytes(),
+                                       stop.to_string().into_bytes()];
+        let res = try!(self.request(&build_list_request("ZRANGE", &items)));
+        return decode_scored(&res);
+    }
+
+    /// Returns the members with a score from min to max inclusive along with their scores
+    pub fn zrange_by_score(&mut self, key: &str, min: f64, max: f64) -> Result<Vec<(Vec<u8>, f64)>, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(),
+                                       value::format_score(min).into_bytes(),
+                                       value::format_score(max).into_bytes()];
+        let res = try!(self.request(&build_list_request("ZRANGEBYSCORE", &items)));
+        return decode_scored(&res);
+    }
+
+    /// Adds delta to the score of member in the sorted set and returns the new score
+    pub fn zincrby(&mut self, key: &str, delta: f64, member: &[u8]) -> Result<f64, RemError> {
+        let items: Vec<Vec<u8>> = vec![key.as_bytes().to_vec(),
+                                       value::format_score(delta).into_bytes(),
+                                       member.to_vec()];
+        let res = try!(self.request(&build_list_request("ZINCRBY", &items)));
+        return value::parse_score(&res);
+    }
+
+    /// Returns the number of keys and bytes held in memory for each type of value
+    pub fn memory(&mut self) -> Result<Vec<(String, usize, usize)>, RemError> {
+        let res = try!(self.request(&build_request("MEMORY", &[])));
+        let mut usage: Vec<(String, usize, usize)> = Vec::new();
+        for item in try!(op::decode_keys(&res)) {
+            let parts: Vec<&str> = item.split(':').collect();
+            if parts.len() != 3 {
+                return Err(RemError::with_reason_str(REM_00015));
+            }
+            let keys = try!(op::parse_integer(parts[1].as_bytes())) as usize;
+            let bytes = try!(op::parse_integer(parts[2].as_bytes())) as usize;
+            usage.push((String::from(parts[0]), keys, bytes));
+        }
+        return Ok(usage);
+    }
+
+    /// Returns the replication state of the server as ```(name, value)``` pairs
+    /// ex: ("role", "primary"), ("offset", "42")
+    pub fn replication_info(&mut self) -> Result<Vec<(String, String)>, RemError> {
+        return self.info_request("REPLICATION", &[]);
+    }
+
+    /// Returns the state of the node in its cluster as ```(name, value)``` pairs
+    /// ex: ("role", "leader"), ("term", "3")
+    pub fn raft_info(&mut self) -> Result<Vec<(String, String)>, RemError> {
+        return self.info_request("RAFT", &[]);
+    }
+
+    /// Repairs a replica so its keys match its primary and returns a report as ```(name, value)```
+    /// pairs, the fixed and deleted counts followed by a pair for each key that was changed
+    /// ex: ("fixed", "2"), ("deleted", "0"), ("fixed", "user:1"), ("fixed", "user:7")
+    pub fn repair(&mut self) -> Result<Vec<(String, String)>, RemError> {
+        return self.info_request("REPAIR", &[]);
+    }
+
+    /// Reloads the server's config file and returns the settings that changed as ```(name, value)```
+    /// pairs, applied for a setting now in use and restart for one that needs a restart
+    /// ex: ("applied", "server.log_level"), ("restart", "ssl")
+    pub fn reload_config(&mut self) -> Result<Vec<(String, String)>, RemError> {
+        return self.info_request("CONFIG", &[b"RELOAD"]);
+    }
+
+    /// Returns every server known to a server running with gossip with its state and incarnation
+    pub fn members(&mut self) -> Result<Vec<(String, MemberState, u64)>, RemError> {
+        let res = try!(self.request(&build_request("MEMBERS", &[])));
+        let mut members: Vec<(String, MemberState, u64)> = Vec::new();
+        for item in try!(op::decode_keys(&res)) {
+            members.push(try!(gossip::decode_member(item.as_str())));
+        }
+        return Ok(members);
+    }
+
+    /// Returns the slots of a server running with slots as ```(slots, node)``` pairs
+    /// ex: ("0-8191", "127.0.0.1:8080"), ("migrating", "42:127.0.0.1:8081")
+    pub fn slots(&mut self) -> Result<Vec<(String, String)>, RemError> {
+        return self.info_request("SLOTS", &[]);
+    }
+
+    /// Assigns the slots from first to last to the node at addr
+    pub fn assign_slots(&mut self, first: usize, last: usize, addr: &str) -> Result<(), RemError> {
+        try!(self.request(&build_request("SLOTSET",
+                                         &[first.to_string().as_bytes(),
+                                           last.to_string().as_bytes(),
+                                           addr.as_bytes()])));
+        return Ok(());
+    }
+
+    /// Moves every key in the slot to the node at addr and hands the slot over to it,
+    /// returns the number of keys moved
+    pub fn migrate(&mut self, slot: usize, addr: &str) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("MIGRATE", &[slot.to_string().as_bytes(), addr.as_bytes()])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Publishes a message to a channel and returns the number of subscribers it was sent to
+    pub fn publish(&mut self, channel: &str, message: &[u8]) -> Result<usize, RemError> {
+        let res = try!(self.request(&build_request("PUBLISH", &[channel.as_bytes(), message])));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Subscribes to the channels, the messages published to them are read with next_message
+    ///
+    /// While subscribed only the subscribe and unsubscribe methods can be used
+    pub fn subscribe(&mut self, channels: &[&str]) -> Result<(), RemError> {
+        return self.subscription_request("SUBSCRIBE", channels);
+    }
+
+    /// Subscribes to every channel matching the glob patterns
+    pub fn psubscribe(&mut self, patterns: &[&str]) -> Result<(), RemError> {
+        return self.subscription_request("PSUBSCRIBE", patterns);
+    }
+
+    /// Unsubscribes from the channels, or every channel if none are given
+    pub fn unsubscribe(&mut self, channels: &[&str]) -> Result<(), RemError> {
+        return self.subscription_request("UNSUBSCRIBE", channels);
+    }
+
+    /// Unsubscribes from the patterns, or every pattern if none are given
+    pub fn punsubscribe(&mut self, patterns: &[&str]) -> Result<(), RemError> {
+        return self.subscription_request("PUNSUBSCRIBE", patterns);
+    }
+
+    /// Waits for the next message pushed by the server while subscribed
+    ///
+    /// The subscription ends once an UNSUBSCRIBE message with a count of 0 is returned,
+    /// after that the client can be used for other requests again
+    pub fn next_message(&mut self) -> Result<PushedMessage, RemError> {
+        if !self.subscribed {
+            return Err(RemError::with_reason_str(REM_00029));
+        }
+        let pushed = try!(op::bytes_from_stream(&mut self.stream));
+        let items = try!(op::decode_list(&pushed));
+        if items.is_empty() {
+            return Err(RemError::with_reason_str(REM_00015));
+        }
+        let text = |idx: usize| String::from_utf8_lossy(&items[idx]).into_owned();
+        let message = match (text(0).as_str(), items.len()) {
+            ("subscribe", 3) | ("psubscribe", 3) => {
+                PushedMessage::SUBSCRIBE(text(1), try!(op::parse_integer(&items[2])) as usize)
+            }
+            ("unsubscribe", 3) | ("punsubscribe", 3) => {
+                PushedMessage::UNSUBSCRIBE(text(1), try!(op::parse_integer(&items[2])) as usize)
+            }
+            ("message", 3) => PushedMessage::MESSAGE(text(1), items[2].clone()),
+            ("pmessage", 4) => PushedMessage::PMESSAGE(text(1), text(2), items[3].clone()),
+            _ => return Err(RemError::with_reason_str(REM_00015)),
+        };
+        if let PushedMessage::UNSUBSCRIBE(_, 0) = message {
+            try!(self.end_subscription());
+        }
+        return Ok(message);
+    }
+
+    /// Starts a transaction, requests sent until exec are queued by the server
+    ///
+    /// While a transaction is open every other method returns the QUEUED response rather than
+    /// its result, use exec to get the results
+    pub fn multi(&mut self) -> Result<(), RemError> {
+        try!(self.request(&build_request("MULTI", &[])));
+        return Ok(());
+    }
+
+    /// Runs the queued requests, returning the raw result of each one in the order they were sent
+    pub fn exec(&mut self) -> Result<Vec<Result<Vec<u8>, RemError>>, RemError> {
+        return self.list_request(&build_request("EXEC", &[]));
+    }
+
+    /// Drops the queued requests and ends the transaction
+    pub fn discard(&mut self) -> Result<(), RemError> {
+        try!(self.request(&build_request("DISCARD", &[])));
+        return Ok(());
+    }
+
+    /// Makes the next exec fail if any of the keys change before it runs
+    pub fn watch(&mut self, keys: &[&str]) -> Result<(), RemError> {
+        let items: Vec<Vec<u8>> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+        try!(self.request(&build_list_request("WATCH", &items)));
+        return Ok(());
+    }
+
+    /// Stops watching every key
+    pub fn unwatch(&mut self) -> Result<(), RemError> {
+        try!(self.request(&build_request("UNWATCH", &[])));
+        return Ok(());
+    }
+
+    /// Closes the connection to the server
+    pub fn close(self) -> Result<(), RemError> {
+        return self.stream.close();
+    }
+
+    /// Sends a subscribe or unsubscribe request
+    ///
+    /// Only the response to the request that starts a subscription is sent straight away,
+    /// the responses to requests made while subscribed are sent once the subscription ends
+    fn subscription_request(&mut self, command: &str, names: &[&str]) -> Result<(), RemError> {
+        let items: Vec<Vec<u8>> = names.iter().map(|name| name.as_bytes().to_vec()).collect();
+        let req = build_list_request(command, &items);
+        if self.subscribed {
+            try!(op::write_bytes_to_stream_with_size(&mut self.stream, &req));
+            self.deferred += 1;
+            return Ok(());
+        }
+        try!(self.request(&req));
+        self.subscribed = !command.ends_with("UNSUBSCRIBE");
+        return Ok(());
+    }
+
+    /// Reads the end of the pushed messages and the responses that were held back
+    fn end_subscription(&mut self) -> Result<(), RemError> {
+        let end = try!(op::bytes_from_stream(&mut self.stream));
+        if !end.is_empty() {
+            return Err(RemError::with_reason_str(REM_00015));
+        }
+        self.subscribed = false;
+        while self.deferred > 0 {
+            self.deferred -= 1;
+            try!(result_from_bytes(try!(op::bytes_from_stream(&mut self.stream))));
+        }
+        return Ok(());
+    }
+
+    /// Sends a key followed by items and parses the count that is returned
+    fn count_request(&mut self, command: &str, key: &str, items: &[&[u8]]) -> Result<usize, RemError> {
+        let mut list: Vec<Vec<u8>> = vec![key.as_bytes().to_vec()];
+        list.extend(items.iter().map(|item| item.to_vec()));
+        let res = try!(self.request(&build_list_request(command, &list)));
+        return Ok(try!(op::parse_integer(&res)) as usize);
+    }
+
+    /// Sends a request and returns the response, converting error responses to a RemError
+    fn request(&mut self, req: &[u8]) -> Result<Vec<u8>, RemError> {
+        if self.subscribed {
+            return Err(RemError::with_reason_str(REM_00028));
+        }
+        let mut redirects = 0;
+        loop {
+            try!(op::write_bytes_to_stream_with_size(&mut self.stream, req));
+            let res = try!(op::bytes_from_stream(&mut self.stream));
+            match redirect_from_bytes(&res) {
+                Some(Redirect::MOVED(addr)) if redirects < MAX_REDIRECTS => {
+                    self.stream = try!(TcpStream::connect(&self.config, addr.as_str()));
+                    redirects += 1;
+                }
+                Some(Redirect::ASK(addr)) if redirects < MAX_REDIRECTS => {
+                    // The slot still belongs to this node, only this request goes to the other one
+                    let mut stream = try!(TcpStream::connect(&self.config, addr.as_str()));
+                    try!(op::send_request(&mut stream, "ASKING", &[]));
+                    try!(op::write_bytes_to_stream_with_size(&mut stream, req));
+                    return result_from_bytes(try!(op::bytes_from_stream(&mut stream)));
+                }
+                _ => return result_from_bytes(res),
+            }
+        }
+    }
+
+    /// Sends a request for an info command, each item of the response is ```{name}:{value}```
+    fn info_request(&mut self, command: &str, parts: &[&[u8]]) -> Result<Vec<(String, String)>, RemError> {
+        let res = try!(self.request(&build_request(command, parts)));
+        let mut info: Vec<(String, String)> = Vec::new();
+        for item in try!(op::decode_keys(&res)) {
+            match item.find(':') {
+                Some(idx) => info.push((String::from(&item[..idx]), String::from(&item[idx + 1..]))),
+                None => return Err(RemError::with_reason_str(REM_00015)),
+            }
+        }
+        return Ok(info);
+    }
+
+    /// Sends a request for a multi key command and returns the result for each key
+    fn list_request(&mut self, req: &[u8]) -> Result<Vec<Result<Vec<u8>, RemError>>, RemError> {
+        let res = try!(self.request(req));
+        let items = try!(op::decode_list(&res));
+        return Ok(items.into_iter().map(result_from_bytes).collect());
+    }
+}
+
+/// Where a response sends a request instead of the node it was sent to
+enum Redirect {
+    /// The leader of the cluster or the owner of the key's slot, requests go there from now on
+    MOVED(String),
+    /// The node a migrating slot's key has moved to, only this request goes there
+    ASK(String),
+}
+
+/// Returns where the response redirects the request to, if it does
+///
+/// A redirect to the leader ends with its address and a redirect for a slot ends with the
+/// slot and the address of its node
+fn redirect_from_bytes(res: &[u8]) -> Option<Redirect> {
+    let desc = String::from_utf8_lossy(res);
+    let addr = || desc.split_whitespace().last().map(String::from);
+    if desc.starts_with(&format!("{}:{} ", ERROR, REM_00034)) ||
+       desc.starts_with(&format!("{}:{} ", ERROR, REM_00042)) {
+        return addr().map(Redirect::MOVED);
+    }
+    if desc.starts_with(&format!("{}:{} ", ERROR, REM_00043)) {
+        return addr().map(Redirect::ASK);
+    }
+    return None;
+}
+
+/// Converts a response into a result, ```ERROR:{description}``` becomes a RemError
+fn result_from_bytes(res: Vec<u8>) -> Result<Vec<u8>, RemError> {
+    if res.starts_with(ERROR.as_bytes()) {
+        let desc = String::from_utf8_lossy(&res[ERROR.len()..]).into_owned();
+        return Err(RemError::with_reason(String::from(desc.trim_left_matches(':'))));
+    }
+    return Ok(res);
+}
+
+/// Decodes a list of alternating sorted set members and scores
+fn decode_scored(bytes: &[u8]) -> Result<Vec<(Vec<u8>, f64)>, RemError> {
+    let mut members: Vec<(Vec<u8>, f64)> = Vec::new();
+    let mut iter = try!(op::decode_list(bytes)).into_iter();
+    while let (Some(member), Some(score)) = (iter.next(), iter.next()) {
+        members.push((member, try!(value::parse_score(&score))));
+    }
+    return Ok(members);
+}
+
+fn parse_version(bytes: &[u8]) -> Result<u64, RemError> {
+    let version = try!(op::parse_integer(bytes));
+    return Ok(version as u64);
+}
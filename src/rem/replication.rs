@@ -0,0 +1,500 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use futures::Stream;
+use futures::stream;
+use futures::sync::mpsc;
+
+use rem::cache::Cache;
+use rem::config::Config;
+use rem::op;
+use rem::pubsub::{PubSub, PushStream};
+use rem::service;
+use rem::tcp_stream::TcpStream;
+use rem::value::Value;
+use rem::error::*;
+
+/// The number of changes buffered for a replica, a replica that falls further behind than this
+/// is disconnected and has to sync again
+pub const REPLICA_BUFFER: usize = 10000;
+
+/// How often a replica reports its offset to the primary
+pub const ACK_INTERVAL_SECS: u64 = 1;
+
+/// How long a replica waits before reconnecting to its primary
+pub const RECONNECT_INTERVAL_SECS: u64 = 1;
+
+/// The start of the response to a SYNC that copies the whole cache, followed by the replica id,
+/// the run id of the primary and the offset of the snapshot
+/// ex: FULLSYNC:0:5a0c3f1e2b:15
+pub const FULLSYNC: &'static str = "FULLSYNC";
+
+/// The start of the response to a SYNC that continues from the replica's offset using the
+/// backlog, followed by the replica id and the offset the changes start after
+/// ex: CONTINUE:1:15
+pub const CONTINUE: &'static str = "CONTINUE";
+
+/// A change to a single key, the value is None when the key was deleted
+pub type Change = (String, Option<Value>);
+
+/// A replica connected to this server
+struct ReplicaLink {
+    sender: mpsc::Sender<Result<Vec<u8>, io::Error>>,
+    /// The offset the replica last reported having applied
+    acked: u64,
+    last_ack: Option<Instant>
+}
+
+/// The replication state of the server
+///
+/// A primary numbers every change made to the cache, the number of the latest change is its
+/// offset. Each change is streamed to the connected replicas which apply it and report the
+/// offset they have reached, the difference between the two offsets is the replica's lag
+///
+/// Offsets are only meaningful for a single run of a primary, so each run is given a random run
+/// id. A replica that reconnects to the same run can continue from its offset as long as the
+/// changes after it are still in the backlog
+pub struct Replication {
+    /// Identifies this run of the server, offsets restart when the server does
+    pub run_id: String,
+    /// The address of the primary this server replicates, None when this server is a primary
+    pub primary: Option<String>,
+    /// The offset of the latest change made on a primary or applied on a replica
+    pub offset: u64,
+    next_id: u64,
+    replicas: HashMap<u64, ReplicaLink>,
+    /// The most recent changes and their offsets, oldest first. A primary keeps them so a
+    /// replica that reconnects can continue from its offset rather than copying the whole
+    /// cache again
+    backlog: VecDeque<(u64, Vec<u8>)>,
+    /// The size of the encoded changes in the backlog
+    backlog_bytes: usize,
+    /// The oldest changes are dropped from the backlog once it holds more bytes than this
+    backlog_limit: usize,
+    full_syncs: u64,
+    partial_syncs: u64,
+    /// The run id of the primary a replica last completed a full sync with, None until then
+    primary_run_id: Option<String>,
+    /// Whether a replica is currently connected to its primary
+    connected: bool,
+    /// The offset of the primary the last time a replica heard from it
+    primary_offset: u64,
+    last_contact: Option<Instant>,
+    /// The number of anti-entropy repairs a replica has run and the keys they changed
+    repairs: u64,
+    repaired_keys: u64
+}
+
+impl Replication {
+    pub fn new(primary: Option<String>, backlog_limit: usize) -> Replication {
+        return Replication {
+            run_id: new_run_id(),
+            primary: primary,
+            offset: 0,
+            next_id: 0,
+            replicas: HashMap::new(),
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            backlog_limit: backlog_limit,
+            full_syncs: 0,
+            partial_syncs: 0,
+            primary_run_id: None,
+            connected: false,
+            primary_offset: 0,
+            last_contact: None,
+            repairs: 0,
+            repaired_keys: 0
+        };
+    }
+
+    /// Replicas reject writes, they only change through the changes streamed by their primary
+    pub fn is_replica(&self) -> bool {
+        return self.primary.is_some();
+    }
+
+    /// Returns true if a replica is connected to its primary and has completed a full sync
+    pub fn is_synced(&self) -> bool {
+        return self.connected && self.primary_run_id.is_some();
+    }
+
+    /// Returns false while a replica doesn't hold a complete copy of its primary's cache, from the
+    /// start of a full sync until the primary says it is complete. Its keys are not served until then
+    pub fn has_full_copy(&self) -> bool {
+        return !self.is_replica() || self.primary_run_id.is_some();
+    }
+
+    /// Counts a repair of a replica and the number of keys it changed
+    pub fn record_repair(&mut self, keys: usize) {
+        self.repairs += 1;
+        self.repaired_keys += keys as u64;
+    }
+
+    /// Returns the changes made after the offset if the run id is this server's and every one
+    /// of them is still in the backlog
+    pub fn backlog_after(&self, run_id: &str, offset: u64) -> Option<Vec<Vec<u8>>> {
+        if run_id != self.run_id || offset > self.offset {
+            return None;
+        }
+        let oldest = self.backlog.front().map(|&(oldest, _)| oldest).unwrap_or(self.offset + 1);
+        // The change right after the offset has already been dropped from the backlog
+        if offset + 1 < oldest {
+            return None;
+        }
+        return Some(self.backlog
+            .iter()
+            .filter(|&&(change_offset, _)| change_offset > offset)
+            .map(|&(_, ref entry)| entry.clone())
+            .collect());
+    }
+
+    /// Registers a new replica and returns its id and the stream of changes sent to it
+    ///
+    /// The stream starts with the entries that bring the replica up to the current offset,
+    /// either a snapshot or the changes from the backlog. They must have been taken while the
+    /// replication lock was held so no change is missed or sent twice
+    pub fn add_replica(&mut self, entries: Vec<Vec<u8>>, partial: bool) -> (u64, PushStream) {
+        let (sender, receiver) = mpsc::channel(REPLICA_BUFFER);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.replicas.insert(id, ReplicaLink {
+            sender: sender,
+            acked: 0,
+            last_ack: None
+        });
+        if partial {
+            self.partial_syncs += 1;
+            info!("Replica {} continued from the backlog, {} changes behind", id, entries.len());
+        } else {
+            self.full_syncs += 1;
+            info!("Replica {} started a full sync at offset {}", id, self.offset);
+        }
+        let changes = receiver.then(|res| {
+            match res {
+                Ok(change) => change,
+                Err(()) => Err(io::Error::new(io::ErrorKind::Other, "Replication closed")),
+            }
+        });
+        return (id, Box::new(stream::iter_ok(entries).chain(changes)));
+    }
+
+    /// Numbers each change, adds it to the backlog and streams it to every replica
+    ///
+    /// A replica whose buffer is full is dropped, ending its stream so that it syncs again
+    pub fn replicate(&mut self, changes: Vec<Change>) {
+        let mut dropped: Vec<u64> = Vec::new();
+        for (key, val) in changes {
+            self.offset += 1;
+            let entry = encode_change(self.offset, key.as_str(), val.as_ref());
+            for (id, replica) in self.replicas.iter_mut() {
+                if replica.sender.try_send(Ok(entry.clone())).is_err() && !dropped.contains(id) {
+                    dropped.push(*id);
+                }
+            }
+            self.backlog_bytes += entry.len();
+            self.backlog.push_back((self.offset, entry));
+        }
+        self.trim_backlog();
+        for id in dropped {
+            warn!("Replica {} fell behind or disconnected and was dropped", id);
+            self.replicas.remove(&id);
+        }
+    }
+
+    /// Changes the most bytes the backlog holds, dropping the oldest changes if it holds more
+    pub fn set_backlog_limit(&mut self, backlog_limit: usize) {
+        self.backlog_limit = backlog_limit;
+        self.trim_backlog();
+    }
+
+    fn trim_backlog(&mut self) {
+        while self.backlog_bytes > self.backlog_limit {
+            match self.backlog.pop_front() {
+                Some((_, entry)) => self.backlog_bytes -= entry.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Records the offset a replica has applied and returns the offset of this server
+    pub fn ack(&mut self, id: u64, offset: u64) -> u64 {
+        if let Some(replica) = self.replicas.get_mut(&id) {
+            replica.acked = offset;
+            replica.last_ack = Some(Instant::now());
+        }
+        return self.offset;
+    }
+
+    /// Describes the replication state, each item is ```{name}:{value}```
+    pub fn info(&self) -> Vec<String> {
+        let mut info: Vec<String> = Vec::new();
+        match self.primary {
+            Some(ref primary) => {
+                info.push(String::from("role:replica"));
+                info.push(format!("primary:{}", primary));
+                info.push(format!("connected:{}", self.connected));
+                info.push(format!("primary_run_id:{}", self.primary_run_id.clone().unwrap_or(String::new())));
+                info.push(format!("offset:{}", self.offset));
+                info.push(format!("primary_offset:{}", self.primary_offset));
+                info.push(format!("lag:{}", self.primary_offset.saturating_sub(self.offset)));
+                if let Some(last_contact) = self.last_contact {
+                    info.push(format!("last_contact_secs:{}", last_contact.elapsed().as_secs()));
+                }
+                info.push(format!("repairs:{}", self.repairs));
+                info.push(format!("repaired_keys:{}", self.repaired_keys));
+            }
+            None => {
+                info.push(String::from("role:primary"));
+                info.push(format!("run_id:{}", self.run_id));
+                info.push(format!("offset:{}", self.offset));
+                let oldest = self.backlog.front().map(|&(oldest, _)| oldest).unwrap_or(self.offset + 1);
+                info.push(format!("backlog_first_offset:{}", oldest));
+                info.push(format!("backlog_changes:{}", self.backlog.len()));
+                info.push(format!("backlog_bytes:{}", self.backlog_bytes));
+                info.push(format!("full_syncs:{}", self.full_syncs));
+                info.push(format!("partial_syncs:{}", self.partial_syncs));
+                info.push(format!("replicas:{}", self.replicas.len()));
+                let mut ids: Vec<&u64> = self.replicas.keys().collect();
+                ids.sort();
+                for id in ids {
+                    let replica = &self.replicas[id];
+                    let last_ack = replica.last_ack
+                        .map(|last_ack| last_ack.elapsed().as_secs().to_string())
+                        .unwrap_or(String::from("never"));
+                    info.push(format!("replica{}:offset={},lag={},last_ack_secs={}",
+                                      id,
+                                      replica.acked,
+                                      self.offset.saturating_sub(replica.acked),
+                                      last_ack));
+                }
+            }
+        }
+        return info;
+    }
+}
+
+/// An entry in the stream of changes sent to a replica, each holds its offset
+pub enum StreamEntry {
+    /// A key was written with the value
+    SET(u64, String, Value),
+    /// A key was deleted
+    DEL(u64, String),
+    /// Ends the snapshot of a full sync, holds the run id of the primary
+    SYNCED(u64, String),
+}
+
+impl StreamEntry {
+    /// Decodes an entry encoded by encode_change or snapshot
+    pub fn decode(bytes: &[u8]) -> Result<StreamEntry, RemError> {
+        let mut items = try!(op::decode_list(bytes)).into_iter();
+        let kind = items.next().unwrap_or(Vec::new());
+        let offset = try!(op::parse_integer(&items.next().unwrap_or(Vec::new()))) as u64;
+        let name = match String::from_utf8(items.next().unwrap_or(Vec::new())) {
+            Ok(name) => name,
+            Err(_) => return Err(RemError::with_reason_str(REM_00005)),
+        };
+        match kind.as_slice() {
+            b"set" => {
+                let type_name = String::from_utf8_lossy(&items.next().unwrap_or(Vec::new())).into_owned();
+                let val = try!(Value::decode(type_name.as_str(), items.next().unwrap_or(Vec::new())));
+                Ok(StreamEntry::SET(offset, name, val))
+            }
+            b"del" => Ok(StreamEntry::DEL(offset, name)),
+            b"synced" => Ok(StreamEntry::SYNCED(offset, name)),
+            _ => Err(RemError::with_reason_str(REM_00015)),
+        }
+    }
+
+    /// Makes the change to the cache and returns the offset of the entry
+    pub fn apply(self, cache: &mut Cache) -> Result<u64, RemError> {
+        match self {
+            StreamEntry::SET(offset, key, val) => {
+                try!(cache.cache_value(key.as_str(), val));
+                Ok(offset)
+            }
+            StreamEntry::DEL(offset, key) => {
+                try!(cache.delete_item(key));
+                Ok(offset)
+            }
+            StreamEntry::SYNCED(offset, _) => Ok(offset),
+        }
+    }
+}
+
+/// Encodes a change as it is sent to a replica
+///
+/// A write is ```[set, offset, key, type, value]``` and a delete is ```[del, offset, key]```
+pub fn encode_change(offset: u64, key: &str, val: Option<&Value>) -> Vec<u8> {
+    let offset_bytes = offset.to_string().into_bytes();
+    match val {
+        Some(val) => {
+            op::encode_list(&[b"set".to_vec(),
+                              offset_bytes,
+                              key.as_bytes().to_vec(),
+                              val.type_name().as_bytes().to_vec(),
+                              val.encode()])
+        }
+        None => op::encode_list(&[b"del".to_vec(), offset_bytes, key.as_bytes().to_vec()]),
+    }
+}
+
+/// Generates a run id from the time the server started, unique enough to tell runs apart
+fn new_run_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    return format!("{:x}{:08x}", now.as_secs(), now.subsec_nanos());
+}
+
+/// Encodes every key in the cache as changes at the offset, followed by
+/// ```[synced, offset, run id]```
+pub fn snapshot(cache: &Cache, offset: u64, run_id: &str) -> Result<Vec<Vec<u8>>, RemError> {
+    let mut entries: Vec<Vec<u8>> = Vec::new();
+    for key in try!(cache.keys()) {
+        if let Some(val) = try!(cache.read_value(key.as_str())) {
+            entries.push(encode_change(offset, key.as_str(), Some(&val)));
+        }
+    }
+    entries.push(op::encode_list(&[b"synced".to_vec(),
+                                   offset.to_string().into_bytes(),
+                                   run_id.as_bytes().to_vec()]));
+    return Ok(entries);
+}
+
+/// Starts the thread that keeps a replica in sync with its primary
+///
+/// The replica reconnects and syncs again whenever the connection to the primary is lost
+pub fn start_replica(config: Config,
+                     cache: Arc<Mutex<Cache>>,
+                     pubsub: Arc<Mutex<PubSub>>,
+                     replication: Arc<Mutex<Replication>>) {
+    thread::spawn(move || {
+        loop {
+            let primary = match replication.lock().unwrap().primary.clone() {
+                Some(primary) => primary,
+                None => return,
+            };
+            info!("Syncing with primary {}", primary);
+            if let Err(why) = sync_with_primary(&config, primary.as_str(), &cache, &pubsub, &replication) {
+                why.log();
+            }
+            replication.lock().unwrap().connected = false;
+            thread::sleep(Duration::from_secs(RECONNECT_INTERVAL_SECS));
+        }
+    });
+}
+
+/// Syncs with the primary then applies the changes streamed by it until the connection is lost
+///
+/// A replica that has completed a full sync asks to continue from its offset, the primary
+/// falls back to a full sync when its backlog no longer holds every change after that offset
+fn sync_with_primary(config: &Config,
+                     primary: &str,
+                     cache: &Arc<Mutex<Cache>>,
+                     pubsub: &Arc<Mutex<PubSub>>,
+                     replication: &Arc<Mutex<Replication>>)
+                     -> Result<(), RemError> {
+    let mut stream = try!(TcpStream::connect(config, primary));
+    let req = match *replication.lock().unwrap() {
+        Replication { primary_run_id: Some(ref run_id), offset, .. } => format!("SYNC${}:{}", run_id, offset),
+        _ => String::from("SYNC$"),
+    };
+    try!(op::write_bytes_to_stream_with_size(&mut stream, req.as_bytes()));
+    let head = try!(op::bytes_from_stream(&mut stream));
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let parts: Vec<&str> = head.split(':').collect();
+    let full = match (parts[0], parts.len()) {
+        (FULLSYNC, 4) => true,
+        (CONTINUE, 3) => false,
+        _ => return Err(RemError::with_reason(format!("{}: {}", REM_00030, head))),
+    };
+    let id = try!(op::parse_integer(parts[1].as_bytes())) as u64;
+    if full {
+        // Until the snapshot has been applied the replica can't continue from its offset and
+        // doesn't serve its keys
+        replication.lock().unwrap().primary_run_id = None;
+        // The snapshot replaces everything the replica had
+        let mut cache = cache.lock().unwrap();
+        try!(cache.clear());
+        service::publish_key_events(pubsub, &mut cache);
+    } else {
+        info!("Continuing from offset {}", parts[2]);
+    }
+    {
+        let mut replication = replication.lock().unwrap();
+        replication.connected = true;
+        replication.last_contact = Some(Instant::now());
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    start_acks(config.clone(), String::from(primary), id, replication.clone(), stop.clone());
+    let res = apply_changes(&mut stream, cache, pubsub, replication);
+    stop.store(true, Ordering::SeqCst);
+    return res;
+}
+
+/// Applies each change streamed by the primary, returning once the stream ends
+fn apply_changes(stream: &mut TcpStream,
+                 cache: &Arc<Mutex<Cache>>,
+                 pubsub: &Arc<Mutex<PubSub>>,
+                 replication: &Arc<Mutex<Replication>>)
+                 -> Result<(), RemError> {
+    loop {
+        let entry = try!(op::bytes_from_stream(stream));
+        // The primary ended the stream, usually because the replica fell too far behind
+        if entry.is_empty() {
+            return Err(RemError::with_reason_str(REM_00031));
+        }
+        let offset = match try!(StreamEntry::decode(&entry)) {
+            StreamEntry::SYNCED(offset, run_id) => {
+                info!("Full sync complete at offset {}", offset);
+                replication.lock().unwrap().primary_run_id = Some(run_id);
+                offset
+            }
+            change => {
+                let mut cache = cache.lock().unwrap();
+                let offset = try!(change.apply(&mut cache));
+                // Subscribers to a replica are notified of the keys changed by its primary
+                service::publish_key_events(pubsub, &mut cache);
+                offset
+            }
+        };
+        let mut replication = replication.lock().unwrap();
+        replication.offset = offset;
+        replication.last_contact = Some(Instant::now());
+    }
+}
+
+/// Starts the thread that reports the replica's offset to the primary on a second connection
+/// The primary responds with its own offset which is used to work out the replica's lag
+fn start_acks(config: Config,
+              primary: String,
+              id: u64,
+              replication: Arc<Mutex<Replication>>,
+              stop: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut stream = match TcpStream::connect(&config, primary.as_str()) {
+            Ok(stream) => stream,
+            Err(why) => return why.log(),
+        };
+        while !stop.load(Ordering::SeqCst) {
+            let offset = replication.lock().unwrap().offset;
+            let req = format!("REPLACK${}:{}", id, offset).into_bytes();
+            let res = op::write_bytes_to_stream_with_size(&mut stream, &req)
+                .and_then(|_| op::bytes_from_stream(&mut stream))
+                .and_then(|res| op::parse_integer(&res));
+            match res {
+                Ok(primary_offset) => {
+                    let mut replication = replication.lock().unwrap();
+                    replication.primary_offset = primary_offset as u64;
+                    replication.last_contact = Some(Instant::now());
+                }
+                Err(why) => return why.log(),
+            }
+            thread::sleep(Duration::from_secs(ACK_INTERVAL_SECS));
+        }
+    });
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::string::String;
+use std::vec::Vec;
+
+/// A consistent hash ring that assigns each key to one of several servers
+///
+/// Every server is placed on the ring at many points, virtual nodes, and a key belongs to the
+/// server at the first point at or after the key's hash. A server with a higher weight gets
+/// proportionally more points and so more keys. Adding or removing a server only moves the
+/// keys between its points and the points before them, every other key keeps its server
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    points: BTreeMap<u64, String>,
+    weights: BTreeMap<String, usize>,
+    virtual_nodes: usize
+}
+
+impl HashRing {
+    /// Creates an empty ring that places virtual_nodes points for each unit of weight
+    pub fn new(virtual_nodes: usize) -> HashRing {
+        return HashRing {
+            points: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            virtual_nodes: virtual_nodes
+        };
+    }
+
+    /// Adds a server, replacing its weight if it is already on the ring
+    pub fn add(&mut self, addr: &str, weight: usize) {
+        self.remove(addr);
+        for idx in 0..weight * self.virtual_nodes {
+            self.points.insert(hash(format!("{}#{}", addr, idx).as_bytes()), String::from(addr));
+        }
+        self.weights.insert(String::from(addr), weight);
+    }
+
+    /// Removes a server, its keys move to the servers after each of its points
+    pub fn remove(&mut self, addr: &str) {
+        if self.weights.remove(addr).is_none() {
+            return;
+        }
+        let points: Vec<u64> = self.points
+            .iter()
+            .filter(|&(_, owner)| owner == addr)
+            .map(|(point, _)| *point)
+            .collect();
+        for point in points {
+            self.points.remove(&point);
+        }
+    }
+
+    /// Returns the server the key belongs to, None when the ring is empty
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let point = hash(key.as_bytes());
+        return self.points
+            .range(point..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| addr.as_str());
+    }
+
+    /// Returns every server on the ring with its weight, sorted by address
+    pub fn nodes(&self) -> Vec<(String, usize)> {
+        return self.weights.iter().map(|(addr, weight)| (addr.clone(), *weight)).collect();
+    }
+}
+
+/// Hashes bytes to a point on the ring using 64 bit FNV-1a followed by the MurmurHash3
+/// finalizer, which spreads similar inputs such as the virtual nodes of a server evenly
+///
+/// The hash must be the same on every client so that they agree on where each key lives
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    return hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HashRing;
+
+    fn keys() -> Vec<String> {
+        return (0..10000).map(|idx| format!("key:{}", idx)).collect();
+    }
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        return keys().iter().map(|key| String::from(ring.node_for(key).unwrap())).collect();
+    }
+
+    #[test]
+    fn an_empty_ring_has_no_owner() {
+        let mut ring = HashRing::new(160);
+        assert_eq!(ring.node_for("abc"), None);
+        ring.add("a:1", 1);
+        ring.remove("a:1");
+        assert_eq!(ring.node_for("abc"), None);
+    }
+
+    #[test]
+    fn placement_is_the_same_for_every_ring_with_the_same_servers() {
+        let mut first = HashRing::new(160);
+        first.add("a:1", 1);
+        first.add("b:1", 1);
+        let mut second = HashRing::new(160);
+        second.add("b:1", 1);
+        second.add("a:1", 1);
+        assert_eq!(owners(&first), owners(&second));
+    }
+
+    #[test]
+    fn adding_a_server_only_moves_keys_to_it() {
+        let mut ring = HashRing::new(160);
+        ring.add("a:1", 1);
+        ring.add("b:1", 1);
+        let before = owners(&ring);
+        ring.add("c:1", 1);
+        let after = owners(&ring);
+        let mut moved = 0;
+        for (old, new) in before.iter().zip(after.iter()) {
+            if old != new {
+                assert_eq!(new, "c:1");
+                moved += 1;
+            }
+        }
+        // About a third of the keys move to the new server
+        assert!(moved > 2500 && moved < 4200, "{} keys moved", moved);
+    }
+
+    #[test]
+    fn removing_a_server_only_moves_its_keys() {
+        let mut ring = HashRing::new(160);
+        ring.add("a:1", 1);
+        ring.add("b:1", 1);
+        ring.add("c:1", 1);
+        let before = owners(&ring);
+        ring.remove("b:1");
+        let after = owners(&ring);
+        for (old, new) in before.iter().zip(after.iter()) {
+            if old != "b:1" {
+                assert_eq!(old, new);
+            } else {
+                assert!(new != "b:1");
+            }
+        }
+        assert_eq!(ring.nodes(), vec![(String::from("a:1"), 1), (String::from("c:1"), 1)]);
+    }
+
+    #[test]
+    fn keys_are_spread_by_weight() {
+        let mut ring = HashRing::new(160);
+        ring.add("a:1", 1);
+        ring.add("b:1", 2);
+        let heavy = owners(&ring).iter().filter(|owner| *owner == "b:1").count();
+        // Two thirds of the keys belong to the server with twice the weight
+        assert!(heavy > 6000 && heavy < 7300, "{} keys on the heavier server", heavy);
+        // Adding a server again replaces its weight
+        ring.add("b:1", 1);
+        assert_eq!(ring.nodes(), vec![(String::from("a:1"), 1), (String::from("b:1"), 1)]);
+        let even = owners(&ring).iter().filter(|owner| *owner == "b:1").count();
+        assert!(even > 4000 && even < 6000, "{} keys on an equal server", even);
+    }
+}
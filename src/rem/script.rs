@@ -0,0 +1,126 @@
+use std::io::prelude::*;
+use std::fs::File;
+use std::string::String;
+use std::vec::Vec;
+use std::error::Error;
+
+use rem::tcp_stream::TcpStream;
+use rem::client;
+use rem::op;
+use rem::output;
+use rem::output::{OutputFormat, Response};
+use rem::error::*;
+
+/// Options controlling how a script file is executed by the client
+pub struct ScriptOptions {
+    /// Path to the file containing one client command per line
+    pub file: String,
+    /// If false execution stops after the first failed command
+    pub continue_on_error: bool,
+    /// The number of requests sent before waiting for their responses
+    pub pipeline: usize,
+    /// The format responses are written to stdout in
+    pub output: OutputFormat,
+}
+
+/// Counts of the commands executed from a script
+pub struct ScriptSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl ScriptSummary {
+    pub fn total(&self) -> usize {
+        return self.succeeded + self.failed;
+    }
+
+    /// Prints the summary to stderr so stdout only contains responses
+    pub fn print(&self) {
+        eprintln!("{} commands executed: {} succeeded, {} failed",
+                 self.total(),
+                 self.succeeded,
+                 self.failed);
+    }
+
+    fn record_failure(&mut self, line: usize, reason: &str) {
+        error!("Line {}: {}", line, reason);
+        self.failed += 1;
+    }
+}
+
+/// Executes every command in the script file against the server
+///
+/// The file uses the same syntax as the interactive client, one command per line
+/// Blank lines and lines starting with # are ignored
+///
+/// Requests are sent in batches of options.pipeline before their responses are read.
+/// When stopping on the first error, requests from the same batch that were already sent
+/// will still have been executed by the server
+pub fn run(options: &ScriptOptions, stream: &mut TcpStream) -> Result<ScriptSummary, RemError> {
+    let mut f: File = try!(File::open(&options.file));
+    let mut script = String::new();
+    try!(f.read_to_string(&mut script));
+
+    let mut summary = ScriptSummary { succeeded: 0, failed: 0 };
+    let mut batch: Vec<(usize, Vec<Vec<u8>>, Vec<u8>)> = Vec::new();
+
+    for (idx, line) in script.lines().enumerate() {
+        let line_no = idx + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let args: Vec<Vec<u8>> = match client::parse_input(String::from(trimmed)) {
+            Ok(args) => args,
+            Err(why) => {
+                try!(flush_batch(&mut batch, stream, options.output, &mut summary));
+                summary.record_failure(line_no, format!("{}", why).as_str());
+                if !options.continue_on_error {
+                    return Ok(summary);
+                }
+                continue;
+            }
+        };
+        if args.len() == 0 {
+            continue;
+        }
+        match client::request_from_args(&args) {
+            Ok(req) => batch.push((line_no, args, req)),
+            Err(why) => {
+                // Keep the output in script order by finishing the pending batch first
+                try!(flush_batch(&mut batch, stream, options.output, &mut summary));
+                summary.record_failure(line_no, why.description());
+            }
+        }
+        if batch.len() >= options.pipeline {
+            try!(flush_batch(&mut batch, stream, options.output, &mut summary));
+        }
+        if summary.failed > 0 && !options.continue_on_error {
+            return Ok(summary);
+        }
+    }
+    try!(flush_batch(&mut batch, stream, options.output, &mut summary));
+    return Ok(summary);
+}
+
+/// Sends every request in the batch and then reads their responses in order
+///
+/// The batch is empty once this returns
+fn flush_batch(batch: &mut Vec<(usize, Vec<Vec<u8>>, Vec<u8>)>,
+               mut stream: &mut TcpStream,
+               output: OutputFormat,
+               summary: &mut ScriptSummary)
+               -> Result<(), RemError> {
+    for &(_, _, ref req) in batch.iter() {
+        try!(op::write_bytes_to_stream_with_size(&mut stream, req));
+    }
+    for (line_no, args, _) in batch.drain(..) {
+        let res = client::response_from_bytes(&args, try!(op::bytes_from_stream(&mut stream)));
+        try!(output::print_response(output, &res));
+        match res {
+            Response::ERROR(_, ref message) => summary.record_failure(line_no, message),
+            _ => summary.succeeded += 1,
+        }
+    }
+    return Ok(());
+}
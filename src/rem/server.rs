@@ -1,52 +1,276 @@
 use std::string::String;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rem::cache::Cache;
-use rem::service::CacheService;
-use rem::proto::CacheProto;
+use rem::service::{self, CacheService, BlockedPops, OpenConnection};
+use rem::pubsub::PubSub;
+use rem::replication::{self, Replication};
+use rem::raft::{self, Raft};
+use rem::slots::SlotMap;
+use rem::gossip::{self, Membership};
+use rem::anti_entropy;
 use rem::config::Config;
+use rem::logging::LogHandle;
+use rem::reload::LiveConfig;
+use rem::error::*;
 
+use futures::{Future, Stream, BoxFuture};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 
-use native_tls::{Pkcs12, TlsAcceptor};
+use signal_hook;
+use signal_hook::iterator::Signals;
 
-use tokio_tls::proto::Server;
-use tokio_proto::TcpServer;
+use tokio_proto::BindServer;
+use tokio_core::reactor::Core;
+use tokio_core::net::TcpListener;
 
-use std::fs::File;
-use std::io::{Read};
-
-pub fn launch(config: Config, ip: String, port: String) {
+/// Launches the server, as a replica of the primary at replica_of when it is provided
+///
+/// When cluster is provided the server is a node in a cluster with the peers at those addresses.
+/// Its own address is ip:port, which must be the address the peers were given for it
+///
+/// When slots is true the keyspace is split into slots and the server only serves the keys in
+/// the slots assigned to ip:port, clients are redirected to the owner of any other key
+///
+/// When gossip is provided the server tracks which servers are alive, joining through the
+/// seeds at those addresses. Gossip is signed with the configured cluster secret
+///
+/// The server runs until it receives SIGTERM or SIGINT. It then stops accepting connections,
+/// waits up to the configured timeout for the requests in flight to be responded to, makes sure
+/// the cache has reached the disk and exits. On SIGHUP it reloads config_file
+///
+/// The files the server keeps are in the configured data directory, other paths in the config
+/// are relative to the directory the server was started in
+pub fn launch(config: Config,
+              config_file: String,
+              log: LogHandle,
+              ip: String,
+              port: String,
+              replica_of: Option<String>,
+              cluster: Option<Vec<String>>,
+              slots: bool,
+              gossip: Option<Vec<String>>) {
    // Specify the localhost address
     let addr = format!("{}:{}", ip, port).parse().unwrap();
 
-    let pkcs12 = get_pkcs12(&config.server.cert_file, &config.server.cert_password);
-    let acceptor = TlsAcceptor::builder(pkcs12).unwrap().build().unwrap();
-
-    let proto = Server::new(CacheProto{}, acceptor);
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
 
-    // The builder requires a protocol and an address
-    let server = TcpServer::new(proto, addr);
+    let started_in = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(why) => {
+            RemError::from(why).log_and_exit();
+            return;
+        }
+    };
+    if let Err(why) = use_data_dir(&config.server.data_dir) {
+        why.log_and_exit();
+        return;
+    }
 
-    let pool = Box::new(CpuPool::new_num_cpus());
+    let pool = match config.server.pool_size {
+        Some(size) => Box::new(CpuPool::new(size)),
+        None => Box::new(CpuPool::new_num_cpus()),
+    };
 
     // We provide a way to *instantiate* the service for each new
     // connection; here, we just immediately return a new instance.
-    let cache = Arc::new(Mutex::new(Cache::new()));
-    let cache_service = CacheService {
-        cache: cache.clone(),
-        pool : pool
+    let mut cache = Cache::new();
+    cache.keyspace_events = config.server.keyspace_events;
+    cache.sync_writes = config.server.sync_writes;
+    // Only a primary streams its changes, a replica's changes come from its primary
+    cache.record_changes = replica_of.is_none() && cluster.is_none();
+    // Roll back any transaction that was interrupted the last time the server ran
+    let rolled_back = match cache.recover() {
+        Ok(rolled_back) => rolled_back,
+        Err(why) => {
+            why.log_and_exit();
+            return;
+        }
+    };
+    let cache = Arc::new(Mutex::new(cache));
+    let replication = Arc::new(Mutex::new(Replication::new(replica_of.clone(), config.server.replication_backlog_bytes)));
+    let live = match LiveConfig::new(started_in, config_file, config.clone(), cache.clone(), replication.clone(), log) {
+        Ok(live) => Arc::new(Mutex::new(live)),
+        Err(why) => {
+            why.log_and_exit();
+            return;
+        }
+    };
+    let blocked = Arc::new(Mutex::new(BlockedPops::default()));
+    service::start_pop_timer(blocked.clone());
+    let pubsub = Arc::new(Mutex::new(PubSub::default()));
+    if replica_of.is_some() {
+        replication::start_replica(config.clone(), cache.clone(), pubsub.clone(), replication.clone());
+        anti_entropy::start(config.clone(), cache.clone(), pubsub.clone(), replication.clone());
+    }
+    let raft = match cluster {
+        Some(peers) => {
+            let (raft, nudges) = match Raft::load(format!("{}:{}", ip, port), peers, rolled_back) {
+                Ok(loaded) => loaded,
+                Err(why) => {
+                    why.log_and_exit();
+                    return;
+                }
+            };
+            let raft = Arc::new(Mutex::new(raft));
+            raft::start(config.clone(), raft.clone(), nudges, cache.clone(), pubsub.clone());
+            Some(raft)
+        }
+        None => None,
+    };
+    let slots = if slots {
+        match SlotMap::load(format!("{}:{}", ip, port)) {
+            Ok(slots) => Some(Arc::new(Mutex::new(slots))),
+            Err(why) => {
+                why.log_and_exit();
+                return;
+            }
+        }
+    } else {
+        None
     };
+    let membership = match gossip {
+        Some(seeds) => {
+            if config.server.cluster_secret.is_empty() {
+                RemError::with_reason_str(REM_00058).log_and_exit();
+                return;
+            }
+            let membership = Membership::new(format!("{}:{}", ip, port),
+                                             seeds,
+                                             config.server.cluster_port_offset,
+                                             config.server.cluster_secret.clone());
+            let membership = Arc::new(Mutex::new(membership));
+            if let Err(why) = gossip::start(membership.clone(), pubsub.clone()) {
+                why.log_and_exit();
+                return;
+            }
+            Some(membership)
+        }
+        None => None,
+    };
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let shutdown = match wait_for_shutdown() {
+        Ok(shutdown) => shutdown,
+        Err(why) => {
+            why.log_and_exit();
+            return;
+        }
+    };
+    if let Err(why) = reload_on_hangup(live.clone()) {
+        why.log_and_exit();
+        return;
+    }
+
+    // Each connection gets its own service so transaction state isn't shared, and is served
+    // with the TLS certificate loaded when it was accepted
+    let serve = listener.incoming().for_each(|(socket, peer)| {
+        let (proto, max_connections) = {
+            let live = live.lock().unwrap();
+            (live.proto(), live.config().server.max_connections)
+        };
+        if let Some(max) = max_connections {
+            if connections.load(Ordering::SeqCst) >= max {
+                warn!("Closing the connection from {}, {} connections are already open", peer, max);
+                return Ok(());
+            }
+        }
+        proto.bind_server(&handle,
+                          socket,
+                          CacheService::new(config.clone(),
+                                            cache.clone(),
+                                            pool.clone(),
+                                            blocked.clone(),
+                                            pubsub.clone(),
+                                            replication.clone(),
+                                            raft.clone(),
+                                            slots.clone(),
+                                            membership.clone(),
+                                            live.clone(),
+                                            in_flight.clone(),
+                                            OpenConnection::new(connections.clone())));
+        Ok(())
+    });
+    // The listener is closed as soon as the server is told to shut down
+    if let Err((why, _)) = core.run(serve.select(shutdown)) {
+        error!("Stopped accepting connections: {}", why);
+    }
 
-    server.serve( move || Ok(cache_service.clone()));
+    let timeout = live.lock().unwrap().config().server.shutdown_timeout_secs;
+    info!("Shutting down, waiting up to {} seconds for requests in flight", timeout);
+    service::release_blocked_pops(&blocked);
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    // The open connections keep running so the responses to requests in flight are sent
+    while in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(100)));
+    }
+    let abandoned = in_flight.load(Ordering::SeqCst);
+    if abandoned > 0 {
+        warn!("{} requests were still in flight when the shutdown timeout passed", abandoned);
+    }
+
+    // Holding the cache lock means no write is in progress
+    let mut cache = cache.lock().unwrap();
+    if let Err(why) = cache.flush() {
+        why.log();
+    }
+    match cache.remove_lock_files() {
+        Ok(0) => (),
+        Ok(removed) => warn!("Removed {} lock files left behind by interrupted writes", removed),
+        Err(why) => why.log(),
+    }
+    info!("Shutdown complete");
+    process::exit(0);
 }
 
-fn get_pkcs12(cert:&String, password:&String) -> Pkcs12{
-    let mut file = File::open(cert).unwrap();
-    let mut pkcs12 = vec![];
-    file.read_to_end(&mut pkcs12).unwrap();
-    let pkcs12 = Pkcs12::from_der(&pkcs12, password).unwrap();
-    return pkcs12;
+/// Returns a future that completes when the server receives SIGTERM or SIGINT
+///
+/// A second signal exits straight away without waiting for requests in flight
+fn wait_for_shutdown() -> Result<BoxFuture<(), io::Error>, RemError> {
+    let signals = try!(Signals::new(&[signal_hook::SIGTERM, signal_hook::SIGINT]));
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}", signal);
+            let _ = sender.send(());
+        }
+        if let Some(signal) = signals.next() {
+            warn!("Received signal {} while shutting down, exiting now", signal);
+            process::exit(1);
+        }
+    });
+    return Ok(receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "Signal handler stopped")).boxed());
 }
 
+/// Creates the data directory if it doesn't exist and makes it the working directory
+fn use_data_dir(dir: &str) -> Result<(), RemError> {
+    try!(fs::create_dir_all(dir));
+    try!(env::set_current_dir(dir));
+    return Ok(());
+}
 
+/// Reloads the config each time the server receives SIGHUP, a config that can't be loaded is
+/// logged and the running settings are kept
+fn reload_on_hangup(live: Arc<Mutex<LiveConfig>>) -> Result<(), RemError> {
+    let signals = try!(Signals::new(&[signal_hook::SIGHUP]));
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if let Err(why) = live.lock().unwrap().reload() {
+                why.log();
+            }
+        }
+    });
+    return Ok(());
+}
@@ -1,30 +1,767 @@
-use futures::{Future, BoxFuture};
+use futures::{future, Future, BoxFuture};
+use futures::future::Shared;
+use futures::sync::oneshot;
 use tokio_service::Service;
+use tokio_proto::streaming::{Message, Body};
 
 use std::error::Error;
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::thread;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use rem::cache::Cache;
 use rem::cache::CacheOperation;
+use rem::cache::WriteCondition;
 use rem::op;
-use rem::error::RemError;
+use rem::value::{self, ListEnd};
+use rem::pubsub::{PubSub, PushStream};
+use rem::replication::{self, Replication};
+use rem::raft::{self, Raft};
+use rem::slots::{self, SlotMap, Route};
+use rem::gossip::Membership;
+use rem::anti_entropy::{self, MerkleTree};
+use rem::reload::LiveConfig;
+use rem::config::Config;
+use rem::error::*;
 
 use futures_cpupool::CpuPool;
 
 pub const OK:    &'static str = "OK";
 pub const ERROR: &'static str = "ERROR";
+pub const QUEUED: &'static str = "QUEUED";
 
+/// The prefixes of the channels keyspace events are published to
+pub const KEYSPACE_PREFIX: &'static str = "__keyspace__:";
+pub const KEYEVENT_PREFIX: &'static str = "__keyevent__:";
+
+/// The commands that change the cache, a replica rejects them
+pub const WRITE_COMMANDS: &'static [&'static str] = &["W", "D", "SETNX", "SETXX", "SETV", "INCR", "DECR",
+                                                      "INCRBY", "MSET", "MDEL", "DELPREFIX", "DELMATCH",
+                                                      "LPUSH", "RPUSH", "LPOP", "RPOP", "BLPOP", "BRPOP",
+                                                      "HSET", "HDEL", "HINCRBY", "SADD", "SREM", "ZADD",
+                                                      "ZINCRBY"];
+
+/// The keys a connection watches and the versions they had when they were watched
+///
+/// Versions are read on the pool, each WATCH is chained after the one before it and EXEC waits
+/// for the last one so a pipelined EXEC always compares against every watch sent before it
+type Watched = Shared<BoxFuture<Vec<(String, u64)>, ()>>;
+
+/// State kept for each client connection
+#[derive(Default)]
+pub struct Connection {
+    /// Operations queued since MULTI, None when the connection is not in a transaction
+    queue: Option<Vec<CacheOperation>>,
+    /// Keys watched with WATCH, None when no key is watched
+    watched: Option<Watched>,
+    /// The id of the connection in PubSub while it is subscribed to a channel or pattern
+    subscriber: Option<u64>,
+    /// Set by ASKING, lets the next request use a slot this node is importing
+    asking: bool,
+    /// The Merkle tree a replica repairing from this node is comparing with, built by MERKLE
+    /// for the root
+    merkle: Arc<Mutex<Option<Arc<MerkleTree>>>>,
+    /// Counts the connection as open until it is dropped, it is only held for that
+    _open: Option<OpenConnection>
+}
+
+/// Adds one to the number of open connections, which is taken away again when it is dropped
+pub struct OpenConnection {
+    count: Arc<AtomicUsize>
+}
+
+impl OpenConnection {
+    pub fn new(count: Arc<AtomicUsize>) -> OpenConnection {
+        count.fetch_add(1, Ordering::SeqCst);
+        return OpenConnection {
+            count: count
+        };
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A request parked by a blocking pop until an element is pushed to its list
+struct BlockedPop {
+    id: u64,
+    end: ListEnd,
+    /// Completes the parked request's future with the response
+    sender: oneshot::Sender<Vec<u8>>
+}
+
+/// The requests parked by blocking pops on every connection, in the order they arrived for
+/// each key so the client that has waited longest is served first
+#[derive(Default)]
+pub struct BlockedPops {
+    next_id: u64,
+    waiting: HashMap<String, VecDeque<BlockedPop>>,
+    /// When each parked pop with a timeout expires with its id and key, soonest first.
+    /// A pop that was served before its deadline is skipped when the deadline passes
+    deadlines: BinaryHeap<Reverse<(Instant, u64, String)>>,
+    /// Wakes the timer thread when a deadline is added
+    timer: Arc<Condvar>
+}
+
+/// A new CacheService is created for each connection, the cache and pool are shared
+/// between connections while the connection state is not
 #[derive(Clone)]
 pub struct CacheService{
+    pub config: Config,
     pub cache: Arc<Mutex<Cache>>,
-    pub pool : Box<CpuPool>
+    pub pool : Box<CpuPool>,
+    pub blocked: Arc<Mutex<BlockedPops>>,
+    pub pubsub: Arc<Mutex<PubSub>>,
+    pub replication: Arc<Mutex<Replication>>,
+    /// The state of this node in the cluster, None unless the server runs in cluster mode
+    pub raft: Option<Arc<Mutex<Raft>>>,
+    /// The owner of every slot, None unless the server runs with slots
+    pub slots: Option<Arc<Mutex<SlotMap>>>,
+    /// The servers this node knows about, None unless the server runs with gossip
+    pub membership: Option<Arc<Mutex<Membership>>>,
+    /// The settings in use, reloaded by CONFIG RELOAD
+    pub live: Arc<Mutex<LiveConfig>>,
+    /// The number of requests on every connection that haven't been responded to yet
+    pub in_flight: Arc<AtomicUsize>,
+    pub connection: Arc<Mutex<Connection>>
 }
 
+impl CacheService {
+    pub fn new(config: Config,
+               cache: Arc<Mutex<Cache>>,
+               pool: Box<CpuPool>,
+               blocked: Arc<Mutex<BlockedPops>>,
+               pubsub: Arc<Mutex<PubSub>>,
+               replication: Arc<Mutex<Replication>>,
+               raft: Option<Arc<Mutex<Raft>>>,
+               slots: Option<Arc<Mutex<SlotMap>>>,
+               membership: Option<Arc<Mutex<Membership>>>,
+               live: Arc<Mutex<LiveConfig>>,
+               in_flight: Arc<AtomicUsize>,
+               open: OpenConnection)
+               -> CacheService {
+        return CacheService {
+            config: config,
+            cache: cache,
+            pool: pool,
+            blocked: blocked,
+            pubsub: pubsub,
+            replication: replication,
+            raft: raft,
+            slots: slots,
+            membership: membership,
+            live: live,
+            in_flight: in_flight,
+            connection: Arc::new(Mutex::new(Connection {
+                _open: Some(open),
+                ..Connection::default()
+            }))
+        };
+    }
+
+    /// Produces a future for the response to a cache request
+    ///
+    /// Transaction commands are handled here since they change the state of the connection,
+    /// everything else is run by execute unless the connection is queueing a transaction
+    fn call_cache(&self, cache_op: CacheOperation, conn: &mut Connection) -> BoxFuture<Vec<u8>, io::Error> {
+        // Membership is answered by any node whatever mode it runs in
+        if cache_op.command() == "MEMBERS" {
+            let res = match self.membership {
+                Some(ref membership) => {
+                    let info: Vec<Vec<u8>> = membership.lock()
+                        .unwrap()
+                        .info()
+                        .into_iter()
+                        .map(|line| line.into_bytes())
+                        .collect();
+                    Ok(op::encode_list(&info))
+                }
+                None => Err(RemError::with_reason_str(REM_00049)),
+            };
+            return respond(res);
+        }
+        // So is reloading the config, each node reads its own file
+        if cache_op.command() == "CONFIG" {
+            if cache_op.value != b"RELOAD" {
+                return respond(Err(RemError::with_reason(format!("Invalid config command {:?}",
+                                                                 String::from_utf8_lossy(&cache_op.value)))));
+            }
+            let live_ref = self.live.clone();
+            return self.pool.as_ref().spawn_fn( move || {
+                let res = live_ref.lock().unwrap().reload().map(|report| {
+                    let info: Vec<Vec<u8>> = report.into_iter().map(|line| line.into_bytes()).collect();
+                    op::encode_list(&info)
+                });
+                return Ok(response_bytes(res));
+            }).boxed();
+        }
+        if let Some(ref raft) = self.raft {
+            return self.call_cluster(raft, cache_op);
+        }
+        if let Some(ref slots_ref) = self.slots {
+            // ASKING only applies to the request that follows it
+            let asking = mem::replace(&mut conn.asking, false);
+            let keys = match request_keys(&cache_op) {
+                Ok(keys) => keys,
+                Err(why) => return respond(Err(why)),
+            };
+            let route = slots_ref.lock().unwrap().route(&keys, asking);
+            match route {
+                Ok(Route::LOCAL) => (),
+                Ok(Route::MIGRATING(target)) => {
+                    // Whether a key has moved is only known when the request runs
+                    match cache_op.command().as_str() {
+                        "WATCH" | "BLPOP" | "BRPOP" => {
+                            return respond(Err(RemError::with_reason_str(REM_00047)));
+                        }
+                        _ if conn.queue.is_some() => return respond(Err(RemError::with_reason_str(REM_00047))),
+                        _ => return self.spawn_migrating_op(cache_op, keys, target),
+                    }
+                }
+                Err(why) => return respond(Err(why)),
+            }
+        }
+        match cache_op.command().as_str() {
+            "MULTI" => {
+                if conn.queue.is_some() {
+                    return respond(Err(RemError::with_reason_str(REM_00018)));
+                }
+                conn.queue = Some(Vec::new());
+                return respond(Ok(OK.as_bytes().to_vec()));
+            }
+            "DISCARD" => {
+                if conn.queue.take().is_none() {
+                    return respond(Err(RemError::with_reason_str(REM_00019)));
+                }
+                conn.watched = None;
+                return respond(Ok(OK.as_bytes().to_vec()));
+            }
+            "EXEC" => {
+                let queue = match conn.queue.take() {
+                    Some(queue) => queue,
+                    None => return respond(Err(RemError::with_reason_str(REM_00019))),
+                };
+                if !self.replication.lock().unwrap().has_full_copy() {
+                    conn.watched = None;
+                    return respond(Err(RemError::with_reason_str(REM_00052)));
+                }
+                let watched = watched_versions(conn.watched.take());
+                let pool = self.pool.as_ref().clone();
+                let cache_ref = self.cache.clone();
+                let blocked_ref = self.blocked.clone();
+                let pubsub_ref = self.pubsub.clone();
+                let replication_ref = self.replication.clone();
+                return watched.map_err(|_| io::Error::new(io::ErrorKind::Other, "Watch was dropped"))
+                    .and_then(move |watched| pool.spawn_fn( move || {
+                        let mut cache = cache_ref.lock().unwrap();
+                        let res = response_bytes(exec_transaction(queue, watched, &mut cache));
+                        serve_blocked_pops(&blocked_ref, &mut cache);
+                        publish_key_events(&pubsub_ref, &mut cache);
+                        replicate_changes(&replication_ref, &mut cache);
+                        return Ok(res);
+                    }))
+                    .boxed();
+            }
+            "WATCH" => {
+                if conn.queue.is_some() {
+                    return respond(Err(RemError::with_reason_str(REM_00020)));
+                }
+                let keys = match op::decode_keys(&cache_op.value) {
+                    Ok(keys) => keys,
+                    Err(why) => return respond(Err(why)),
+                };
+                let pool = self.pool.as_ref().clone();
+                let cache_ref = self.cache.clone();
+                let watched = watched_versions(conn.watched.take())
+                    .and_then(move |mut watched| pool.spawn_fn( move || {
+                        let cache = cache_ref.lock().unwrap();
+                        for key in keys {
+                            let version = cache.watch_version(key.as_str());
+                            watched.push((key, version));
+                        }
+                        return Ok(watched);
+                    }))
+                    .boxed()
+                    .shared();
+                conn.watched = Some(watched.clone());
+                return watched.then(|_| Ok(OK.as_bytes().to_vec())).boxed();
+            }
+            "UNWATCH" => {
+                conn.watched = None;
+                return respond(Ok(OK.as_bytes().to_vec()));
+            }
+            // Sent by a replica every second with the offset it has applied
+            "REPLACK" => {
+                let res = cache_op.key_and_arg().and_then(|(id, offset)| {
+                    let id = try!(op::parse_integer(id.as_bytes()));
+                    let offset = try!(op::parse_integer(&offset));
+                    let primary_offset = self.replication.lock().unwrap().ack(id as u64, offset as u64);
+                    Ok(primary_offset.to_string().into_bytes())
+                });
+                return respond(res);
+            }
+            "REPLICATION" => {
+                let (mut info, primary) = {
+                    let replication = self.replication.lock().unwrap();
+                    (replication.info(), replication.primary.clone())
+                };
+                // A replica running with gossip also reports whether its primary is alive
+                if let (Some(ref membership), Some(primary)) = (self.membership.as_ref(), primary) {
+                    if let Some(state) = membership.lock().unwrap().state_of(primary.as_str()) {
+                        info.push(format!("primary_state:{}", state.name()));
+                    }
+                }
+                let info: Vec<Vec<u8>> = info.into_iter().map(|line| line.into_bytes()).collect();
+                return respond(Ok(op::encode_list(&info)));
+            }
+            "RAFT" => return respond(Err(RemError::with_reason_str(REM_00038))),
+            // Sent by a replica comparing its keys with this node's
+            "MERKLE" | "MERKLEKEYS" | "MERKLEFETCH" => {
+                let cache_ref = self.cache.clone();
+                let session = conn.merkle.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    let res = match cache_op.command().as_str() {
+                        "MERKLE" => anti_entropy::merkle_hashes(&cache_ref, &session, &cache_op.value),
+                        "MERKLEKEYS" => anti_entropy::merkle_keys(&session, &cache_op.value),
+                        _ => anti_entropy::merkle_fetch(&cache_ref.lock().unwrap(), &cache_op.value),
+                    };
+                    return Ok(response_bytes(res));
+                }).boxed();
+            }
+            // Responds once the replica matches its primary with the keys that were changed
+            "REPAIR" => {
+                let config = self.config.clone();
+                let cache_ref = self.cache.clone();
+                let pubsub_ref = self.pubsub.clone();
+                let replication_ref = self.replication.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    let res = anti_entropy::repair(&config, &cache_ref, &pubsub_ref, &replication_ref).map(|report| {
+                        let info: Vec<Vec<u8>> = report.info().into_iter().map(|line| line.into_bytes()).collect();
+                        op::encode_list(&info)
+                    });
+                    return Ok(response_bytes(res));
+                }).boxed();
+            }
+            "ASKING" | "SLOTS" | "SLOTSET" | "SLOTIMPORTING" | "SLOTRESTORE" | "MIGRATE" => {
+                return match self.slots {
+                    Some(ref slots_ref) => self.call_slots(slots_ref, cache_op, conn),
+                    None => respond(Err(RemError::with_reason_str(REM_00048))),
+                };
+            }
+            _ => (),
+        }
+        {
+            let replication = self.replication.lock().unwrap();
+            if WRITE_COMMANDS.contains(&cache_op.command().as_str()) && replication.is_replica() {
+                return respond(Err(RemError::with_reason_str(REM_00032)));
+            }
+            // A replica part way through a full sync would answer from a partial copy
+            if !replication.has_full_copy() {
+                return respond(Err(RemError::with_reason_str(REM_00052)));
+            }
+        }
+        if let Some(ref mut queue) = conn.queue {
+            queue.push(cache_op);
+            return respond(Ok(QUEUED.as_bytes().to_vec()));
+        }
+        // Blocking pops only block outside of a transaction
+        match cache_op.command().as_str() {
+            "BLPOP" => return self.spawn_blocking_pop(cache_op, ListEnd::LEFT),
+            "BRPOP" => return self.spawn_blocking_pop(cache_op, ListEnd::RIGHT),
+            _ => return self.spawn_op(cache_op),
+        }
+    }
+
+    /// Produces a future for the response to a request made to a node in cluster mode
+    ///
+    /// Only the leader serves requests, the other nodes redirect clients to it. Writes are
+    /// appended to the operation log and respond once a majority of the cluster holds them
+    /// and they have been applied. Reads are only served while a majority has responded to the
+    /// leader recently
+    fn call_cluster(&self, raft_ref: &Arc<Mutex<Raft>>, cache_op: CacheOperation) -> BoxFuture<Vec<u8>, io::Error> {
+        let cmd = cache_op.command();
+        match cmd.as_str() {
+            "RAFTVOTE" => {
+                let raft_ref = raft_ref.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    Ok(response_bytes(raft::handle_vote(&raft_ref, &cache_op.value)))
+                }).boxed();
+            }
+            "RAFTAPPEND" => {
+                let raft_ref = raft_ref.clone();
+                let cache_ref = self.cache.clone();
+                let pubsub_ref = self.pubsub.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    Ok(response_bytes(raft::handle_append(&raft_ref, &cache_ref, &pubsub_ref, &cache_op.value)))
+                }).boxed();
+            }
+            "RAFTSNAPSHOT" => {
+                let raft_ref = raft_ref.clone();
+                let cache_ref = self.cache.clone();
+                let pubsub_ref = self.pubsub.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    Ok(response_bytes(raft::handle_snapshot(&raft_ref, &cache_ref, &pubsub_ref, &cache_op.value)))
+                }).boxed();
+            }
+            "RAFT" => {
+                let info: Vec<Vec<u8>> = raft_ref.lock()
+                    .unwrap()
+                    .info()
+                    .into_iter()
+                    .map(|line| line.into_bytes())
+                    .collect();
+                return respond(Ok(op::encode_list(&info)));
+            }
+            _ => (),
+        }
+        if raft::UNSUPPORTED_COMMANDS.contains(&cmd.as_str()) {
+            return respond(Err(RemError::with_reason(format!("{}: {}", REM_00037, cmd))));
+        }
+        {
+            let raft = raft_ref.lock().unwrap();
+            if !raft.is_leader() {
+                return respond(Err(raft.redirect()));
+            }
+            // A leader cut off from the cluster may have been replaced and would serve stale reads
+            if !WRITE_COMMANDS.contains(&cmd.as_str()) && !raft.can_serve_reads() {
+                return respond(Err(RemError::with_reason_str(REM_00060)));
+            }
+        }
+        if !WRITE_COMMANDS.contains(&cmd.as_str()) {
+            return self.spawn_op(cache_op);
+        }
+        let mut data: Vec<u8> = format!("{}$", cmd).into_bytes();
+        data.extend(cache_op.value);
+        let raft_ref = raft_ref.clone();
+        let cache_ref = self.cache.clone();
+        let pubsub_ref = self.pubsub.clone();
+        self.pool.as_ref().spawn_fn( move || -> BoxFuture<Vec<u8>, io::Error> {
+            match raft::propose(&raft_ref, &cache_ref, &pubsub_ref, data) {
+                Ok(receiver) => {
+                    receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "Write was dropped")).boxed()
+                }
+                Err(why) => respond(Err(why)),
+            }
+        }).boxed()
+    }
+
+    /// Produces a future for the response to a request that manages the slots of this node
+    ///
+    /// SLOTSET, SLOTIMPORTING and SLOTRESTORE are sent by the node a slot is migrated from
+    fn call_slots(&self,
+                  slots_ref: &Arc<Mutex<SlotMap>>,
+                  cache_op: CacheOperation,
+                  conn: &mut Connection)
+                  -> BoxFuture<Vec<u8>, io::Error> {
+        let cmd = cache_op.command();
+        match cmd.as_str() {
+            "ASKING" => {
+                conn.asking = true;
+                return respond(Ok(OK.as_bytes().to_vec()));
+            }
+            "SLOTS" => {
+                let info: Vec<Vec<u8>> = slots_ref.lock()
+                    .unwrap()
+                    .info()
+                    .into_iter()
+                    .map(|line| line.into_bytes())
+                    .collect();
+                return respond(Ok(op::encode_list(&info)));
+            }
+            // {first slot}:{last slot}:{node}
+            "SLOTSET" => {
+                let res = cache_op.key_and_arg().and_then(|(first, arg)| {
+                    let (last, node) = op::split_arg(&arg);
+                    let (first, last) = try!(slots::parse_range(first.as_bytes(), last));
+                    let node = try!(String::from_utf8(node.to_vec())
+                        .map_err(|_| RemError::with_reason_str(REM_00005)));
+                    try!(slots_ref.lock().unwrap().assign(first, last, node.as_str()));
+                    Ok(OK.as_bytes().to_vec())
+                });
+                return respond(res);
+            }
+            // {slot}:{source}
+            "SLOTIMPORTING" => {
+                let res = cache_op.key_and_arg().and_then(|(slot, source)| {
+                    let (slot, _) = try!(slots::parse_range(slot.as_bytes(), slot.as_bytes()));
+                    let source = try!(String::from_utf8(source)
+                        .map_err(|_| RemError::with_reason_str(REM_00005)));
+                    slots_ref.lock().unwrap().import(slot, source.as_str());
+                    Ok(OK.as_bytes().to_vec())
+                });
+                return respond(res);
+            }
+            // A key moved from the node migrating the slot, encoded as it is sent to a replica
+            "SLOTRESTORE" => {
+                let cache_ref = self.cache.clone();
+                let blocked_ref = self.blocked.clone();
+                let pubsub_ref = self.pubsub.clone();
+                let replication_ref = self.replication.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    let mut cache = cache_ref.lock().unwrap();
+                    let res = replication::StreamEntry::decode(&cache_op.value)
+                        .and_then(|entry| entry.apply(&mut cache))
+                        .map(|_| OK.as_bytes().to_vec());
+                    serve_blocked_pops(&blocked_ref, &mut cache);
+                    publish_key_events(&pubsub_ref, &mut cache);
+                    replicate_changes(&replication_ref, &mut cache);
+                    return Ok(response_bytes(res));
+                }).boxed();
+            }
+            // {slot}:{target}, responds with the number of keys moved once the target owns the slot
+            "MIGRATE" => {
+                let (slot, target) = match cache_op.key_and_arg().and_then(|(slot, target)| {
+                    let (slot, _) = try!(slots::parse_range(slot.as_bytes(), slot.as_bytes()));
+                    let target = try!(String::from_utf8(target)
+                        .map_err(|_| RemError::with_reason_str(REM_00005)));
+                    Ok((slot, target))
+                }) {
+                    Ok(args) => args,
+                    Err(why) => return respond(Err(why)),
+                };
+                let config = self.config.clone();
+                let slots_ref = slots_ref.clone();
+                let cache_ref = self.cache.clone();
+                let pubsub_ref = self.pubsub.clone();
+                let replication_ref = self.replication.clone();
+                return self.pool.as_ref().spawn_fn( move || {
+                    let res = slots::migrate_slot(&config,
+                                                  &slots_ref,
+                                                  &cache_ref,
+                                                  &pubsub_ref,
+                                                  &replication_ref,
+                                                  slot,
+                                                  target.as_str());
+                    Ok(response_bytes(res.map(|moved| moved.to_string().into_bytes())))
+                }).boxed();
+            }
+            _ => return respond(Err(RemError::with_reason(format!("Invalid cache command {:?}", cmd)))),
+        }
+    }
+
+    /// Runs an operation on keys in a slot that is being migrated away from this node
+    ///
+    /// The keys are checked while the cache lock is held so none can move in the meantime.
+    /// The operation runs here when every key is still here and the client is sent to the
+    /// target with ASK when none are, a new key is created on the target. When only some
+    /// of the keys have moved neither node can run it until the migration is done
+    fn spawn_migrating_op(&self, cache_op: CacheOperation, keys: Vec<String>, target: String)
+                          -> BoxFuture<Vec<u8>, io::Error> {
+        let cache_ref = self.cache.clone();
+        let blocked_ref = self.blocked.clone();
+        let pubsub_ref = self.pubsub.clone();
+        let replication_ref = self.replication.clone();
+        self.pool.as_ref().spawn_fn( move || {
+            let mut cache = cache_ref.lock().unwrap();
+            let present = keys.iter().filter(|key| cache.contains_key(key.as_str())).count();
+            if present == 0 {
+                let slot = slots::slot_for(keys[0].as_str());
+                return Ok(response_bytes(Err(RemError::with_reason(format!("{} {} {}",
+                                                                           REM_00043,
+                                                                           slot,
+                                                                           target)))));
+            }
+            if present < keys.len() {
+                return Ok(response_bytes(Err(RemError::with_reason_str(REM_00047))));
+            }
+            let res = response_bytes(execute(cache_op, &mut cache));
+            serve_blocked_pops(&blocked_ref, &mut cache);
+            publish_key_events(&pubsub_ref, &mut cache);
+            replicate_changes(&replication_ref, &mut cache);
+            return Ok(res);
+        }).boxed()
+    }
+
+    /// Runs the operation on the thread pool while holding the cache lock
+    fn spawn_op(&self, cache_op: CacheOperation) -> BoxFuture<Vec<u8>, io::Error> {
+        // Clone the cache arc so we can move a ref into the closure
+        let cache_ref = self.cache.clone();
+        let blocked_ref = self.blocked.clone();
+        let pubsub_ref = self.pubsub.clone();
+        let replication_ref = self.replication.clone();
+        // Spawn the actual work on the thread pool
+        self.pool.as_ref().spawn_fn( move || {
+            let mut cache = cache_ref.lock().unwrap();
+            let res = response_bytes(execute(cache_op, &mut cache));
+            serve_blocked_pops(&blocked_ref, &mut cache);
+            publish_key_events(&pubsub_ref, &mut cache);
+            replicate_changes(&replication_ref, &mut cache);
+            return Ok(res);
+        }).boxed()
+    }
+
+    /// Pops an element from the list, or parks the request until an element is pushed by
+    /// another client or the timeout passes. A timeout of 0 waits forever
+    fn spawn_blocking_pop(&self, cache_op: CacheOperation, end: ListEnd) -> BoxFuture<Vec<u8>, io::Error> {
+        let (key, timeout) = match decode_blocking_pop_args(&cache_op) {
+            Ok(args) => args,
+            Err(why) => return respond(Err(why)),
+        };
+        let cache_ref = self.cache.clone();
+        let blocked_ref = self.blocked.clone();
+        let pubsub_ref = self.pubsub.clone();
+        let replication_ref = self.replication.clone();
+        self.pool.as_ref().spawn_fn( move || -> BoxFuture<Vec<u8>, io::Error> {
+            let mut cache = cache_ref.lock().unwrap();
+            match op::list_length(key.clone(), &cache) {
+                Ok(0) => (),
+                Ok(_) => {
+                    let res = op::pop_from_list(key, end, &mut cache);
+                    publish_key_events(&pubsub_ref, &mut cache);
+                    replicate_changes(&replication_ref, &mut cache);
+                    return respond(res);
+                }
+                Err(why) => return respond(Err(why)),
+            }
+            // The cache lock is still held so no push can happen before the request is parked
+            let (sender, receiver) = oneshot::channel();
+            let mut blocked = blocked_ref.lock().unwrap();
+            let id = blocked.next_id;
+            blocked.next_id += 1;
+            blocked.waiting.entry(key.clone()).or_insert(VecDeque::new()).push_back(BlockedPop {
+                id: id,
+                end: end,
+                sender: sender
+            });
+            if timeout > 0 {
+                blocked.deadlines.push(Reverse((Instant::now() + Duration::from_secs(timeout), id, key)));
+                blocked.timer.notify_one();
+            }
+            return receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "Blocked pop was dropped"))
+                .boxed();
+        }).boxed()
+    }
+
+    /// Syncs a replica, the request is empty or ```{run id}:{offset}``` when the replica
+    /// wants to continue from the offset it reached with this run of the primary
+    ///
+    /// When the backlog still holds every change after the offset the response is
+    /// ```CONTINUE:{id}:{offset}``` and its body streams those changes. Otherwise the response is
+    /// ```FULLSYNC:{id}:{run id}:{offset}``` and its body streams every key in the cache.
+    /// Either way the body goes on to stream every change made after that
+    fn spawn_sync(&self, cache_op: CacheOperation) -> BoxFuture<Message<Vec<u8>, PushStream>, io::Error> {
+        if self.raft.is_some() || self.replication.lock().unwrap().is_replica() {
+            return respond_message(Err(RemError::with_reason_str(REM_00033)));
+        }
+        let resume = if cache_op.value.is_empty() {
+            None
+        } else {
+            match cache_op.key_and_arg().and_then(|(run_id, offset)| {
+                Ok((run_id, try!(op::parse_integer(&offset)) as u64))
+            }) {
+                Ok(resume) => Some(resume),
+                Err(why) => return respond_message(Err(why)),
+            }
+        };
+        let cache_ref = self.cache.clone();
+        let replication_ref = self.replication.clone();
+        self.pool.as_ref().spawn_fn( move || {
+            // The cache lock is held until the replica is registered so no change is missed
+            let cache = cache_ref.lock().unwrap();
+            let mut replication = replication_ref.lock().unwrap();
+            let offset = replication.offset;
+            let backlog = resume.and_then(|(run_id, offset)| {
+                replication.backlog_after(run_id.as_str(), offset).map(|changes| (offset, changes))
+            });
+            if let Some((resume_offset, changes)) = backlog {
+                let (id, stream) = replication.add_replica(changes, true);
+                let head = format!("{}:{}:{}", replication::CONTINUE, id, resume_offset).into_bytes();
+                return Ok(Message::WithBody(head, stream));
+            }
+            let snapshot = match replication::snapshot(&cache, offset, replication.run_id.as_str()) {
+                Ok(snapshot) => snapshot,
+                Err(why) => return Ok(Message::WithoutBody(response_bytes(Err(why)))),
+            };
+            let (id, stream) = replication.add_replica(snapshot, false);
+            let head = format!("{}:{}:{}:{}", replication::FULLSYNC, id, replication.run_id, offset)
+                .into_bytes();
+            return Ok(Message::WithBody(head, stream));
+        }).boxed()
+    }
+
+    /// Produces a future for the response to a request
+    ///
+    /// Subscribing is handled here since only the response to the first subscription has a body,
+    /// the messages pushed to the connection are streamed through that body.
+    /// While a connection is subscribed its other responses are held back until the body ends
+    fn call_request(&self, req: Message<Vec<u8>, Body<Vec<u8>, io::Error>>)
+                    -> BoxFuture<Message<Vec<u8>, PushStream>, io::Error> {
+        let cache_op = CacheOperation::new_from_bytes(&req.into_inner());
+        let mut conn = self.connection.lock().unwrap();
+        let cmd = cache_op.command();
+        match cmd.as_str() {
+            "SUBSCRIBE" | "PSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "PUBLISH" | "SYNC" => {
+                if conn.queue.is_some() {
+                    return respond_message(Err(RemError::with_reason(format!("{}: {}",
+                                                                             REM_00027,
+                                                                             cmd))));
+                }
+            }
+            _ => {
+                if conn.subscriber.is_some() {
+                    return respond_message(Err(RemError::with_reason_str(REM_00028)));
+                }
+                return self.call_cache(cache_op, &mut conn).map(Message::WithoutBody).boxed();
+            }
+        }
+        if cmd == "SYNC" {
+            if conn.subscriber.is_some() {
+                return respond_message(Err(RemError::with_reason_str(REM_00028)));
+            }
+            return self.spawn_sync(cache_op);
+        }
+        if cmd == "PUBLISH" {
+            let res = cache_op.key_and_arg().map(|(channel, message)| {
+                let receivers = self.pubsub.lock().unwrap().publish(channel.as_str(), &message);
+                receivers.to_string().into_bytes()
+            });
+            return respond_message(res);
+        }
+        let names = match op::decode_keys(&cache_op.value) {
+            Ok(names) => names,
+            Err(why) => return respond_message(Err(why)),
+        };
+        let patterns = cmd.starts_with("P");
+        let mut pubsub = self.pubsub.lock().unwrap();
+        if cmd.ends_with("UNSUBSCRIBE") {
+            if let Some(id) = conn.subscriber {
+                if !pubsub.unsubscribe(id, names, patterns) {
+                    conn.subscriber = None;
+                }
+            }
+            return respond_message(Ok(OK.as_bytes().to_vec()));
+        }
+        if names.is_empty() {
+            return respond_message(Err(RemError::with_reason_str(REM_00015)));
+        }
+        match conn.subscriber {
+            Some(id) => {
+                pubsub.subscribe(id, names, patterns);
+                return respond_message(Ok(OK.as_bytes().to_vec()));
+            }
+            None => {
+                let (id, stream) = pubsub.register(&self.pubsub);
+                pubsub.subscribe(id, names, patterns);
+                conn.subscriber = Some(id);
+                return future::ok(Message::WithBody(OK.as_bytes().to_vec(), stream)).boxed();
+            }
+        }
+    }
+}
+
+
 impl Service for CacheService {
     // These types must match the corresponding protocol types:
-    type Request =  String;
-    type Response = String;
+    type Request = Message<Vec<u8>, Body<Vec<u8>, io::Error>>;
+    type Response = Message<Vec<u8>, PushStream>;
 
     // For non-streaming protocols, service errors are always io::Error
     type Error = io::Error;
@@ -33,43 +770,582 @@ impl Service for CacheService {
     type Future = BoxFuture<Self::Response, Self::Error>;
 
     // Produce a future for computing a response from a request.
+    //
+    // The request is counted as in flight until its response is ready so a shutdown can wait for it
     fn call(&self, req: Self::Request) -> Self::Future {
-        // Clone the cache arc so we can move a ref into the closure
-        let cache_ref = self.cache.clone();
-        // Spawn the actual work on the thread pool
-        self.pool.as_ref().spawn_fn( move || {
-            let cache_op = CacheOperation::new_from_string(&req);
-            let prim_cmd: char = cache_op.commands[0];
-            let cache_res:Result<String, RemError> = match prim_cmd {
-                'W' => {
-                    match op::write_stream_str_to_cache(cache_op.value, cache_ref.as_ref()) {
-                        Ok(()) => Ok(String::from(OK)),
-                        Err(cause) => Err(cause)
-                    }      
-                },
-                'R' => {
-                    match op::read_value_from_cache(cache_op.value, cache_ref.as_ref()){
-                        // Todo handle errors
-                        Ok(res) => Ok(String::from_utf8(res).unwrap()),
-                        Err(cause) => Err(cause)
-                    }
-                },
-                'D' => {
-                    match op::delete_value_from_cache(cache_op.value, cache_ref.as_ref()) {
-                        Ok(()) => Ok(String::from(OK)),
-                        Err(cause) => Err(cause)
-                    } 
-                }
-                _ => Err(RemError::with_reason(format!("Invalid cache command {:?}", prim_cmd))),
-            };
-            let ret = match cache_res {
-                Ok(res) =>  res,
-                Err(cause) => {
-                    let err_desc = String::from(cause.description());
-                    format!("{}:{}", ERROR, err_desc)
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.in_flight.clone();
+        return self.call_request(req).then(move |res| {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            res
+        }).boxed();
+    }
+}
+
+/// Runs a single operation against the cache, the caller must hold the cache lock
+pub fn execute(cache_op: CacheOperation, cache: &mut Cache) -> Result<Vec<u8>, RemError> {
+    let cmd: String = cache_op.command();
+    match cmd.as_str() {
+        "W" => {
+            match op::write_bytes_to_cache(cache_op.value, cache) {
+                Ok(()) => Ok(OK.as_bytes().to_vec()),
+                Err(cause) => Err(cause)
+            }      
+        },
+        "R" => {
+            cache_op.value_str().and_then(|key| {
+                op::read_value_from_cache(key, cache)
+            })
+        },
+        "D" => {
+            match cache_op.value_str().and_then(|key| {
+                op::delete_value_from_cache(key, cache)
+            }) {
+                Ok(()) => Ok(OK.as_bytes().to_vec()),
+                Err(cause) => Err(cause)
+            } 
+        }
+        "K" => {
+            cache_op.value_str().and_then(|prefix| {
+                op::keys_from_cache(prefix, cache)
+            }).map(|keys| {
+                let items: Vec<Vec<u8>> = keys.into_iter().map(String::into_bytes).collect();
+                op::encode_list(&items)
+            })
+        }
+        "RV" => {
+            cache_op.value_str().and_then(|key| {
+                op::read_value_and_version_from_cache(key, cache)
+            }).map(|(version, val)| {
+                let mut res = format!("{}:", version).into_bytes();
+                res.extend(val);
+                res
+            })
+        }
+        "SETNX" => {
+            cache_op.key_and_arg().and_then(|(key, val)| {
+                op::write_value_to_cache_if(key, val, WriteCondition::ABSENT, cache)
+            }).map(|version| version.to_string().into_bytes())
+        }
+        "SETXX" => {
+            cache_op.key_and_arg().and_then(|(key, val)| {
+                op::write_value_to_cache_if(key, val, WriteCondition::PRESENT, cache)
+            }).map(|version| version.to_string().into_bytes())
+        }
+        "SETV" => {
+            cache_op.key_and_arg().and_then(|(key, arg)| {
+                let (version, val) = op::split_arg(&arg);
+                let expected = try!(op::parse_integer(version));
+                if expected < 0 {
+                    return Err(RemError::with_reason_str(REM_00012));
+                }
+                op::write_value_to_cache_if(key,
+                                            val.to_vec(),
+                                            WriteCondition::VERSION(expected as u64),
+                                            cache)
+            }).map(|version| version.to_string().into_bytes())
+        }
+        "INCR" => {
+            cache_op.value_str().and_then(|key| {
+                op::increment_value_in_cache(key, 1, cache)
+            }).map(|val| val.to_string().into_bytes())
+        }
+        "DECR" => {
+            cache_op.value_str().and_then(|key| {
+                op::increment_value_in_cache(key, -1, cache)
+            }).map(|val| val.to_string().into_bytes())
+        }
+        "INCRBY" => {
+            cache_op.key_and_arg().and_then(|(key, arg)| {
+                let delta = try!(op::parse_integer(&arg));
+                op::increment_value_in_cache(key, delta, cache)
+            }).map(|val| val.to_string().into_bytes())
+        }
+        "MGET" => {
+            op::decode_keys(&cache_op.value).map(|keys| {
+                let results: Vec<Vec<u8>> = op::read_values_from_cache(keys, cache)
+                    .into_iter()
+                    .map(response_bytes)
+                    .collect();
+                op::encode_list(&results)
+            })
+        }
+        "MSET" => {
+            decode_pairs(&cache_op.value).map(|pairs| {
+                let results: Vec<Vec<u8>> = op::write_values_to_cache(pairs, cache)
+                    .into_iter()
+                    .map(|res| response_bytes(res.map(|()| OK.as_bytes().to_vec())))
+                    .collect();
+                op::encode_list(&results)
+            })
+        }
+        "MDEL" => {
+            op::decode_keys(&cache_op.value).map(|keys| {
+                let results: Vec<Vec<u8>> = op::delete_values_from_cache(keys, cache)
+                    .into_iter()
+                    .map(|res| {
+                        response_bytes(res.map(|existed| {
+                            if existed { b"1".to_vec() } else { b"0".to_vec() }
+                        }))
+                    })
+                    .collect();
+                op::encode_list(&results)
+            })
+        }
+        "SCAN" => {
+            decode_scan_args(&cache_op.value).and_then(|(cursor, pattern, count)| {
+                op::scan_cache(cursor, pattern, count, cache)
+            }).map(|(next, keys)| {
+                let mut items: Vec<Vec<u8>> = vec![next.into_bytes()];
+                items.extend(keys.into_iter().map(String::into_bytes));
+                op::encode_list(&items)
+            })
+        }
+        "KEYS" => {
+            cache_op.value_str().and_then(|pattern| {
+                let pattern = if pattern.is_empty() { String::from("*") } else { pattern };
+                op::keys_matching_from_cache(pattern, cache)
+            }).map(|keys| {
+                let items: Vec<Vec<u8>> = keys.into_iter().map(String::into_bytes).collect();
+                op::encode_list(&items)
+            })
+        }
+        "DELPREFIX" => {
+            cache_op.value_str().and_then(|prefix| {
+                op::delete_prefix_from_cache(prefix, cache)
+            }).map(|count| count.to_string().into_bytes())
+        }
+        "DELMATCH" => {
+            cache_op.value_str().and_then(|pattern| {
+                op::delete_matching_from_cache(pattern, cache)
+            }).map(|count| count.to_string().into_bytes())
+        }
+        "DBSIZE" => {
+            op::count_cache(cache).map(|count| count.to_string().into_bytes())
+        }
+        "LPUSH" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, elements)| {
+                op::push_to_list(key, ListEnd::LEFT, elements, cache)
+            }).map(|len| len.to_string().into_bytes())
+        }
+        "RPUSH" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, elements)| {
+                op::push_to_list(key, ListEnd::RIGHT, elements, cache)
+            }).map(|len| len.to_string().into_bytes())
+        }
+        // Inside a transaction a blocking pop returns straight away like a normal pop
+        "LPOP" | "BLPOP" => {
+            cache_op.key_and_arg().and_then(|(key, _)| {
+                op::pop_from_list(key, ListEnd::LEFT, cache)
+            })
+        }
+        "RPOP" | "BRPOP" => {
+            cache_op.key_and_arg().and_then(|(key, _)| {
+                op::pop_from_list(key, ListEnd::RIGHT, cache)
+            })
+        }
+        "LRANGE" => {
+            cache_op.key_and_arg().and_then(|(key, arg)| {
+                let (start, stop) = op::split_arg(&arg);
+                let start = try!(op::parse_integer(start));
+                let stop = try!(op::parse_integer(stop));
+                op::range_from_list(key, start, stop, cache)
+            }).map(|elements| op::encode_list(&elements))
+        }
+        "LLEN" => {
+            cache_op.value_str().and_then(|key| {
+                op::list_length(key, cache)
+            }).map(|len| len.to_string().into_bytes())
+        }
+        "SADD" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, members)| {
+                op::add_set_members(key, members, cache)
+            }).map(|added| added.to_string().into_bytes())
+        }
+        "SREM" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, members)| {
+                op::remove_set_members(key, members, cache)
+            }).map(|removed| removed.to_string().into_bytes())
+        }
+        "SISMEMBER" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, mut items)| {
+                if items.len() != 1 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                op::is_set_member(key, items.remove(0), cache)
+            }).map(|member| if member { b"1".to_vec() } else { b"0".to_vec() })
+        }
+        "SMEMBERS" => {
+            cache_op.value_str().and_then(|key| {
+                op::set_members(key, cache)
+            }).map(|members| op::encode_list(&members))
+        }
+        "SINTER" => {
+            op::decode_keys(&cache_op.value).and_then(|keys| {
+                op::intersect_sets(keys, cache)
+            }).map(|members| op::encode_list(&members))
+        }
+        "ZADD" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, items)| {
+                if items.is_empty() || items.len() % 2 != 0 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let mut scored: Vec<(f64, Vec<u8>)> = Vec::new();
+                let mut iter = items.into_iter();
+                while let (Some(score), Some(member)) = (iter.next(), iter.next()) {
+                    scored.push((try!(value::parse_score(&score)), member));
+                }
+                op::add_sorted_set_members(key, scored, cache)
+            }).map(|added| added.to_string().into_bytes())
+        }
+        "ZRANGE" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, items)| {
+                if items.len() != 2 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let start = try!(op::parse_integer(&items[0]));
+                let stop = try!(op::parse_integer(&items[1]));
+                op::range_sorted_set_by_rank(key, start, stop, cache)
+            }).map(|members| encode_scored(members))
+        }
+        "ZRANGEBYSCORE" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, items)| {
+                if items.len() != 2 {
+                    return Err(RemError::with_reason_str(REM_00015));
                 }
+                let min = try!(value::parse_score(&items[0]));
+                let max = try!(value::parse_score(&items[1]));
+                op::range_sorted_set_by_score(key, min, max, cache)
+            }).map(|members| encode_scored(members))
+        }
+        "ZINCRBY" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, mut items)| {
+                if items.len() != 2 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let delta = try!(value::parse_score(&items[0]));
+                op::increment_sorted_set_member(key, items.remove(1), delta, cache)
+            }).map(|score| value::format_score(score).into_bytes())
+        }
+        "MEMORY" => {
+            let items: Vec<Vec<u8>> = op::memory_usage_from_cache(cache)
+                .into_iter()
+                .map(String::into_bytes)
+                .collect();
+            Ok(op::encode_list(&items))
+        }
+        // Hash commands use lists since fields may contain :
+        "HSET" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, items)| {
+                if items.is_empty() || items.len() % 2 != 0 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+                let mut iter = items.into_iter();
+                while let (Some(field), Some(val)) = (iter.next(), iter.next()) {
+                    pairs.push((field, val));
+                }
+                op::set_hash_fields(key, pairs, cache)
+            }).map(|added| added.to_string().into_bytes())
+        }
+        "HGET" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, mut items)| {
+                if items.len() != 1 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                op::read_hash_field(key, items.remove(0), cache)
+            })
+        }
+        "HDEL" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, fields)| {
+                op::delete_hash_fields(key, fields, cache)
+            }).map(|removed| removed.to_string().into_bytes())
+        }
+        "HGETALL" => {
+            cache_op.value_str().and_then(|key| {
+                op::read_hash_fields(key, cache)
+            }).map(|fields| {
+                let mut items: Vec<Vec<u8>> = Vec::new();
+                for (field, val) in fields {
+                    items.push(field);
+                    items.push(val);
+                }
+                op::encode_list(&items)
+            })
+        }
+        "HINCRBY" => {
+            decode_key_and_items(&cache_op.value).and_then(|(key, mut items)| {
+                if items.len() != 2 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let delta = try!(op::parse_integer(&items[1]));
+                op::increment_hash_field(key, items.remove(0), delta, cache)
+            }).map(|val| val.to_string().into_bytes())
+        }
+        _ => Err(RemError::with_reason(format!("Invalid cache command {:?}", cmd))),
+    }
+}
+
+/// Runs the queued operations of a transaction as a unit
+///
+/// The transaction is aborted without running anything if a watched key has changed.
+/// Otherwise every operation is run and the response holds the result of each one in order,
+/// an operation that fails does not stop the ones after it. The changes are recorded in the
+/// undo log until every operation has run so a crash part way through loses all of them
+fn exec_transaction(queue: Vec<CacheOperation>,
+                    watched: Vec<(String, u64)>,
+                    cache: &mut Cache)
+                    -> Result<Vec<u8>, RemError> {
+    for (key, version) in watched {
+        if cache.watch_version(key.as_str()) != version {
+            return Err(RemError::with_reason(format!("{}: {} was changed", REM_00021, key)));
+        }
+    }
+    try!(cache.begin_transaction());
+    let results: Vec<Vec<u8>> = queue.into_iter()
+        .map(|cache_op| response_bytes(execute(cache_op, cache)))
+        .collect();
+    try!(cache.commit_transaction());
+    return Ok(op::encode_list(&results));
+}
+
+/// Hands elements to parked blocking pops for any of their lists that are no longer empty
+///
+/// Called after every operation while the cache lock is still held
+fn serve_blocked_pops(blocked_mtx: &Mutex<BlockedPops>, cache: &mut Cache) {
+    let mut blocked = blocked_mtx.lock().unwrap();
+    if blocked.waiting.is_empty() {
+        return;
+    }
+    let keys: Vec<String> = blocked.waiting.keys().cloned().collect();
+    for key in keys {
+        let mut queue = blocked.waiting.remove(&key).unwrap_or(VecDeque::new());
+        while let Some(waiter) = queue.pop_front() {
+            // The client disconnected while it was waiting
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+            match op::list_length(key.clone(), cache) {
+                Ok(len) if len > 0 => (),
+                _ => {
+                    queue.push_front(waiter);
+                    break;
+                }
+            }
+            let res = response_bytes(op::pop_from_list(key.clone(), waiter.end, cache));
+            if let Err(element) = waiter.sender.send(res) {
+                // The client went away after the check, put the element back where it was
+                if let Err(why) = op::push_to_list(key.clone(), waiter.end, vec![element], cache) {
+                    why.log();
+                }
+            }
+        }
+        if !queue.is_empty() {
+            blocked.waiting.insert(key, queue);
+        }
+    }
+}
+
+/// Publishes the keyspace events recorded by the cache
+///
+/// Each event is published twice, to ```__keyspace__:{key}``` with the event as the message and
+/// to ```__keyevent__:{event}``` with the key as the message. Subscribing to a pattern such as
+/// __keyspace__:user:* filters by key and subscribing to __keyevent__:del filters by event
+pub fn publish_key_events(pubsub_mtx: &Mutex<PubSub>, cache: &mut Cache) {
+    let events = cache.take_events();
+    if events.is_empty() {
+        return;
+    }
+    let mut pubsub = pubsub_mtx.lock().unwrap();
+    for (event, key) in events {
+        pubsub.publish(&format!("{}{}", KEYSPACE_PREFIX, key), event.name().as_bytes());
+        pubsub.publish(&format!("{}{}", KEYEVENT_PREFIX, event.name()), key.as_bytes());
+    }
+}
+
+/// Streams the changes recorded by the cache to the replicas
+///
+/// Called after every operation while the cache lock is still held so changes are numbered in
+/// the order they were made
+pub fn replicate_changes(replication_mtx: &Mutex<Replication>, cache: &mut Cache) {
+    let changes = cache.take_changes();
+    if changes.is_empty() {
+        return;
+    }
+    replication_mtx.lock().unwrap().replicate(changes);
+}
+
+/// Responds to every parked blocking pop with an error, called when the server shuts down so
+/// they don't hold up the shutdown until they time out
+pub fn release_blocked_pops(blocked_mtx: &Mutex<BlockedPops>) {
+    let mut blocked = blocked_mtx.lock().unwrap();
+    for (_, queue) in blocked.waiting.drain() {
+        for waiter in queue {
+            let _ = waiter.sender.send(response_bytes(Err(RemError::with_reason_str(REM_00053))));
+        }
+    }
+}
+
+/// Starts the thread that times out parked blocking pops
+///
+/// One thread serves every timeout, it sleeps until the soonest deadline or until a deadline
+/// is added
+pub fn start_pop_timer(blocked_mtx: Arc<Mutex<BlockedPops>>) {
+    thread::spawn(move || {
+        let mut blocked = blocked_mtx.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut wait: Option<Duration> = None;
+            while let Some(Reverse((deadline, id, key))) = blocked.deadlines.pop() {
+                if deadline > now {
+                    wait = Some(deadline - now);
+                    blocked.deadlines.push(Reverse((deadline, id, key)));
+                    break;
+                }
+                expire_blocked_pop(&mut blocked, key.as_str(), id);
+            }
+            let timer = blocked.timer.clone();
+            blocked = match wait {
+                Some(wait) => timer.wait_timeout(blocked, wait).unwrap().0,
+                None => timer.wait(blocked).unwrap(),
             };
-            return Ok(ret);
-        }).boxed()
+        }
+    });
+}
+
+/// Responds to a parked blocking pop with a timeout error if it is still waiting
+fn expire_blocked_pop(blocked: &mut BlockedPops, key: &str, id: u64) {
+    let mut expired: Option<BlockedPop> = None;
+    if let Some(queue) = blocked.waiting.get_mut(key) {
+        if let Some(idx) = queue.iter().position(|waiter| waiter.id == id) {
+            expired = queue.remove(idx);
+        }
+    }
+    if blocked.waiting.get(key).map(|queue| queue.is_empty()).unwrap_or(false) {
+        blocked.waiting.remove(key);
+    }
+    if let Some(waiter) = expired {
+        let _ = waiter.sender.send(response_bytes(Err(RemError::with_reason(format!("{}: {}",
+                                                                                    REM_00024,
+                                                                                    key)))));
+    }
+}
+
+/// Creates a future for a response that doesn't need to run on the thread pool
+fn respond(res: Result<Vec<u8>, RemError>) -> BoxFuture<Vec<u8>, io::Error> {
+    return future::ok(response_bytes(res)).boxed();
+}
+
+/// Produces a future for the versions recorded by a connection's watches, empty when no key
+/// is watched
+fn watched_versions(watched: Option<Watched>) -> BoxFuture<Vec<(String, u64)>, ()> {
+    return match watched {
+        Some(watched) => watched.map(|versions| (*versions).clone()).map_err(|_| ()).boxed(),
+        None => future::ok(Vec::new()).boxed(),
+    };
+}
+
+/// Creates a future for a response without a body
+fn respond_message(res: Result<Vec<u8>, RemError>) -> BoxFuture<Message<Vec<u8>, PushStream>, io::Error> {
+    return future::ok(Message::WithoutBody(response_bytes(res))).boxed();
+}
+
+/// Converts the result of an operation into the bytes sent back to the client
+/// Errors are sent as ```ERROR:{description}```
+pub fn response_bytes(res: Result<Vec<u8>, RemError>) -> Vec<u8> {
+    match res {
+        Ok(res) =>  res,
+        Err(cause) => {
+            let err_desc = String::from(cause.description());
+            format!("{}:{}", ERROR, err_desc).into_bytes()
+        }
+    }
+}
+
+/// Returns the keys a request reads or writes, which decide the slot it belongs to
+///
+/// Requests such as KEYS and DELPREFIX that aren't about particular keys return no keys
+/// and only act on the keys held by this node
+fn request_keys(cache_op: &CacheOperation) -> Result<Vec<String>, RemError> {
+    match cache_op.command().as_str() {
+        "R" | "D" | "RV" | "INCR" | "DECR" | "LLEN" | "SMEMBERS" | "HGETALL" => {
+            cache_op.value_str().map(|key| vec![key])
+        }
+        "W" | "SETNX" | "SETXX" | "SETV" | "INCRBY" | "LPOP" | "RPOP" | "BLPOP" | "BRPOP" | "LRANGE" => {
+            cache_op.key_and_arg().map(|(key, _)| vec![key])
+        }
+        "LPUSH" | "RPUSH" | "SADD" | "SREM" | "SISMEMBER" | "ZADD" | "ZRANGE" | "ZRANGEBYSCORE" |
+        "ZINCRBY" | "HSET" | "HGET" | "HDEL" | "HINCRBY" => {
+            decode_key_and_items(&cache_op.value).map(|(key, _)| vec![key])
+        }
+        "MGET" | "MDEL" | "SINTER" | "WATCH" => op::decode_keys(&cache_op.value),
+        "MSET" => decode_pairs(&cache_op.value).map(|pairs| pairs.into_iter().map(|(key, _)| key).collect()),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Decodes the arguments of a scan, a list of cursor, pattern and count
+/// The pattern defaults to * and the count defaults to 10 when they are not provided
+fn decode_scan_args(bytes: &[u8]) -> Result<(String, String, usize), RemError> {
+    let mut args = try!(op::decode_keys(bytes)).into_iter();
+    let cursor = args.next().unwrap_or(String::from(op::SCAN_START));
+    let pattern = args.next().unwrap_or(String::from("*"));
+    let count = match args.next() {
+        Some(count) => try!(op::parse_integer(count.as_bytes())),
+        None => 10,
+    };
+    if count <= 0 {
+        return Err(RemError::with_reason_str(REM_00017));
+    }
+    return Ok((cursor, pattern, count as usize));
+}
+
+/// Decodes a list whose first item is a key followed by any number of items
+fn decode_key_and_items(bytes: &[u8]) -> Result<(String, Vec<Vec<u8>>), RemError> {
+    let mut items = try!(op::decode_list(bytes));
+    if items.is_empty() {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let rest = items.split_off(1);
+    match String::from_utf8(items.remove(0)) {
+        Ok(key) => Ok((key, rest)),
+        Err(_) => Err(RemError::with_reason_str(REM_00005)),
+    }
+}
+
+/// Decodes the key and timeout in seconds of a blocking pop, ex: jobs:30
+fn decode_blocking_pop_args(cache_op: &CacheOperation) -> Result<(String, u64), RemError> {
+    let (key, arg) = try!(cache_op.key_and_arg());
+    let timeout = if arg.is_empty() { 0 } else { try!(op::parse_integer(&arg)) };
+    if timeout < 0 {
+        return Err(RemError::with_reason_str(REM_00012));
+    }
+    return Ok((key, timeout as u64));
+}
+
+/// Encodes the members of a sorted set as a list of alternating members and scores
+fn encode_scored(members: Vec<(Vec<u8>, f64)>) -> Vec<u8> {
+    let mut items: Vec<Vec<u8>> = Vec::new();
+    for (member, score) in members {
+        items.push(member);
+        items.push(value::format_score(score).into_bytes());
+    }
+    return op::encode_list(&items);
+}
+
+/// Decodes a list of alternating keys and values
+fn decode_pairs(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>, RemError> {
+    let items = try!(op::decode_list(bytes));
+    if items.len() % 2 != 0 {
+        return Err(RemError::with_reason_str(REM_00015));
+    }
+    let mut pairs: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut iter = items.into_iter();
+    while let (Some(key), Some(val)) = (iter.next(), iter.next()) {
+        match String::from_utf8(key) {
+            Ok(key) => pairs.push((key, val)),
+            Err(_) => return Err(RemError::with_reason_str(REM_00005)),
+        }
     }
+    return Ok(pairs);
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::string::String;
+use std::vec::Vec;
+
+use rem::config::Config;
+use rem::rem_client::RemClient;
+use rem::ring::HashRing;
+use rem::gossip::MemberState;
+use rem::error::*;
+
+/// A client that spreads keys across the servers listed in the client config
+///
+/// Each key is sent to the server a consistent hash ring assigns it to, so the servers don't
+/// need to know about each other. A server that can't be reached is removed from the ring and
+/// its keys are sent to the servers that now own them, the values it held are not moved
+///
+/// Operations on several keys are split into one request for each server
+pub struct ShardedClient {
+    config: Config,
+    ring: HashRing,
+    /// Connections are opened the first time a server is used
+    clients: HashMap<String, RemClient>
+}
+
+impl ShardedClient {
+    /// Creates a client for the servers in the client config, connections are opened when
+    /// they are first needed
+    pub fn new(config: &Config) -> Result<ShardedClient, RemError> {
+        if config.client.servers.is_empty() {
+            return Err(RemError::with_reason_str(REM_00039));
+        }
+        let mut ring = HashRing::new(config.client.virtual_nodes);
+        for server in &config.client.servers {
+            ring.add(server.addr.as_str(), server.weight);
+        }
+        return Ok(ShardedClient {
+            config: config.clone(),
+            ring: ring,
+            clients: HashMap::new()
+        });
+    }
+
+    /// Returns every server on the ring with its weight
+    pub fn nodes(&self) -> Vec<(String, usize)> {
+        return self.ring.nodes();
+    }
+
+    /// Adds a server to the ring, the keys it now owns are sent to it from then on
+    pub fn add_node(&mut self, addr: &str, weight: usize) {
+        self.ring.add(addr, weight);
+    }
+
+    /// Removes a server from the ring and closes its connection
+    pub fn remove_node(&mut self, addr: &str) {
+        self.ring.remove(addr);
+        self.clients.remove(addr);
+    }
+
+    /// Updates the ring from the membership of servers running with gossip
+    ///
+    /// The first server on the ring that responds is asked for its members, servers it reports
+    /// dead are removed and servers it reports alive that aren't on the ring are added with the
+    /// weight in the client config, or 1 if they aren't listed. A suspect server keeps its keys
+    /// until it is declared dead. Servers that aren't members at all are removed too, a dead
+    /// server is forgotten by gossip after a while
+    pub fn refresh_members(&mut self) -> Result<(), RemError> {
+        let mut members = None;
+        for (addr, _) in self.ring.nodes() {
+            match self.client(addr.as_str()).and_then(|client| client.members()) {
+                Ok(found) => {
+                    members = Some(found);
+                    break;
+                }
+                Err(ref why) if is_unreachable(why) => self.drop_unreachable(addr.as_str(), why),
+                Err(why) => return Err(why),
+            }
+        }
+        let members = try!(members.ok_or(RemError::with_reason_str(REM_00040)));
+        let on_ring: Vec<String> = self.ring.nodes().into_iter().map(|(addr, _)| addr).collect();
+        for addr in &on_ring {
+            if !members.iter().any(|&(ref member, _, _)| member == addr) {
+                info!("Removing {} from the ring, gossip doesn't know it", addr);
+                self.remove_node(addr.as_str());
+            }
+        }
+        for (addr, state, _) in members {
+            match state {
+                MemberState::DEAD => self.remove_node(addr.as_str()),
+                MemberState::ALIVE if !on_ring.contains(&addr) => {
+                    let weight = self.config
+                        .client
+                        .servers
+                        .iter()
+                        .find(|server| server.addr == addr)
+                        .map(|server| server.weight)
+                        .unwrap_or(1);
+                    info!("Adding {} to the ring, gossip reports it alive", addr);
+                    self.add_node(addr.as_str(), weight);
+                }
+                _ => (),
+            }
+        }
+        return Ok(());
+    }
+
+    /// Returns the address of the server the key is sent to
+    pub fn node_for(&self, key: &str) -> Option<String> {
+        return self.ring.node_for(key).map(String::from);
+    }
+
+    /// Runs an operation on the client for the server that owns the key
+    /// ex: ```sharded.with_key("abc", |client| client.lpush("abc", &[b"x"]))```
+    ///
+    /// If the server can't be reached it is removed and the operation is run on the next owner
+    pub fn with_key<T, F>(&mut self, key: &str, mut op: F) -> Result<T, RemError>
+        where F: FnMut(&mut RemClient) -> Result<T, RemError>
+    {
+        loop {
+            let addr = try!(self.owner(key));
+            match self.client(addr.as_str()).and_then(|client| op(client)) {
+                Err(ref why) if is_unreachable(why) => self.drop_unreachable(addr.as_str(), why),
+                res => return res,
+            }
+        }
+    }
+
+    /// Writes the value to the cache under key
+    pub fn write(&mut self, key: &str, val: &[u8]) -> Result<(), RemError> {
+        return self.with_key(key, |client| client.write(key, val));
+    }
+
+    /// Reads the value stored under key
+    pub fn read(&mut self, key: &str) -> Result<Vec<u8>, RemError> {
+        return self.with_key(key, |client| client.read(key));
+    }
+
+    /// Deletes the value stored under key
+    pub fn delete(&mut self, key: &str) -> Result<(), RemError> {
+        return self.with_key(key, |client| client.delete(key));
+    }
+
+    /// Adds 1 to the integer stored under key and returns the new value
+    pub fn incr(&mut self, key: &str) -> Result<i64, RemError> {
+        return self.with_key(key, |client| client.incr(key));
+    }
+
+    /// Subtracts 1 from the integer stored under key and returns the new value
+    pub fn decr(&mut self, key: &str) -> Result<i64, RemError> {
+        return self.with_key(key, |client| client.decr(key));
+    }
+
+    /// Adds delta to the integer stored under key and returns the new value
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, RemError> {
+        return self.with_key(key, |client| client.incr_by(key, delta));
+    }
+
+    /// Reads every key, returning a result for each key in the same order
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Result<Vec<u8>, RemError>>, RemError> {
+        return self.with_keys(keys, |client, idxs| {
+            let group: Vec<&str> = idxs.iter().map(|&idx| keys[idx]).collect();
+            client.mget(&group)
+        });
+    }
+
+    /// Writes every key value pair, returning a result for each pair in the same order
+    pub fn mset(&mut self, pairs: &[(&str, &[u8])]) -> Result<Vec<Result<(), RemError>>, RemError> {
+        let keys: Vec<&str> = pairs.iter().map(|&(key, _)| key).collect();
+        return self.with_keys(&keys, |client, idxs| {
+            let group: Vec<(&str, &[u8])> = idxs.iter().map(|&idx| pairs[idx]).collect();
+            client.mset(&group)
+        });
+    }
+
+    /// Deletes every key, returning for each key in the same order whether it existed
+    pub fn mdel(&mut self, keys: &[&str]) -> Result<Vec<Result<bool, RemError>>, RemError> {
+        return self.with_keys(keys, |client, idxs| {
+            let group: Vec<&str> = idxs.iter().map(|&idx| keys[idx]).collect();
+            client.mdel(&group)
+        });
+    }
+
+    /// Runs an operation on several keys with one request to each server that owns some of them
+    ///
+    /// The operation is given the indexes of the keys owned by the server and returns a result
+    /// for each, the results are put back in the order of the keys
+    fn with_keys<T, F>(&mut self, keys: &[&str], mut op: F) -> Result<Vec<T>, RemError>
+        where F: FnMut(&mut RemClient, &[usize]) -> Result<Vec<T>, RemError>
+    {
+        let mut results: Vec<Option<T>> = keys.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        while !pending.is_empty() {
+            let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+            for idx in pending {
+                let addr = try!(self.owner(keys[idx]));
+                groups.entry(addr).or_insert(Vec::new()).push(idx);
+            }
+            pending = Vec::new();
+            for (addr, idxs) in groups {
+                match self.client(addr.as_str()).and_then(|client| op(client, &idxs)) {
+                    Ok(group_results) => {
+                        for (idx, res) in idxs.into_iter().zip(group_results) {
+                            results[idx] = Some(res);
+                        }
+                    }
+                    Err(ref why) if is_unreachable(why) => {
+                        self.drop_unreachable(addr.as_str(), why);
+                        pending.extend(idxs);
+                    }
+                    Err(why) => return Err(why),
+                }
+            }
+        }
+        // The server responds with a result for every key it was sent
+        return results.into_iter()
+            .map(|res| res.ok_or(RemError::with_reason_str(REM_00015)))
+            .collect();
+    }
+
+    fn owner(&self, key: &str) -> Result<String, RemError> {
+        match self.ring.node_for(key) {
+            Some(addr) => Ok(String::from(addr)),
+            None => Err(RemError::with_reason_str(REM_00040)),
+        }
+    }
+
+    /// Returns the client for the server, connecting to it if this is the first time it is used
+    fn client(&mut self, addr: &str) -> Result<&mut RemClient, RemError> {
+        if !self.clients.contains_key(addr) {
+            let client = try!(RemClient::connect(&self.config, addr));
+            self.clients.insert(String::from(addr), client);
+        }
+        return Ok(self.clients.get_mut(addr).unwrap());
+    }
+
+    fn drop_unreachable(&mut self, addr: &str, why: &RemError) {
+        warn!("Removing {} from the ring, it could not be reached: {}", addr, why);
+        self.remove_node(addr);
+    }
+}
+
+/// Returns true if the error means the server could not be reached rather than that it
+/// responded with an error
+fn is_unreachable(why: &RemError) -> bool {
+    let desc = format!("{}", why);
+    return desc.starts_with(REM_00003) || desc.starts_with(REM_00006);
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+use std::string::String;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use rem::cache::Cache;
+use rem::config::Config;
+use rem::op;
+use rem::pubsub::PubSub;
+use rem::replication::{self, Replication};
+use rem::service;
+use rem::tcp_stream::TcpStream;
+use rem::error::*;
+
+/// The number of slots the keyspace is divided into
+pub const SLOT_COUNT: usize = 16384;
+
+/// The file the owner of every slot is kept in, one ```{first slot} {last slot} {node}``` line
+/// for each range of slots owned by the same node
+pub const SLOTS_FILE: &'static str = "_slots";
+
+/// Where a request for keys in a slot should be served
+pub enum Route {
+    /// This node serves the request
+    LOCAL,
+    /// The slot is being migrated to the node, keys that have already been moved are served there
+    MIGRATING(String),
+}
+
+/// The node that owns each slot, as seen by this node
+///
+/// Every key belongs to a slot and each slot is owned by a single node, a node redirects
+/// requests for keys in slots it doesn't own with a MOVED error naming the owner.
+/// While a slot is migrated its keys are moved one at a time, the source keeps serving the
+/// keys it still holds and sends requests for keys that have moved to the target with an ASK
+/// error. The target only serves keys in a slot it is importing when the request follows ASKING
+pub struct SlotMap {
+    /// The address of this node
+    pub id: String,
+    owners: Vec<Option<String>>,
+    /// The slots this node is migrating to another node, and the node they are going to
+    migrating: HashMap<usize, String>,
+    /// The slots this node is importing from another node, and the node they come from
+    importing: HashMap<usize, String>
+}
+
+impl SlotMap {
+    /// Loads the owners of every slot from SLOTS_FILE, slots are unassigned if it doesn't exist
+    pub fn load(id: String) -> Result<SlotMap, RemError> {
+        let mut slots = SlotMap {
+            id: id,
+            owners: vec![None; SLOT_COUNT],
+            migrating: HashMap::new(),
+            importing: HashMap::new()
+        };
+        if !Path::new(SLOTS_FILE).exists() {
+            return Ok(slots);
+        }
+        let mut contents = String::new();
+        try!(try!(File::open(SLOTS_FILE)).read_to_string(&mut contents));
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(RemError::with_reason(format!("{}: {}", REM_00015, line)));
+            }
+            let (first, last) = try!(parse_range(parts[0].as_bytes(), parts[1].as_bytes()));
+            for slot in first..last + 1 {
+                slots.owners[slot] = Some(String::from(parts[2]));
+            }
+        }
+        return Ok(slots);
+    }
+
+    /// Assigns the slots from first to last to a node
+    ///
+    /// Assigning a slot ends any migration of it, so this is also how the target of a
+    /// migration takes ownership once every key has moved
+    pub fn assign(&mut self, first: usize, last: usize, node: &str) -> Result<(), RemError> {
+        for slot in first..last + 1 {
+            self.owners[slot] = Some(String::from(node));
+            self.migrating.remove(&slot);
+            self.importing.remove(&slot);
+        }
+        return self.save();
+    }
+
+    /// Marks a slot as being imported from another node
+    pub fn import(&mut self, slot: usize, source: &str) {
+        self.importing.insert(slot, String::from(source));
+    }
+
+    /// Decides where a request for the keys should be served, every key must be in the same slot
+    ///
+    /// asking is true when the request follows ASKING, which lets a node serve keys in a slot
+    /// it is importing
+    pub fn route(&self, keys: &[String], asking: bool) -> Result<Route, RemError> {
+        let slot = match keys.first() {
+            Some(key) => slot_for(key),
+            None => return Ok(Route::LOCAL),
+        };
+        if keys.iter().any(|key| slot_for(key) != slot) {
+            return Err(RemError::with_reason_str(REM_00041));
+        }
+        if let Some(target) = self.migrating.get(&slot) {
+            return Ok(Route::MIGRATING(target.clone()));
+        }
+        if asking && self.importing.contains_key(&slot) {
+            return Ok(Route::LOCAL);
+        }
+        match self.owners[slot] {
+            Some(ref owner) if *owner == self.id => Ok(Route::LOCAL),
+            Some(ref owner) => Err(RemError::with_reason(format!("{} {} {}", REM_00042, slot, owner))),
+            None => Err(RemError::with_reason(format!("{}: {}", REM_00044, slot))),
+        }
+    }
+
+    /// Describes the slots, each item is ```{first slot}-{last slot}:{node}``` for a range of
+    /// slots owned by the same node followed by ```migrating:{slot}:{target}``` and
+    /// ```importing:{slot}:{source}``` for each slot being moved
+    pub fn info(&self) -> Vec<String> {
+        let mut info: Vec<String> = self.ranges()
+            .into_iter()
+            .map(|(first, last, node)| format!("{}-{}:{}", first, last, node))
+            .collect();
+        let mut migrating: Vec<(&usize, &String)> = self.migrating.iter().collect();
+        migrating.sort();
+        for (slot, target) in migrating {
+            info.push(format!("migrating:{}:{}", slot, target));
+        }
+        let mut importing: Vec<(&usize, &String)> = self.importing.iter().collect();
+        importing.sort();
+        for (slot, source) in importing {
+            info.push(format!("importing:{}:{}", slot, source));
+        }
+        return info;
+    }
+
+    /// Returns the ranges of assigned slots owned by the same node
+    fn ranges(&self) -> Vec<(usize, usize, String)> {
+        let mut ranges: Vec<(usize, usize, String)> = Vec::new();
+        for (slot, owner) in self.owners.iter().enumerate() {
+            let owner = match *owner {
+                Some(ref owner) => owner,
+                None => continue,
+            };
+            if let Some(&mut (_, ref mut last, ref node)) = ranges.last_mut() {
+                if *last + 1 == slot && node == owner {
+                    *last = slot;
+                    continue;
+                }
+            }
+            ranges.push((slot, slot, owner.clone()));
+        }
+        return ranges;
+    }
+
+    fn save(&self) -> Result<(), RemError> {
+        let mut contents = String::new();
+        for (first, last, node) in self.ranges() {
+            contents.push_str(&format!("{} {} {}\n", first, last, node));
+        }
+        let tmp = format!("{}.tmp", SLOTS_FILE);
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(contents.as_bytes()));
+            try!(file.sync_data());
+        }
+        try!(fs::rename(tmp, SLOTS_FILE));
+        return Ok(());
+    }
+}
+
+/// Returns the slot a key belongs to
+///
+/// When the key contains a hash tag, a non empty part between the first { and the } after it,
+/// only the tag is hashed so keys such as {user:1}:name and {user:1}:email share a slot
+pub fn slot_for(key: &str) -> usize {
+    let mut hashed = key;
+    if let Some(open) = key.find('{') {
+        if let Some(len) = key[open + 1..].find('}') {
+            if len > 0 {
+                hashed = &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    return crc16(hashed.as_bytes()) as usize % SLOT_COUNT;
+}
+
+/// CRC-16/XMODEM, the same hash Redis cluster uses to pick a slot
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    return crc;
+}
+
+/// Parses a range of slots, the first slot can't be after the last
+pub fn parse_range(first: &[u8], last: &[u8]) -> Result<(usize, usize), RemError> {
+    let first = try!(op::parse_integer(first));
+    let last = try!(op::parse_integer(last));
+    if first < 0 || last < first || last as usize >= SLOT_COUNT {
+        return Err(RemError::with_reason(format!("{}: {}-{}", REM_00045, first, last)));
+    }
+    return Ok((first as usize, last as usize));
+}
+
+/// Moves every key in a slot owned by this node to the target node, then hands the slot over
+/// Returns the number of keys moved
+///
+/// Each key is sent to the target and deleted while the cache lock is held so a request can
+/// never see it on both nodes or on neither. Keys written to the slot while it is migrating are
+/// moved too. If the migration fails part way the slot stays migrating and running it again
+/// moves the remaining keys
+pub fn migrate_slot(config: &Config,
+                    slots_mtx: &Mutex<SlotMap>,
+                    cache_mtx: &Mutex<Cache>,
+                    pubsub_mtx: &Mutex<PubSub>,
+                    replication_mtx: &Mutex<Replication>,
+                    slot: usize,
+                    target: &str)
+                    -> Result<usize, RemError> {
+    let id = {
+        let mut slots = slots_mtx.lock().unwrap();
+        if slot >= SLOT_COUNT || slots.owners[slot].as_ref() != Some(&slots.id) {
+            return Err(RemError::with_reason(format!("{}: {}", REM_00046, slot)));
+        }
+        slots.migrating.insert(slot, String::from(target));
+        slots.id.clone()
+    };
+    info!("Migrating slot {} to {}", slot, target);
+    let mut stream = try!(TcpStream::connect(config, target));
+    try!(op::send_request(&mut stream, "SLOTIMPORTING", format!("{}:{}", slot, id).as_bytes()));
+
+    let mut moved = 0;
+    loop {
+        let keys: Vec<String> = try!(cache_mtx.lock().unwrap().keys())
+            .into_iter()
+            .filter(|key| slot_for(key) == slot)
+            .collect();
+        if keys.is_empty() {
+            break;
+        }
+        for key in keys {
+            let mut cache = cache_mtx.lock().unwrap();
+            if let Some(val) = try!(cache.read_value(key.as_str())) {
+                let entry = replication::encode_change(0, key.as_str(), Some(&val));
+                try!(op::send_request(&mut stream, "SLOTRESTORE", &entry));
+                try!(cache.delete_item(key));
+                service::publish_key_events(pubsub_mtx, &mut cache);
+                service::replicate_changes(replication_mtx, &mut cache);
+                moved += 1;
+            }
+        }
+    }
+
+    try!(slots_mtx.lock().unwrap().assign(slot, slot, target));
+    try!(op::send_request(&mut stream, "SLOTSET", format!("{}:{}:{}", slot, slot, target).as_bytes()));
+    info!("Migrated slot {} to {}, {} keys moved", slot, target, moved);
+    return Ok(moved);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, parse_range, slot_for, SLOT_COUNT};
+
+    #[test]
+    fn crc16_matches_the_xmodem_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn slot_for_matches_redis() {
+        assert_eq!(slot_for("foo"), 12182);
+        assert_eq!(slot_for("bar"), 5061);
+        assert!(slot_for("any key") < SLOT_COUNT);
+    }
+
+    #[test]
+    fn hash_tags_share_a_slot() {
+        assert_eq!(slot_for("{user1000}.following"), slot_for("{user1000}.followers"));
+        assert_eq!(slot_for("{user1000}.following"), slot_for("user1000"));
+        assert_eq!(slot_for("foo{bar}{zap}"), slot_for("bar"));
+    }
+
+    #[test]
+    fn empty_or_unclosed_tags_hash_the_whole_key() {
+        assert_eq!(slot_for("foo{}{bar}"), crc16(b"foo{}{bar}") as usize % SLOT_COUNT);
+        assert_eq!(slot_for("{}"), crc16(b"{}") as usize % SLOT_COUNT);
+        assert_eq!(slot_for("foo{bar"), crc16(b"foo{bar") as usize % SLOT_COUNT);
+    }
+
+    #[test]
+    fn parse_range_rejects_bad_ranges() {
+        assert_eq!(parse_range(b"0", b"16383").unwrap(), (0, 16383));
+        assert_eq!(parse_range(b"5", b"5").unwrap(), (5, 5));
+        assert!(parse_range(b"6", b"5").is_err());
+        assert!(parse_range(b"-1", b"5").is_err());
+        assert!(parse_range(b"0", b"16384").is_err());
+    }
+}
@@ -12,7 +12,8 @@ trait ReadWrite : Read + Write {}
 impl<T: Read + Write> ReadWrite for T {}
 
 pub struct TcpStream{
-    io_delegate : Box<ReadWrite>
+    io_delegate : Box<ReadWrite>,
+    socket      : net::TcpStream
 }
 
 /// Wraps std::net::TcpStream and native_tls::TcpStream
@@ -21,18 +22,29 @@ impl TcpStream {
 
     pub fn connect<A: ToSocketAddrs>(config: &Config, addr: A) -> Result<TcpStream, RemError> {
         let tcp_stream = try!(net::TcpStream::connect(addr));
+        // Keep a handle to the underlying socket so it can be shut down through a TLS stream
+        let socket     = try!(tcp_stream.try_clone());
         if config.ssl {
             let tls_builder   = try!(TlsConnector::builder());
             let tls_connector = try!(tls_builder.build());
             let tls_stream    = try!(tls_connector.connect(config.domain.as_str(), tcp_stream));
             return Ok(TcpStream {
-                io_delegate: Box::new(tls_stream)
+                io_delegate: Box::new(tls_stream),
+                socket: socket
             });
         }
         return Ok(TcpStream{
-                io_delegate:Box::new(tcp_stream)
+                io_delegate:Box::new(tcp_stream),
+                socket: socket
         });
     }
+
+    /// Flushes any pending writes and shuts down both halves of the connection
+    pub fn close(mut self) -> Result<(), RemError> {
+        try!(self.io_delegate.flush());
+        try!(self.socket.shutdown(net::Shutdown::Both));
+        return Ok(());
+    }
 }
 
 impl Read for TcpStream {
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem;
+use std::str;
+use std::vec::Vec;
+
+use rem::op;
+use rem::error::*;
+
+/// The type names of every kind of value, in the order they are checked when loading a key
+pub const TYPES: &'static [&'static str] = &["string", "list", "hash", "set", "zset"];
+
+/// A value stored in the cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// An opaque value written with W, the only type the original cache stored
+    BYTES(Vec<u8>),
+    /// An ordered list of elements
+    LIST(VecDeque<Vec<u8>>),
+    /// A map of fields to values, fields are kept sorted
+    HASH(BTreeMap<Vec<u8>, Vec<u8>>),
+    /// A set of unique members, kept sorted
+    SET(BTreeSet<Vec<u8>>),
+    /// A set of unique members each with a score, ordered by score when ranged over
+    ZSET(BTreeMap<Vec<u8>, f64>),
+}
+
+/// The end of a list an element is pushed to or popped from
+#[derive(Debug, Clone, Copy)]
+pub enum ListEnd {
+    LEFT,
+    RIGHT,
+}
+
+impl Value {
+    /// Returns the name of the value's type, one of TYPES
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::BYTES(_) => "string",
+            Value::LIST(_) => "list",
+            Value::HASH(_) => "hash",
+            Value::SET(_) => "set",
+            Value::ZSET(_) => "zset",
+        }
+    }
+
+    /// Returns the number of bytes of data held by the value, used for memory accounting
+    ///
+    /// Only the elements are counted, not the overhead of the collection holding them
+    pub fn size(&self) -> usize {
+        match *self {
+            Value::BYTES(ref val) => val.len(),
+            Value::LIST(ref elements) => elements.iter().map(|element| element.len()).sum(),
+            Value::HASH(ref fields) => fields.iter().map(|(field, val)| field.len() + val.len()).sum(),
+            Value::SET(ref members) => members.iter().map(|member| member.len()).sum(),
+            Value::ZSET(ref members) => {
+                members.keys().map(|member| member.len() + mem::size_of::<f64>()).sum()
+            }
+        }
+    }
+
+    /// Encodes the value for the file store
+    ///
+    /// Plain values are stored as is, structured values are stored using encode_list
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Value::BYTES(ref val) => val.clone(),
+            Value::LIST(ref elements) => {
+                let items: Vec<Vec<u8>> = elements.iter().cloned().collect();
+                op::encode_list(&items)
+            }
+            // Fields and values alternate
+            Value::HASH(ref fields) => {
+                let mut items: Vec<Vec<u8>> = Vec::new();
+                for (field, val) in fields {
+                    items.push(field.clone());
+                    items.push(val.clone());
+                }
+                op::encode_list(&items)
+            }
+            Value::SET(ref members) => {
+                let items: Vec<Vec<u8>> = members.iter().cloned().collect();
+                op::encode_list(&items)
+            }
+            // Members and scores alternate
+            Value::ZSET(ref members) => {
+                let mut items: Vec<Vec<u8>> = Vec::new();
+                for (member, score) in members {
+                    items.push(member.clone());
+                    items.push(format_score(*score).into_bytes());
+                }
+                op::encode_list(&items)
+            }
+        }
+    }
+
+    /// Decodes a value of the named type that was encoded by encode
+    pub fn decode(type_name: &str, bytes: Vec<u8>) -> Result<Value, RemError> {
+        match type_name {
+            "string" => Ok(Value::BYTES(bytes)),
+            "list" => Ok(Value::LIST(try!(op::decode_list(&bytes)).into_iter().collect())),
+            "hash" => {
+                let items = try!(op::decode_list(&bytes));
+                if items.len() % 2 != 0 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let mut fields: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+                let mut iter = items.into_iter();
+                while let (Some(field), Some(val)) = (iter.next(), iter.next()) {
+                    fields.insert(field, val);
+                }
+                Ok(Value::HASH(fields))
+            }
+            "set" => Ok(Value::SET(try!(op::decode_list(&bytes)).into_iter().collect())),
+            "zset" => {
+                let items = try!(op::decode_list(&bytes));
+                if items.len() % 2 != 0 {
+                    return Err(RemError::with_reason_str(REM_00015));
+                }
+                let mut members: BTreeMap<Vec<u8>, f64> = BTreeMap::new();
+                let mut iter = items.into_iter();
+                while let (Some(member), Some(score)) = (iter.next(), iter.next()) {
+                    members.insert(member, try!(parse_score(&score)));
+                }
+                Ok(Value::ZSET(members))
+            }
+            _ => Err(RemError::with_reason(format!("Unknown value type {}", type_name))),
+        }
+    }
+}
+
+/// Returns the error for an operation on a key holding a different type of value
+pub fn wrong_type(key: &str, value: &Value) -> RemError {
+    return RemError::with_reason(format!("{}: {} holds a {}", REM_00022, key, value.type_name()));
+}
+
+/// Converts a list index that may count from the end, where -1 is the last element, into a
+/// position from the start. Returns None for an index before the start
+pub fn list_index(idx: i64, len: usize) -> Option<usize> {
+    if idx < 0 {
+        let from_end = idx.checked_neg().map_or(u64::max_value(), |from_end| from_end as u64);
+        if from_end > len as u64 {
+            return None;
+        }
+        return Some(len - from_end as usize);
+    }
+    return Some(idx as usize);
+}
+
+/// Parses the score of a sorted set member, any finite or infinite number but not NaN
+pub fn parse_score(bytes: &[u8]) -> Result<f64, RemError> {
+    match str::from_utf8(bytes).ok().and_then(|val| val.parse::<f64>().ok()) {
+        Some(score) if !score.is_nan() => Ok(score),
+        _ => Err(RemError::with_reason_str(REM_00026)),
+    }
+}
+
+/// Formats a score so that parse_score returns the same score, ex: 1.5 or 3
+pub fn format_score(score: f64) -> String {
+    return format!("{}", score);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::list_index;
+
+    #[test]
+    fn list_index_counts_negative_indexes_from_the_end() {
+        assert_eq!(list_index(0, 3), Some(0));
+        assert_eq!(list_index(5, 3), Some(5));
+        assert_eq!(list_index(-1, 3), Some(2));
+        assert_eq!(list_index(-3, 3), Some(0));
+        assert_eq!(list_index(-4, 3), None);
+        assert_eq!(list_index(-1, 0), None);
+    }
+
+    #[test]
+    fn list_index_handles_the_extreme_indexes() {
+        assert_eq!(list_index(i64::min_value(), 3), None);
+        assert_eq!(list_index(i64::min_value() + 1, 3), None);
+        assert_eq!(list_index(i64::max_value(), 3), Some(i64::max_value() as usize));
+    }
+}